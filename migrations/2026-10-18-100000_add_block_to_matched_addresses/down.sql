@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE matched_addresses
+    DROP COLUMN IF EXISTS block_hash,
+    DROP COLUMN IF EXISTS block_height;
@@ -0,0 +1,4 @@
+-- Track the block a matched transaction was confirmed in
+ALTER TABLE matched_addresses
+    ADD COLUMN block_hash TEXT,
+    ADD COLUMN block_height INTEGER;
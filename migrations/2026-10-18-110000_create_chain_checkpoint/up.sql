@@ -0,0 +1,6 @@
+-- Last block whose transactions were fully processed, used to catch up after downtime
+CREATE TABLE chain_checkpoint (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    block_height INTEGER NOT NULL,
+    block_hash TEXT NOT NULL
+);
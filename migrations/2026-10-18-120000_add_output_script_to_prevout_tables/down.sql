@@ -0,0 +1,8 @@
+DROP INDEX input_transactions_spilled_at;
+DROP INDEX gen_transactions_spilled_at;
+
+ALTER TABLE input_transactions DROP COLUMN spilled_at;
+ALTER TABLE gen_transactions DROP COLUMN spilled_at;
+
+ALTER TABLE input_transactions DROP COLUMN output_script;
+ALTER TABLE gen_transactions DROP COLUMN output_script;
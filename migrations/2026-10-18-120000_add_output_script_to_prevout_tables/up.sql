@@ -0,0 +1,10 @@
+-- Keep the script of every output so outputs without an address form can be matched
+ALTER TABLE gen_transactions ADD COLUMN output_script TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE input_transactions ADD COLUMN output_script TEXT[] NOT NULL DEFAULT '{}';
+
+-- Spilled prevouts are pruned once they are old enough to be unlikely to be spent
+ALTER TABLE gen_transactions ADD COLUMN spilled_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE input_transactions ADD COLUMN spilled_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX gen_transactions_spilled_at ON gen_transactions (spilled_at);
+CREATE INDEX input_transactions_spilled_at ON input_transactions (spilled_at);
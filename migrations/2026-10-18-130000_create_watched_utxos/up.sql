@@ -0,0 +1,17 @@
+-- Outputs paying to watched records, so their spends are recognised by outpoint
+CREATE TABLE watched_utxos (
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    nostr_pubkey TEXT NOT NULL REFERENCES users (nostr_pubkey),
+    record TEXT NOT NULL,
+    script_pubkey TEXT NOT NULL,
+    value BIGINT NOT NULL,
+    block_hash TEXT,
+    block_height INTEGER,
+    spent_by TEXT,
+    -- Spends seen in the mempool stay undoable until they confirm
+    spent_height INTEGER,
+    PRIMARY KEY (txid, vout, nostr_pubkey)
+);
+
+CREATE INDEX watched_utxos_unspent ON watched_utxos (nostr_pubkey) WHERE spent_height IS NULL;
@@ -0,0 +1,7 @@
+-- How far the scripts derived from each watched record have been used
+CREATE TABLE derivation_windows (
+    record TEXT NOT NULL,
+    chain INTEGER NOT NULL,
+    next_index INTEGER NOT NULL,
+    PRIMARY KEY (record, chain)
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE matched_addresses RENAME COLUMN record TO address;
+ALTER TABLE user_addresses DROP COLUMN kind;
+ALTER TABLE user_addresses RENAME COLUMN record TO address;
@@ -0,0 +1,31 @@
+-- Watched records are stored with their kind, so a value is read back as
+-- the record it was saved as rather than guessed from its text
+ALTER TABLE user_addresses RENAME COLUMN address TO record;
+ALTER TABLE user_addresses ADD COLUMN kind TEXT;
+
+UPDATE user_addresses SET kind = CASE
+    WHEN record LIKE '%(%' THEN 'descriptor'
+    WHEN record ~ '^[0-9a-fA-F]{64}:[0-9]+$' THEN 'utxo'
+    WHEN record ~ '^[xyztuv]pub' THEN 'xpub'
+    WHEN record ~ '^([0-9a-fA-F]{2})+$' THEN 'script'
+    ELSE 'address'
+END;
+
+-- Hex and bech32 values are written in lowercase; drop rows that only
+-- differ from another one of the same user by case before normalising
+DELETE FROM user_addresses a
+    USING user_addresses b
+    WHERE a.nostr_pubkey = b.nostr_pubkey
+      AND a.kind = b.kind
+      AND a.record > b.record
+      AND LOWER(a.record) = LOWER(b.record)
+      AND (a.kind IN ('script', 'utxo') OR a.record ~* '^(bc|tb|bcrt)1');
+UPDATE user_addresses SET record = LOWER(record)
+    WHERE kind IN ('script', 'utxo')
+       OR (kind = 'address' AND record ~* '^(bc|tb|bcrt)1');
+
+ALTER TABLE user_addresses ALTER COLUMN kind SET NOT NULL;
+
+-- Matches keep the labels of the records that matched
+ALTER TABLE matched_addresses RENAME COLUMN address TO record;
+UPDATE matched_addresses SET record = array_remove(record, NULL);
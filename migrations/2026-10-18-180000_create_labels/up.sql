@@ -0,0 +1,10 @@
+-- BIP-329 labels a user gave to records, transactions and outputs
+CREATE TABLE labels (
+    nostr_pubkey TEXT NOT NULL REFERENCES users (nostr_pubkey),
+    label_type TEXT NOT NULL,
+    reference TEXT NOT NULL,
+    label TEXT NOT NULL,
+    origin TEXT,
+    spendable BOOLEAN,
+    PRIMARY KEY (nostr_pubkey, label_type, reference)
+);
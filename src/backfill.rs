@@ -0,0 +1,265 @@
+use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, Txid};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashSet};
+use tokio::sync::Mutex;
+
+use crate::models::RecordType;
+use crate::chain_source::OutpointStatus;
+use crate::utxo_index::Coin;
+use crate::{chain_source, db_operations, nostr_notify, rpc, tx_watch, utxo_index};
+
+/// bitcoind only runs one `scantxoutset` at a time and block scans are heavy,
+/// so backfill jobs take turns: a UTXO set scan per script, or
+/// `BLOCKS_PER_TURN` blocks of a block scan.
+static BACKFILL_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Blocks a block scan reads before letting other backfills run, so one
+/// started from an early birth height does not hold them up for hours.
+const BLOCKS_PER_TURN: u64 = 100;
+
+#[derive(Default)]
+struct BackfillSummary {
+    funding_txs: HashSet<Txid>,
+    spending_txs: HashSet<Txid>,
+    /// Unspent outputs found, with the block they confirmed in.
+    unspent: BTreeMap<OutPoint, (Coin, BlockHash)>,
+    scanned_from: Option<u64>,
+    scanned_to: Option<u64>,
+    error: Option<String>,
+}
+
+/// Discovers the existing funding transactions and unspent outputs of a newly
+/// added record, stores them as confirmed history and sends the user a
+/// summary. Scans blocks from `birth_height` when given, otherwise queries the
+/// node's UTXO set, which finds current coins but no spent history.
+pub async fn backfill_record(user: String, record: RecordType, birth_height: Option<u64>) {
+    match record {
+        RecordType::Utxo(outpoint) => {
+            report_outpoint_status(user, outpoint).await;
+            return;
+        }
+        RecordType::Txid(watch) => {
+            tx_watch::report_status(user, watch).await;
+            return;
+        }
+        _ => {}
+    }
+    let label = record.label();
+    let scripts = record.script_pubkeys();
+    if scripts.is_empty() {
+        println!("Backfill is not supported for record {}", label);
+        return;
+    }
+
+    println!("🔄 Backfilling {} for {}", label, user);
+
+    let summary = match birth_height {
+        Some(height) => scan_blocks(&user, &label, &scripts, height).await,
+        None => scan_utxo_set(&user, &label, &scripts).await,
+    };
+
+    // Spends of the outputs found are then recognised like live ones, and
+    // count towards the record's balance.
+    for (outpoint, (coin, block_hash)) in summary.unspent.iter() {
+        utxo_index::add_confirmed(*outpoint, coin.clone(), *block_hash);
+    }
+
+    let total: Amount = summary.unspent.values().map(|(coin, _)| coin.value).sum();
+    let mut message = format!(
+        "Backfill for {} complete: {} UTXO(s), {} BTC currently {}.",
+        label,
+        summary.unspent.len(),
+        total.to_btc(),
+        holder(&record)
+    );
+    if let (Some(from), Some(to)) = (summary.scanned_from, summary.scanned_to) {
+        message.push_str(&format!(
+            " Scanned blocks {} to {}: {} funding and {} spending transaction(s).",
+            from,
+            to,
+            summary.funding_txs.len(),
+            summary.spending_txs.len()
+        ));
+    }
+    if let Some(error) = summary.error {
+        message.push_str(&format!(" The scan was incomplete: {}", error));
+    }
+    nostr_notify::send_message(message, user);
+}
+
+/// Where the summary says a record's coins are.
+fn holder(record: &RecordType) -> &'static str {
+    match record {
+        RecordType::Address(_) => "at this address",
+        RecordType::Script(_) => "paid to this script",
+        RecordType::Xpub(_) => "at the addresses of this xpub",
+        RecordType::Descriptor(_) => "at the scripts of this descriptor",
+        RecordType::Pubkey(_) => "paid to this public key",
+        RecordType::Utxo(_) | RecordType::Txid(_) => "in this record",
+    }
+}
+
+/// Tells the user whether a newly watched UTXO can still be spent, since an
+/// already spent one will never trigger a notification.
+async fn report_outpoint_status(user: String, outpoint: OutPoint) {
+    let message = match chain_source::get_chain_source()
+        .get_outpoint_status(&outpoint)
+        .await
+    {
+        Ok(OutpointStatus::Unspent) => format!(
+            "UTXO {} is unspent. You will be notified when it is spent.",
+            outpoint
+        ),
+        Ok(OutpointStatus::Spent {
+            spending_txid: Some(txid),
+        }) => format!("UTXO {} has already been spent in tx {}.", outpoint, txid),
+        Ok(OutpointStatus::Spent {
+            spending_txid: None,
+        }) => format!("UTXO {} has already been spent or does not exist.", outpoint),
+        Err(e) => {
+            eprintln!("❌ Failed to check status of {}: {}", outpoint, e);
+            return;
+        }
+    };
+    nostr_notify::send_message(message, user);
+}
+
+async fn scan_blocks(
+    user: &str,
+    label: &str,
+    scripts: &[ScriptBuf],
+    birth_height: u64,
+) -> BackfillSummary {
+    let mut summary = BackfillSummary::default();
+    let source = chain_source::get_chain_source();
+    let tip = match source.get_tip().await {
+        Ok(tip) => tip.height,
+        Err(e) => {
+            summary.error = Some(format!("unable to fetch chain tip ({})", e));
+            return summary;
+        }
+    };
+
+    let mut turn = None;
+    for height in birth_height..=tip {
+        if (height - birth_height).is_multiple_of(BLOCKS_PER_TURN) {
+            drop(turn.take());
+            turn = Some(BACKFILL_LOCK.lock().await);
+        }
+        let block = match source.get_block_hash(height).await {
+            Ok(hash) => source.get_block(&hash).await,
+            Err(e) => Err(e),
+        };
+        let block = match block {
+            Ok(block) => block,
+            Err(e) => {
+                summary.error = Some(format!("block {} unavailable ({})", height, e));
+                break;
+            }
+        };
+        summary.scanned_from.get_or_insert(height);
+        summary.scanned_to = Some(height);
+
+        let block_hash = block.block_hash();
+        for tx in block.txdata.iter() {
+            let txid = tx.compute_txid();
+
+            for input in tx.input.iter() {
+                if summary.unspent.remove(&input.previous_output).is_some()
+                    && summary.spending_txs.insert(txid)
+                {
+                    store_history(
+                        user,
+                        label,
+                        txid,
+                        Some(input.previous_output.txid),
+                        block_hash,
+                        height,
+                    );
+                }
+            }
+
+            let mut funded = false;
+            for (vout, output) in tx.output.iter().enumerate() {
+                if scripts.contains(&output.script_pubkey) {
+                    funded = true;
+                    let coin = Coin {
+                        user: user.to_string(),
+                        record: label.to_string(),
+                        script: output.script_pubkey.clone(),
+                        value: output.value,
+                        block_height: Some(height as u32),
+                    };
+                    summary
+                        .unspent
+                        .insert(OutPoint::new(txid, vout as u32), (coin, block_hash));
+                }
+            }
+            if funded && summary.funding_txs.insert(txid) {
+                store_history(user, label, txid, None, block_hash, height);
+            }
+        }
+    }
+    summary
+}
+
+async fn scan_utxo_set(user: &str, label: &str, scripts: &[ScriptBuf]) -> BackfillSummary {
+    let mut summary = BackfillSummary::default();
+    for script in scripts {
+        let script = script.clone();
+        let turn = BACKFILL_LOCK.lock().await;
+        let result = rpc::with_client(move |client| client.scan_utxos(&script)).await;
+        drop(turn);
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                summary.error = Some(format!("UTXO set scan failed ({})", e));
+                return summary;
+            }
+        };
+        for utxo in result.unspents {
+            let height = utxo.height;
+            let block_hash = match chain_source::get_chain_source().get_block_hash(height).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    summary.error = Some(format!("block {} unavailable ({})", height, e));
+                    continue;
+                }
+            };
+            if summary.funding_txs.insert(utxo.txid) {
+                store_history(user, label, utxo.txid, None, block_hash, height);
+            }
+            let coin = Coin {
+                user: user.to_string(),
+                record: label.to_string(),
+                script: utxo.script_pub_key,
+                value: utxo.amount,
+                block_height: Some(height as u32),
+            };
+            summary
+                .unspent
+                .insert(OutPoint::new(utxo.txid, utxo.vout), (coin, block_hash));
+        }
+    }
+    summary
+}
+
+fn store_history(
+    user: &str,
+    label: &str,
+    txid: Txid,
+    prev_txid: Option<Txid>,
+    block_hash: BlockHash,
+    height: u64,
+) {
+    if let Err(e) = db_operations::store_history_match(
+        user.to_string(),
+        vec![label.to_string()],
+        txid.to_string(),
+        prev_txid.map(|t| t.to_string()),
+        block_hash.to_string(),
+        height as i32,
+    ) {
+        eprintln!("❌ Failed to store backfilled tx {}: {}", txid, e);
+    }
+}
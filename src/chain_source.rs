@@ -0,0 +1,208 @@
+use async_trait::async_trait;
+use bitcoin::{Block, BlockHash, OutPoint, Transaction, Txid};
+use dotenvy::dotenv;
+use once_cell::sync::OnceCell;
+use std::{env, fmt};
+use tokio::task;
+
+use crate::electrum::ElectrumSource;
+use crate::esplora::{EsploraConfig, EsploraSource};
+use crate::network;
+use crate::rpc::{self, ChainClient, RpcError};
+
+static GLOBAL_CHAIN_SOURCE: OnceCell<Box<dyn ChainSource>> = OnceCell::new();
+
+#[derive(Debug, Clone)]
+pub enum ChainSourceError {
+    /// The backend does not know the requested transaction, block or output.
+    NotFound(String),
+    /// The backend cannot answer this kind of query at all.
+    Unsupported(&'static str),
+    /// The backend could not be reached or returned something unexpected.
+    Backend(String),
+}
+
+impl fmt::Display for ChainSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainSourceError::NotFound(what) => write!(f, "not found: {}", what),
+            ChainSourceError::Unsupported(what) => write!(f, "not supported by backend: {}", what),
+            ChainSourceError::Backend(e) => write!(f, "backend error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChainSourceError {}
+
+impl From<RpcError> for ChainSourceError {
+    fn from(e: RpcError) -> Self {
+        match e {
+            RpcError::NotFound(what) => ChainSourceError::NotFound(what),
+            other => ChainSourceError::Backend(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTip {
+    pub height: u64,
+    pub hash: BlockHash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutpointStatus {
+    Unspent,
+    /// Spent in the mempool or the chain. Not every backend can tell by which
+    /// transaction.
+    Spent { spending_txid: Option<Txid> },
+}
+
+/// Where transactions, blocks and output status are fetched from.
+#[async_trait]
+pub trait ChainSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, ChainSourceError>;
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, ChainSourceError>;
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, ChainSourceError>;
+
+    async fn get_tip(&self) -> Result<ChainTip, ChainSourceError>;
+
+    async fn get_outpoint_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<OutpointStatus, ChainSourceError>;
+}
+
+/// Reads the local node through its JSON-RPC interface.
+pub struct BitcoindSource {
+    client: &'static ChainClient,
+}
+
+impl BitcoindSource {
+    pub fn new(client: &'static ChainClient) -> Self {
+        BitcoindSource { client }
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T, ChainSourceError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&ChainClient) -> Result<T, RpcError> + Send + 'static,
+    {
+        let client = self.client;
+        task::spawn_blocking(move || f(client))
+            .await
+            .map_err(|e| ChainSourceError::Backend(format!("RPC task failed: {}", e)))?
+            .map_err(ChainSourceError::from)
+    }
+}
+
+#[async_trait]
+impl ChainSource for BitcoindSource {
+    fn name(&self) -> &'static str {
+        "bitcoind"
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, ChainSourceError> {
+        let txid = *txid;
+        self.call(move |client| client.get_raw_transaction(&txid)).await
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, ChainSourceError> {
+        let hash = *hash;
+        self.call(move |client| client.get_block(&hash)).await
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, ChainSourceError> {
+        self.call(move |client| client.get_block_hash(height)).await
+    }
+
+    async fn get_tip(&self) -> Result<ChainTip, ChainSourceError> {
+        self.call(|client| {
+            let height = client.get_block_count()?;
+            let hash = client.get_block_hash(height)?;
+            Ok(ChainTip { height, hash })
+        })
+        .await
+    }
+
+    /// bitcoind keeps no spent-output index, so spends carry no spending txid
+    /// and an outpoint that never existed is reported as spent.
+    async fn get_outpoint_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<OutpointStatus, ChainSourceError> {
+        let outpoint = *outpoint;
+        let unspent = self.call(move |client| client.is_unspent(&outpoint)).await?;
+        Ok(if unspent {
+            OutpointStatus::Unspent
+        } else {
+            OutpointStatus::Spent {
+                spending_txid: None,
+            }
+        })
+    }
+}
+
+/// Builds the backend named by `CHAIN_SOURCE` (`bitcoind`, `esplora` or
+/// `electrum`). Without it, pruned nodes fall back to Esplora since they
+/// cannot serve old transactions themselves.
+pub fn chain_source_from_env(is_pruned: bool) -> Result<Box<dyn ChainSource>, ChainSourceError> {
+    dotenv().ok();
+
+    let kind = env::var("CHAIN_SOURCE").unwrap_or_else(|_| {
+        if is_pruned {
+            "esplora".into()
+        } else {
+            "bitcoind".into()
+        }
+    });
+    match kind.to_lowercase().as_str() {
+        "bitcoind" => Ok(Box::new(BitcoindSource::new(rpc::get_rpc_client()?))),
+        "esplora" => {
+            let url = match env::var("ESPLORA_URL") {
+                Ok(url) => url,
+                Err(_) => {
+                    let network = network::get_network();
+                    network::default_esplora_url(network)
+                        .ok_or_else(|| {
+                            ChainSourceError::Backend(format!(
+                                "ESPLORA_URL must be set for CHAIN_SOURCE=esplora on {}",
+                                network
+                            ))
+                        })?
+                        .to_string()
+                }
+            };
+            Ok(Box::new(EsploraSource::with_config(url, EsploraConfig::from_env())))
+        }
+        "electrum" => {
+            let url = env::var("ELECTRUM_URL").map_err(|_| {
+                ChainSourceError::Backend("ELECTRUM_URL must be set for CHAIN_SOURCE=electrum".into())
+            })?;
+            let blocks = BitcoindSource::new(rpc::get_rpc_client()?);
+            Ok(Box::new(ElectrumSource::new(&url, blocks)?))
+        }
+        other => Err(ChainSourceError::Backend(format!(
+            "unknown CHAIN_SOURCE {:?}, expected bitcoind, esplora or electrum",
+            other
+        ))),
+    }
+}
+
+/// Selects the global chain source. Must run once at startup before any
+/// lookups are made.
+pub fn init_chain_source(is_pruned: bool) -> Result<&'static dyn ChainSource, ChainSourceError> {
+    let source = GLOBAL_CHAIN_SOURCE.get_or_try_init(|| chain_source_from_env(is_pruned))?;
+    println!("✅ Using {} as chain source", source.name());
+    Ok(source.as_ref())
+}
+
+pub fn get_chain_source() -> &'static dyn ChainSource {
+    GLOBAL_CHAIN_SOURCE
+        .get()
+        .expect("Chain source used before init_chain_source")
+        .as_ref()
+}
@@ -0,0 +1,92 @@
+use bitcoin::BlockHash;
+use std::collections::VecDeque;
+
+/// How many recent blocks are remembered for fork detection.
+pub const REORG_WINDOW: usize = 144;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackedBlock {
+    pub height: u64,
+    pub hash: BlockHash,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TipUpdate {
+    /// The block extends the current tip.
+    Connected { height: u64 },
+    /// The block forks off below the tip; `disconnected` lists the blocks
+    /// that are no longer part of the active chain, highest first.
+    Reorg {
+        height: u64,
+        disconnected: Vec<TrackedBlock>,
+    },
+    /// The block is already part of the tracked chain.
+    AlreadyKnown { height: u64 },
+    /// The parent is not in the tracked window, so the height is unknown.
+    UnknownParent,
+}
+
+/// Keeps the last few blocks of the active chain and classifies every newly
+/// announced block by comparing its parent hash against them.
+#[derive(Debug)]
+pub struct ChainTracker {
+    blocks: VecDeque<TrackedBlock>,
+    window: usize,
+}
+
+impl ChainTracker {
+    pub fn new(window: usize) -> Self {
+        ChainTracker {
+            blocks: VecDeque::new(),
+            window: window.max(1),
+        }
+    }
+
+    pub fn tip(&self) -> Option<TrackedBlock> {
+        self.blocks.back().copied()
+    }
+
+    /// Forgets the tracked chain and restarts it at the given block. Returns
+    /// the forgotten blocks at or above `height`, highest first, as they can
+    /// no longer be part of the active chain.
+    pub fn reset(&mut self, height: u64, hash: BlockHash) -> Vec<TrackedBlock> {
+        let mut stale: Vec<TrackedBlock> = self
+            .blocks
+            .drain(..)
+            .filter(|b| b.height >= height && b.hash != hash)
+            .collect();
+        stale.reverse();
+        self.blocks.push_back(TrackedBlock { height, hash });
+        stale
+    }
+
+    pub fn connect(&mut self, hash: BlockHash, prev_hash: BlockHash) -> TipUpdate {
+        if let Some(known) = self.blocks.iter().find(|b| b.hash == hash) {
+            return TipUpdate::AlreadyKnown {
+                height: known.height,
+            };
+        }
+
+        let Some(parent_pos) = self.blocks.iter().rposition(|b| b.hash == prev_hash) else {
+            return TipUpdate::UnknownParent;
+        };
+        let height = self.blocks[parent_pos].height + 1;
+
+        let mut disconnected: Vec<TrackedBlock> = self.blocks.drain(parent_pos + 1..).collect();
+        disconnected.reverse();
+
+        self.blocks.push_back(TrackedBlock { height, hash });
+        while self.blocks.len() > self.window {
+            self.blocks.pop_front();
+        }
+
+        if disconnected.is_empty() {
+            TipUpdate::Connected { height }
+        } else {
+            TipUpdate::Reorg {
+                height,
+                disconnected,
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use bitcoin::{Block, BlockHash, Txid};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::chain_tracker::{ChainTracker, TipUpdate, REORG_WINDOW};
+use crate::{db_operations, labels, nostr_notify, prevout_cache, rpc, timelock, tx_watch, utxo_index};
+
+static CHAIN_TRACKER: Lazy<Mutex<ChainTracker>> =
+    Lazy::new(|| Mutex::new(ChainTracker::new(REORG_WINDOW)));
+
+pub type MatchRows = Vec<(String, String, Vec<String>)>;
+
+/// Confirms every previously notified transaction contained in `block`,
+/// rolls back confirmations from blocks it reorganised away, and tells the
+/// affected users about both. The block then becomes the new checkpoint.
+pub async fn process_block(block: Block) {
+    let block_hash = block.block_hash();
+    let update = CHAIN_TRACKER
+        .lock()
+        .unwrap()
+        .connect(block_hash, block.header.prev_blockhash);
+
+    let (height, disconnected) = match update {
+        TipUpdate::Connected { height } => (height, Vec::new()),
+        TipUpdate::AlreadyKnown { height } => {
+            println!("Block {} at height {} already processed", block_hash, height);
+            return;
+        }
+        TipUpdate::Reorg {
+            height,
+            disconnected,
+        } => (height, disconnected),
+        TipUpdate::UnknownParent => {
+            let height = match fetch_block_height(block_hash).await {
+                Some(height) => height,
+                None => return,
+            };
+            let stale = CHAIN_TRACKER.lock().unwrap().reset(height, block_hash);
+            (height, stale)
+        }
+    };
+
+    let mut rolled_back = MatchRows::new();
+    if !disconnected.is_empty() {
+        println!(
+            "⚠️ Reorg at height {}: disconnecting {} block(s)",
+            height,
+            disconnected.len()
+        );
+        let hashes = disconnected.iter().map(|b| b.hash.to_string()).collect();
+        match db_operations::unconfirm_matched_transactions(hashes) {
+            Ok(rows) => rolled_back = rows,
+            Err(e) => eprintln!("❌ Failed to roll back confirmations: {}", e),
+        }
+        utxo_index::unconfirm_blocks(&disconnected);
+    }
+
+    let txids: Vec<String> = block
+        .txdata
+        .iter()
+        .map(|tx| tx.compute_txid().to_string())
+        .collect();
+    println!(
+        "New block {} at height {} with {} transactions",
+        block_hash,
+        height,
+        txids.len()
+    );
+
+    let confirmed = match db_operations::confirm_matched_transactions(
+        txids,
+        block_hash.to_string(),
+        height as i32,
+    ) {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to store confirmations for {}: {}", block_hash, e);
+            MatchRows::new()
+        }
+    };
+
+    utxo_index::confirm_block(&block, height);
+    utxo_index::restore_dropped_spends().await;
+    timelock::process_block(block_hash, height).await;
+    prevout_cache::prune();
+
+    if let Err(e) = db_operations::store_checkpoint(height as i32, block_hash.to_string()) {
+        eprintln!("❌ Failed to store checkpoint at {}: {}", height, e);
+    }
+
+    let reconfirmed: HashSet<String> = confirmed.iter().map(|(_, txid, _)| txid.clone()).collect();
+    for ((user, txid), addrs) in group_by_user_and_tx(confirmed) {
+        let message = format!(
+            "Transaction {} involving your watch list Address {} has been confirmed in block {} ({}).{}",
+            labels::describe_tx(&user, &txid),
+            labels::describe_records(&user, &addrs),
+            height,
+            block_hash,
+            utxo_index::describe_balance(&user, &addrs, None)
+        );
+        nostr_notify::send_message(message, user);
+    }
+    tx_watch::process_block(&block, height).await;
+
+    let rolled_back: MatchRows = rolled_back
+        .into_iter()
+        .filter(|(_, txid, _)| !reconfirmed.contains(txid))
+        .collect();
+    notify_rolled_back(rolled_back).await;
+}
+
+/// Tells users whose matched transactions lost their confirmation whether
+/// they are back in the mempool or were dropped altogether.
+pub async fn notify_rolled_back(rows: MatchRows) {
+    for ((user, txid), addrs) in group_by_user_and_tx(rows) {
+        let tx = labels::describe_tx(&user, &txid);
+        let balance = utxo_index::describe_balance(&user, &addrs, None);
+        let addrs = labels::describe_records(&user, &addrs);
+        let mut message = match is_in_mempool(&txid).await {
+            Some(true) => format!(
+                "Transaction {} involving your watch list Address {} is no longer confirmed after a chain reorganization. It is back in the mempool awaiting confirmation.",
+                tx, addrs
+            ),
+            Some(false) => format!(
+                "Transaction {} involving your watch list Address {} was dropped by a chain reorganization and is no longer in the mempool. It may have been double-spent.",
+                tx, addrs
+            ),
+            None => format!(
+                "Transaction {} involving your watch list Address {} is no longer confirmed after a chain reorganization.",
+                tx, addrs
+            ),
+        };
+        message.push_str(&balance);
+        nostr_notify::send_message(message, user);
+    }
+}
+
+/// A transaction may have matched several times for the same user (as output
+/// and as input), so collapse rows into one entry per user and txid.
+fn group_by_user_and_tx(rows: MatchRows) -> BTreeMap<(String, String), Vec<String>> {
+    let mut per_user_tx: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for (user, txid, addrs) in rows {
+        let entry = per_user_tx.entry((user, txid)).or_default();
+        for addr in addrs {
+            if !entry.contains(&addr) {
+                entry.push(addr);
+            }
+        }
+    }
+    per_user_tx
+}
+
+async fn fetch_block_height(block_hash: BlockHash) -> Option<u64> {
+    match rpc::with_client(move |client| client.get_block_height(&block_hash)).await {
+        Ok(height) => Some(height),
+        Err(e) => {
+            eprintln!("❌ Failed to fetch height of block {}: {}", block_hash, e);
+            None
+        }
+    }
+}
+
+pub async fn is_in_mempool(txid: &str) -> Option<bool> {
+    let txid = Txid::from_str(txid).ok()?;
+    match rpc::with_client(move |client| client.is_in_mempool(&txid)).await {
+        Ok(in_mempool) => Some(in_mempool),
+        Err(e) => {
+            eprintln!("❌ Failed to query mempool for {}: {}", txid, e);
+            None
+        }
+    }
+}
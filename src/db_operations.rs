@@ -1,19 +1,22 @@
-use diesel::debug_query;
-use diesel::pg::Pg;
+use bitcoin::Network;
+use chrono::{Duration, Utc};
 use diesel::{
-    query_dsl::methods::{FilterDsl, SelectDsl},
-    ExpressionMethods, PgConnection, RunQueryDsl,
+    query_dsl::methods::{FilterDsl, OrderDsl, SelectDsl},
+    Connection, ExpressionMethods, OptionalExtension, RunQueryDsl,
 };
 
 use crate::{
     db,
-    models::{MatchedEvent, User, UserAddress},
-    schema::{matched_addresses, user_addresses, users},
+    models::{Checkpoint, GenTransaction, InputTrans, Label, MatchedEvent, RecordType, User, UserAddress, WatchedUtxo, DerivationWindow},
+    schema::{
+        chain_checkpoint, gen_transactions, input_transactions, labels, matched_addresses,
+        user_addresses, users, watched_utxos, derivation_windows,
+    },
 };
 
 pub fn create_new_user(nostr_pubkey: String) -> Result<User, diesel::result::Error> {
     let new_user = User {
-        nostr_pubkey: nostr_pubkey,
+        nostr_pubkey,
     };
     let mut conn = db::get_connection();
     diesel::insert_into(users::table)
@@ -22,21 +25,22 @@ pub fn create_new_user(nostr_pubkey: String) -> Result<User, diesel::result::Err
     Ok(new_user)
 }
 
-pub fn store_user_address(nostr_pubkey: String, address: String) {
+/// Starts watching `record` for the user. Fails with a unique violation if
+/// they already watch it.
+pub fn store_user_address(nostr_pubkey: String, record: RecordType) -> Result<usize, diesel::result::Error> {
     let new_addr = UserAddress {
         nostr_pubkey,
-        address,
+        record,
     };
     let mut conn = db::get_connection();
-
     diesel::insert_into(user_addresses::table)
         .values(new_addr)
-        .execute(&mut *conn);
+        .execute(&mut *conn)
 }
 
 pub fn store_matched_address(
     nostr_pubkey: String,
-    address: Vec<String>,
+    record: Vec<String>,
     txid: String,
     prev_txid: Option<String>,
 ) {
@@ -44,12 +48,67 @@ pub fn store_matched_address(
         nostr_pubkey,
         txid,
         prev_txid,
-        address,
+        record,
     };
     let mut conn = db::get_connection();
-    diesel::insert_into(matched_addresses::table)
+    if let Err(e) = diesel::insert_into(matched_addresses::table)
         .values(new_match)
-        .execute(&mut *conn);
+        .execute(&mut *conn)
+    {
+        eprintln!("❌ Failed to store match: {}", e);
+    }
+}
+
+/// Stops watching `addr` for `user` and returns the number of rows removed.
+pub fn remove_user_address(user: String, addr: RecordType) -> Result<usize, diesel::result::Error> {
+    use self::user_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::delete(
+        user_addresses
+            .filter(nostr_pubkey.eq(user))
+            .filter(kind.eq(addr.kind()))
+            .filter(record.eq(addr)),
+    )
+    .execute(&mut conn)
+}
+
+/// Rewrites stored records that are not in the form they are written in
+/// now, such as descriptors saved without their checksum, so removing them
+/// finds the row. Returns the number of records rewritten.
+pub fn normalize_user_addresses(network: Network) -> Result<usize, diesel::result::Error> {
+    use self::user_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    let rows = user_addresses
+        .select((nostr_pubkey, record, kind))
+        .load::<(String, String, String)>(&mut conn)?;
+    let mut rewritten = 0;
+    for (user, stored, stored_kind) in rows {
+        let Ok(parsed) = RecordType::from_kind(&stored_kind, &stored, network) else {
+            continue;
+        };
+        if parsed.to_string() == stored {
+            continue;
+        }
+        conn.transaction(|conn| {
+            diesel::delete(
+                user_addresses
+                    .filter(nostr_pubkey.eq(&user))
+                    .filter(record.eq(&stored)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(user_addresses)
+                .values(UserAddress {
+                    nostr_pubkey: user.clone(),
+                    record: parsed,
+                })
+                .on_conflict_do_nothing()
+                .execute(conn)
+        })?;
+        rewritten += 1;
+    }
+    Ok(rewritten)
 }
 
 pub fn get_tagged_addresses(user: String) -> Result<Vec<UserAddress>, diesel::result::Error> {
@@ -70,3 +129,417 @@ pub fn get_all_tagged_addresses() -> Result<Vec<UserAddress>, diesel::result::Er
     println!("get_all_tagged_addresses::: {:?}", addrs.len());
     Ok(addrs)
 }
+
+/// Marks the still-unconfirmed matches of `txids` as confirmed in the given
+/// block and returns the affected `(nostr_pubkey, txid, record)` rows.
+pub fn confirm_matched_transactions(
+    txids: Vec<String>,
+    hash: String,
+    height: i32,
+) -> Result<Vec<(String, String, Vec<String>)>, diesel::result::Error> {
+    use self::matched_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(
+        matched_addresses
+            .filter(txid.eq_any(txids))
+            .filter(block_hash.is_null()),
+    )
+    .set((block_hash.eq(hash), block_height.eq(height)))
+    .returning((nostr_pubkey, txid, record))
+    .get_results(&mut conn)
+}
+
+/// Clears the confirmation of every match recorded in one of the given
+/// blocks and returns the affected `(nostr_pubkey, txid, record)` rows.
+pub fn unconfirm_matched_transactions(
+    hashes: Vec<String>,
+) -> Result<Vec<(String, String, Vec<String>)>, diesel::result::Error> {
+    use self::matched_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(matched_addresses.filter(block_hash.eq_any(hashes)))
+        .set((
+            block_hash.eq(None::<String>),
+            block_height.eq(None::<i32>),
+        ))
+        .returning((nostr_pubkey, txid, record))
+        .get_results(&mut conn)
+}
+
+/// Clears the confirmation of every match recorded above `height`, for
+/// blocks that were reorganised away while the monitor was not running.
+pub fn unconfirm_matched_above(
+    height: i32,
+) -> Result<Vec<(String, String, Vec<String>)>, diesel::result::Error> {
+    use self::matched_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(matched_addresses.filter(block_height.gt(height)))
+        .set((
+            block_hash.eq(None::<String>),
+            block_height.eq(None::<i32>),
+        ))
+        .returning((nostr_pubkey, txid, record))
+        .get_results(&mut conn)
+}
+
+/// Every stored match of `txids` as `(nostr_pubkey, txid, block_height)`,
+/// the height being `None` while unconfirmed.
+pub fn get_match_confirmations(
+    txids: Vec<String>,
+) -> Result<Vec<(String, String, Option<i32>)>, diesel::result::Error> {
+    use self::matched_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    matched_addresses
+        .filter(txid.eq_any(txids))
+        .select((nostr_pubkey, txid, block_height))
+        .load(&mut conn)
+}
+
+/// Returns which of `txids` already have a stored match.
+pub fn get_matched_txids(txids: Vec<String>) -> Result<Vec<String>, diesel::result::Error> {
+    use self::matched_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    matched_addresses
+        .filter(txid.eq_any(txids))
+        .select(txid)
+        .load::<String>(&mut conn)
+}
+
+pub fn get_checkpoint() -> Result<Option<Checkpoint>, diesel::result::Error> {
+    use self::chain_checkpoint::dsl::*;
+
+    let mut conn = db::get_connection();
+    chain_checkpoint
+        .filter(id.eq(1))
+        .first::<Checkpoint>(&mut conn)
+        .optional()
+}
+
+pub fn store_checkpoint(height: i32, hash: String) -> Result<(), diesel::result::Error> {
+    use self::chain_checkpoint::dsl::*;
+
+    let checkpoint = Checkpoint {
+        id: 1,
+        block_height: height,
+        block_hash: hash,
+    };
+    let mut conn = db::get_connection();
+    diesel::insert_into(chain_checkpoint)
+        .values(&checkpoint)
+        .on_conflict(id)
+        .do_update()
+        .set((
+            block_height.eq(&checkpoint.block_height),
+            block_hash.eq(&checkpoint.block_hash),
+        ))
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Stores an already confirmed match discovered by a backfill, without the
+/// live notification flow.
+pub fn store_history_match(
+    user: String,
+    addrs: Vec<String>,
+    tx: String,
+    prev_tx: Option<String>,
+    hash: String,
+    height: i32,
+) -> Result<(), diesel::result::Error> {
+    use self::matched_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::insert_into(matched_addresses)
+        .values((
+            nostr_pubkey.eq(user),
+            txid.eq(tx),
+            prev_txid.eq(prev_tx),
+            record.eq(addrs),
+            block_hash.eq(Some(hash)),
+            block_height.eq(Some(height)),
+        ))
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Output addresses and hex scripts of a stored transaction.
+pub type StoredOutputs = (Vec<Option<String>>, Vec<String>);
+
+/// Outputs of `tx_id` if it was spilled from the prevout cache, either as a
+/// fetched or as a processed transaction.
+pub fn get_cached_outputs(tx_id: String) -> Result<Option<StoredOutputs>, diesel::result::Error> {
+    let mut conn = db::get_connection();
+    let fetched = input_transactions::table
+        .filter(input_transactions::txid.eq(&tx_id))
+        .select((input_transactions::output_address, input_transactions::output_script))
+        .first::<StoredOutputs>(&mut conn)
+        .optional()?;
+    if fetched.is_some() {
+        return Ok(fetched);
+    }
+    gen_transactions::table
+        .filter(gen_transactions::txid.eq(&tx_id))
+        .select((gen_transactions::output_address, gen_transactions::output_script))
+        .first::<StoredOutputs>(&mut conn)
+        .optional()
+}
+
+pub fn store_gen_transactions(rows: Vec<GenTransaction>) -> Result<(), diesel::result::Error> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut conn = db::get_connection();
+    diesel::insert_into(gen_transactions::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+pub fn store_input_transactions(rows: Vec<InputTrans>) -> Result<(), diesel::result::Error> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut conn = db::get_connection();
+    diesel::insert_into(input_transactions::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Deletes transactions spilled from the prevout cache more than `days`
+/// days ago. Returns how many rows were deleted.
+pub fn prune_cached_outputs(days: i64) -> Result<usize, diesel::result::Error> {
+    let cutoff = Utc::now() - Duration::days(days);
+    let mut conn = db::get_connection();
+    conn.transaction(|conn| {
+        let processed = diesel::delete(
+            gen_transactions::table.filter(gen_transactions::spilled_at.lt(cutoff)),
+        )
+        .execute(conn)?;
+        let fetched = diesel::delete(
+            input_transactions::table.filter(input_transactions::spilled_at.lt(cutoff)),
+        )
+        .execute(conn)?;
+        Ok(processed + fetched)
+    })
+}
+
+pub fn store_watched_utxos(rows: Vec<WatchedUtxo>) -> Result<(), diesel::result::Error> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut conn = db::get_connection();
+    diesel::insert_into(watched_utxos::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+/// Every watched output not known to be spent in a block.
+pub fn get_unspent_watched_utxos() -> Result<Vec<WatchedUtxo>, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    watched_utxos
+        .filter(spent_height.is_null())
+        .load::<WatchedUtxo>(&mut conn)
+}
+
+/// Marks the outpoint `prev_txid:prev_vout` as spent by the unconfirmed
+/// `spending_txid`.
+pub fn spend_watched_utxo(
+    prev_txid: String,
+    prev_vout: i32,
+    spending_txid: String,
+) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(
+        watched_utxos
+            .filter(txid.eq(prev_txid))
+            .filter(vout.eq(prev_vout))
+            .filter(spent_height.is_null()),
+    )
+    .set(spent_by.eq(Some(spending_txid)))
+    .execute(&mut conn)
+}
+
+/// Marks the outpoint `prev_txid:prev_vout` as spent by `spending_txid`
+/// confirmed at `height`.
+pub fn confirm_watched_spend(
+    prev_txid: String,
+    prev_vout: i32,
+    spending_txid: String,
+    height: i32,
+) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(
+        watched_utxos
+            .filter(txid.eq(prev_txid))
+            .filter(vout.eq(prev_vout)),
+    )
+    .set((spent_by.eq(Some(spending_txid)), spent_height.eq(Some(height))))
+    .execute(&mut conn)
+}
+
+/// Clears the unconfirmed spend by `spending_txid` once it left the mempool.
+pub fn unspend_watched_utxos(spending_txid: String) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(
+        watched_utxos
+            .filter(spent_by.eq(spending_txid))
+            .filter(spent_height.is_null()),
+    )
+    .set(spent_by.eq(None::<String>))
+    .execute(&mut conn)
+}
+
+/// Marks the unconfirmed watched outputs created by `txids` as confirmed in
+/// the given block.
+pub fn confirm_watched_utxos(
+    txids: Vec<String>,
+    hash: String,
+    height: i32,
+) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(
+        watched_utxos
+            .filter(txid.eq_any(txids))
+            .filter(block_hash.is_null()),
+    )
+    .set((block_hash.eq(hash), block_height.eq(height)))
+    .execute(&mut conn)
+}
+
+/// Clears the confirmation of watched outputs created in the given blocks.
+pub fn unconfirm_watched_utxos(hashes: Vec<String>) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(watched_utxos.filter(block_hash.eq_any(hashes)))
+        .set((
+            block_hash.eq(None::<String>),
+            block_height.eq(None::<i32>),
+        ))
+        .execute(&mut conn)
+}
+
+/// Marks spends confirmed at or above `height` as unconfirmed again.
+pub fn unconfirm_watched_spends(height: i32) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::update(watched_utxos.filter(spent_height.ge(height)))
+        .set(spent_height.eq(None::<i32>))
+        .execute(&mut conn)
+}
+
+/// Forgets the outputs a user held through `label` once they stop watching it.
+pub fn remove_watched_utxos(user: String, label: String) -> Result<usize, diesel::result::Error> {
+    use self::watched_utxos::dsl::*;
+
+    let mut conn = db::get_connection();
+    diesel::delete(
+        watched_utxos
+            .filter(nostr_pubkey.eq(user))
+            .filter(record.eq(label)),
+    )
+    .execute(&mut conn)
+}
+
+/// Whether any user still watches `addr`.
+pub fn is_record_watched(addr: &RecordType) -> Result<bool, diesel::result::Error> {
+    use self::user_addresses::dsl::*;
+
+    let mut conn = db::get_connection();
+    let watcher = user_addresses
+        .filter(kind.eq(addr.kind()))
+        .filter(record.eq(addr.clone()))
+        .select(nostr_pubkey)
+        .first::<String>(&mut conn)
+        .optional()?;
+    Ok(watcher.is_some())
+}
+
+pub fn get_derivation_windows() -> Result<Vec<DerivationWindow>, diesel::result::Error> {
+    let mut conn = db::get_connection();
+    derivation_windows::table.load::<DerivationWindow>(&mut conn)
+}
+
+pub fn store_derivation_windows(rows: Vec<DerivationWindow>) -> Result<(), diesel::result::Error> {
+    use diesel::upsert::excluded;
+
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut conn = db::get_connection();
+    diesel::insert_into(derivation_windows::table)
+        .values(&rows)
+        .on_conflict((derivation_windows::record, derivation_windows::chain))
+        .do_update()
+        .set(derivation_windows::next_index.eq(excluded(derivation_windows::next_index)))
+        .execute(&mut conn)?;
+    Ok(())
+}
+
+pub fn remove_derivation_windows(id: String) -> Result<usize, diesel::result::Error> {
+    let mut conn = db::get_connection();
+    diesel::delete(derivation_windows::table.filter(derivation_windows::record.eq(id)))
+        .execute(&mut conn)
+}
+
+/// Stores labels, replacing any the user already gave the same reference.
+pub fn store_labels(rows: Vec<Label>) -> Result<usize, diesel::result::Error> {
+    use diesel::upsert::excluded;
+
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let mut conn = db::get_connection();
+    conn.transaction(|conn| {
+        let mut stored = 0;
+        // Postgres takes at most 65535 bind parameters per statement.
+        for chunk in rows.chunks(5000) {
+            stored += diesel::insert_into(labels::table)
+                .values(chunk)
+                .on_conflict((labels::nostr_pubkey, labels::label_type, labels::reference))
+                .do_update()
+                .set((
+                    labels::label.eq(excluded(labels::label)),
+                    labels::origin.eq(excluded(labels::origin)),
+                    labels::spendable.eq(excluded(labels::spendable)),
+                ))
+                .execute(conn)?;
+        }
+        Ok(stored)
+    })
+}
+
+pub fn get_labels(user: String) -> Result<Vec<Label>, diesel::result::Error> {
+    use self::labels::dsl::*;
+
+    let mut conn = db::get_connection();
+    labels
+        .filter(nostr_pubkey.eq(user))
+        .order((label_type, reference))
+        .load::<Label>(&mut conn)
+}
+
+pub fn get_all_labels() -> Result<Vec<Label>, diesel::result::Error> {
+    let mut conn = db::get_connection();
+    labels::table.load::<Label>(&mut conn)
+}
@@ -0,0 +1,229 @@
+use bitcoin::{Script, ScriptBuf};
+use dotenvy::dotenv;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::env;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use crate::db_operations;
+use crate::models::{DerivationWindow, RecordType};
+
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+static GAP_LIMIT: Lazy<u32> = Lazy::new(|| {
+    dotenv().ok();
+    env::var("XPUB_GAP_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(DEFAULT_GAP_LIMIT)
+});
+
+static WINDOWS: Lazy<Mutex<DerivationWindows>> =
+    Lazy::new(|| Mutex::new(DerivationWindows::default()));
+
+/// Number of derivation chains of a record: receive and change for an xpub,
+/// one per path for a descriptor, none for records that don't derive.
+fn chain_count(record: &RecordType) -> usize {
+    match record {
+        RecordType::Xpub(_) => 2,
+        RecordType::Descriptor(descriptor) => descriptor.chain_count(),
+        _ => 0,
+    }
+}
+
+fn derive(record: &RecordType, chain: u32, indexes: Range<u32>) -> Vec<ScriptBuf> {
+    match record {
+        RecordType::Xpub(key) => key.scripts(chain, indexes),
+        RecordType::Descriptor(descriptor) => descriptor.scripts(chain, indexes),
+        _ => Vec::new(),
+    }
+}
+
+/// Scripts derived for every watched xpub and descriptor. Each chain is
+/// derived up to `gap_limit` scripts past its last used index.
+#[derive(Default)]
+pub struct DerivationWindows {
+    /// record -> index after the last used one, per chain.
+    next_unused: HashMap<String, Vec<u32>>,
+    /// record -> scripts derived so far, per chain.
+    derived: HashMap<String, Vec<Vec<ScriptBuf>>>,
+    /// Derived script -> (record, chain, index) of everyone it was derived for.
+    positions: HashMap<ScriptBuf, Vec<(RecordType, u32, u32)>>,
+}
+
+impl DerivationWindows {
+    /// Derives `record` up to its current window and returns all its scripts.
+    pub fn extend(&mut self, record: &RecordType, gap_limit: u32) -> Vec<ScriptBuf> {
+        let id = record.to_string();
+        let chains = chain_count(record);
+        let next_unused = self.next_unused.get(&id).cloned().unwrap_or_default();
+        let derived = self.derived.entry(id).or_default();
+        derived.resize(chains, Vec::new());
+        for (chain, scripts) in derived.iter_mut().enumerate() {
+            let start = scripts.len() as u32;
+            let end = next_unused.get(chain).copied().unwrap_or(0) + gap_limit;
+            let new_scripts = derive(record, chain as u32, start..end);
+            for (index, script) in (start..end).zip(new_scripts) {
+                self.positions
+                    .entry(script.clone())
+                    .or_default()
+                    .push((record.clone(), chain as u32, index));
+                scripts.push(script);
+            }
+        }
+        derived.iter().flatten().cloned().collect()
+    }
+
+    /// Moves the window of every record one of `scripts` was derived from
+    /// past it. Returns the records whose window moved.
+    pub fn mark_used<'a>(
+        &mut self,
+        scripts: impl IntoIterator<Item = &'a Script>,
+    ) -> Vec<RecordType> {
+        let mut moved: HashMap<String, RecordType> = HashMap::new();
+        for script in scripts {
+            let Some(positions) = self.positions.get(script) else {
+                continue;
+            };
+            for (record, chain, index) in positions {
+                let id = record.to_string();
+                let next_unused = self.next_unused.entry(id.clone()).or_default();
+                let chain = *chain as usize;
+                if next_unused.len() <= chain {
+                    next_unused.resize(chain + 1, 0);
+                }
+                if *index >= next_unused[chain] {
+                    next_unused[chain] = index + 1;
+                    moved.insert(id, record.clone());
+                }
+            }
+        }
+        moved.into_values().collect()
+    }
+
+    /// The record, chain and index `script` was first derived from.
+    pub fn origin(&self, script: &Script) -> Option<(RecordType, u32, u32)> {
+        self.positions.get(script)?.first().cloned()
+    }
+
+    /// Drops everything derived for `record` and how far it was used.
+    pub fn forget(&mut self, record: &RecordType) {
+        let id = record.to_string();
+        self.next_unused.remove(&id);
+        for script in self.derived.remove(&id).into_iter().flatten().flatten() {
+            if let Some(positions) = self.positions.get_mut(&script) {
+                positions.retain(|(derived_for, _, _)| derived_for != record);
+                if positions.is_empty() {
+                    self.positions.remove(&script);
+                }
+            }
+        }
+    }
+
+    /// Index after the last used one on each chain of `record`.
+    pub fn next_unused(&self, record: &RecordType) -> Vec<u32> {
+        self.next_unused
+            .get(&record.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+pub fn gap_limit() -> u32 {
+    *GAP_LIMIT
+}
+
+/// Restores how far each record has been used from `derivation_windows`.
+/// Runs once at startup, before the watched records are indexed.
+pub fn load() {
+    let rows = match db_operations::get_derivation_windows() {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch derivation windows from DB: {}", e);
+            return;
+        }
+    };
+    let mut windows = WINDOWS.lock().unwrap_or_else(|e| e.into_inner());
+    for row in rows {
+        let chain = row.chain.max(0) as usize;
+        let next_unused = windows.next_unused.entry(row.record).or_default();
+        if next_unused.len() <= chain {
+            next_unused.resize(chain + 1, 0);
+        }
+        next_unused[chain] = row.next_index.max(0) as u32;
+    }
+}
+
+/// Every script currently watched for `record`: each chain up to the gap
+/// limit past its last used index.
+pub fn watched_scripts(record: &RecordType) -> Vec<ScriptBuf> {
+    WINDOWS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend(record, gap_limit())
+}
+
+/// Notes that `scripts` appeared in a transaction. Records they were derived
+/// from get their window moved, which is stored, and are returned so the
+/// newly derived scripts can be watched.
+pub fn note_used<'a>(scripts: impl IntoIterator<Item = &'a Script>) -> Vec<RecordType> {
+    let mut windows = WINDOWS.lock().unwrap_or_else(|e| e.into_inner());
+    let moved = windows.mark_used(scripts);
+    let rows: Vec<DerivationWindow> = moved
+        .iter()
+        .flat_map(|record| {
+            let id = record.to_string();
+            windows
+                .next_unused(record)
+                .into_iter()
+                .enumerate()
+                .map(move |(chain, next_index)| DerivationWindow {
+                    record: id.clone(),
+                    chain: chain as i32,
+                    next_index: next_index as i32,
+                })
+        })
+        .collect();
+    drop(windows);
+
+    if let Err(e) = db_operations::store_derivation_windows(rows) {
+        eprintln!("❌ Failed to store derivation windows: {}", e);
+    }
+    if !moved.is_empty() {
+        println!("🔄 Extended the derivation window of {} record(s)", moved.len());
+    }
+    moved
+}
+
+/// Stops deriving `record` once no user watches it any more, and removes
+/// its stored window.
+pub fn forget(record: &RecordType) {
+    if chain_count(record) == 0 {
+        return;
+    }
+    match db_operations::is_record_watched(record) {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(e) => {
+            eprintln!("❌ Failed to check watchers of {}: {}", record, e);
+            return;
+        }
+    }
+    WINDOWS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .forget(record);
+    if let Err(e) = db_operations::remove_derivation_windows(record.to_string()) {
+        eprintln!("❌ Failed to remove derivation windows of {}: {}", record, e);
+    }
+}
+
+/// The record, chain and index a watched script was derived from.
+pub fn origin(script: &Script) -> Option<(RecordType, u32, u32)> {
+    WINDOWS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .origin(script)
+}
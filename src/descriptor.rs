@@ -0,0 +1,110 @@
+use bitcoin::{Network, NetworkKind, ScriptBuf};
+use bitcoin::bip32::ChildNumber;
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey, Wildcard};
+use miniscript::ForEachKey;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// An output descriptor with public keys only, such as `wpkh(...)`,
+/// `wsh(sortedmulti(...))` or `tr(...)`. Ranged descriptors (`/*`) are
+/// derived like an xpub; multipath ones (`/<0;1>/*`) are split into one
+/// chain per path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WatchedDescriptor {
+    descriptor: Descriptor<DescriptorPublicKey>,
+    /// One single-path descriptor per chain.
+    chains: Vec<Descriptor<DescriptorPublicKey>>,
+}
+
+impl WatchedDescriptor {
+    /// Parses a descriptor, checking its checksum when one is given and
+    /// requiring its extended keys to belong to `network`.
+    pub fn parse(s: &str, network: Network) -> Result<Self, &'static str> {
+        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(s.trim())
+            .map_err(|_| "Invalid descriptor")?;
+
+        let kind = NetworkKind::from(network);
+        let same_network = descriptor.for_each_key(|key| match key {
+            DescriptorPublicKey::Single(_) => true,
+            DescriptorPublicKey::XPub(xkey) => xkey.xkey.network == kind,
+            DescriptorPublicKey::MultiXPub(xkey) => xkey.xkey.network == kind,
+        });
+        if !same_network {
+            return Err("Descriptor keys belong to a different network");
+        }
+
+        // Hardened steps after an xpub can't be derived without the private key.
+        if !descriptor.for_each_key(is_unhardened) {
+            return Err("Descriptor cannot be derived from public keys");
+        }
+
+        let chains = descriptor
+            .clone()
+            .into_single_descriptors()
+            .map_err(|_| "Invalid descriptor")?;
+        Ok(WatchedDescriptor { descriptor, chains })
+    }
+
+    pub fn chain_count(&self) -> usize {
+        self.chains.len()
+    }
+
+    /// Scripts at `indexes` of `chain`. A descriptor without a wildcard has
+    /// a single script, at index 0.
+    pub fn scripts(&self, chain: u32, indexes: Range<u32>) -> Vec<ScriptBuf> {
+        let Some(descriptor) = self.chains.get(chain as usize) else {
+            return Vec::new();
+        };
+        let indexes = if descriptor.has_wildcard() {
+            indexes
+        } else {
+            indexes.start.min(1)..indexes.end.min(1)
+        };
+        indexes
+            .map_while(|index| descriptor.at_derivation_index(index).ok())
+            .map(|derived| derived.script_pubkey())
+            .collect()
+    }
+
+    /// Scripts able to spend the output at `index` of `chain`: the witness
+    /// or redeem script, or every leaf script of a taproot descriptor.
+    pub fn spending_scripts(&self, chain: u32, index: u32) -> Vec<ScriptBuf> {
+        let Some(descriptor) = self.chains.get(chain as usize) else {
+            return Vec::new();
+        };
+        let Ok(derived) = descriptor.at_derivation_index(index) else {
+            return Vec::new();
+        };
+        match derived {
+            Descriptor::Tr(tr) => tr.iter_scripts().map(|(_, leaf)| leaf.encode()).collect(),
+            derived => derived.explicit_script().into_iter().collect(),
+        }
+    }
+}
+
+fn is_unhardened(key: &DescriptorPublicKey) -> bool {
+    match key {
+        DescriptorPublicKey::Single(_) => true,
+        DescriptorPublicKey::XPub(xkey) => {
+            xkey.wildcard != Wildcard::Hardened
+                && xkey.derivation_path.into_iter().all(ChildNumber::is_normal)
+        }
+        DescriptorPublicKey::MultiXPub(xkey) => {
+            xkey.wildcard != Wildcard::Hardened
+                && xkey
+                    .derivation_paths
+                    .paths()
+                    .iter()
+                    .all(|path| path.into_iter().all(ChildNumber::is_normal))
+        }
+    }
+}
+
+/// Written with its checksum, so the same descriptor entered with or without
+/// one is the same record.
+impl fmt::Display for WatchedDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.descriptor)
+    }
+}
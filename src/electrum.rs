@@ -0,0 +1,264 @@
+use async_trait::async_trait;
+use bitcoin::block::Header;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Block, BlockHash, OutPoint, Script, Transaction, Txid};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::chain_source::{BitcoindSource, ChainSource, ChainSourceError, ChainTip, OutpointStatus};
+
+impl From<std::io::Error> for ChainSourceError {
+    fn from(e: std::io::Error) -> Self {
+        ChainSourceError::Backend(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ChainSourceError {
+    fn from(e: serde_json::Error) -> Self {
+        ChainSourceError::Backend(e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct HeaderNotification {
+    height: u64,
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct ScripthashUnspent {
+    tx_hash: Txid,
+    tx_pos: u32,
+}
+
+#[derive(Deserialize)]
+struct ScripthashHistory {
+    tx_hash: Txid,
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    reader: BufReader<Box<dyn Stream>>,
+    next_id: u64,
+}
+
+/// Electrum server (electrs, Fulcrum, ElectrumX) spoken to over TCP or TLS.
+/// Electrum has no call for full blocks, so those come from `blocks`.
+pub struct ElectrumSource {
+    addr: String,
+    /// Server name checked against the certificate, for `ssl://` URLs.
+    tls: Option<ServerName<'static>>,
+    connection: Mutex<Option<Connection>>,
+    blocks: BitcoindSource,
+}
+
+impl ElectrumSource {
+    /// Accepts `ssl://host:port`, `tcp://host:port` or `host:port`. Server
+    /// certificates are checked against the Mozilla root store, so servers
+    /// with self-signed certificates have to be reached over TCP.
+    pub fn new(url: &str, blocks: BitcoindSource) -> Result<Self, ChainSourceError> {
+        let (addr, tls) = match url.strip_prefix("ssl://") {
+            Some(addr) => {
+                let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+                let name = ServerName::try_from(host.to_string()).map_err(|_| {
+                    ChainSourceError::Backend(format!("invalid Electrum server name {:?}", host))
+                })?;
+                (addr, Some(name))
+            }
+            None => (url.trim_start_matches("tcp://"), None),
+        };
+        Ok(ElectrumSource {
+            addr: addr.to_string(),
+            tls,
+            connection: Mutex::new(None),
+            blocks,
+        })
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, ChainSourceError> {
+        let mut guard = self.connection.lock().await;
+        if guard.is_none() {
+            *guard = Some(self.connect().await?);
+        }
+        let connection = guard.as_mut().unwrap();
+
+        let result = Self::request(connection, method, params).await;
+        if let Err(ChainSourceError::Backend(_)) = result {
+            // Drop a possibly broken socket so the next call reconnects.
+            *guard = None;
+        }
+        result
+    }
+
+    async fn connect(&self) -> Result<Connection, ChainSourceError> {
+        let tcp = TcpStream::connect(&self.addr).await?;
+        let stream: Box<dyn Stream> = match &self.tls {
+            Some(name) => {
+                let roots = RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .map_err(|e| ChainSourceError::Backend(e.to_string()))?
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let connector = TlsConnector::from(Arc::new(config));
+                Box::new(connector.connect(name.clone(), tcp).await?)
+            }
+            None => Box::new(tcp),
+        };
+        let mut connection = Connection {
+            reader: BufReader::new(stream),
+            next_id: 0,
+        };
+        Self::request(
+            &mut connection,
+            "server.version",
+            json!(["utxo-monitor", "1.4"]),
+        )
+        .await?;
+        Ok(connection)
+    }
+
+    async fn request(
+        connection: &mut Connection,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, ChainSourceError> {
+        connection.next_id += 1;
+        let id = connection.next_id;
+        let mut line = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+            .to_string();
+        line.push('\n');
+        connection.reader.get_mut().write_all(line.as_bytes()).await?;
+
+        loop {
+            let mut response = String::new();
+            if connection.reader.read_line(&mut response).await? == 0 {
+                return Err(ChainSourceError::Backend("Electrum server closed the connection".into()));
+            }
+            let response: Value = serde_json::from_str(&response)?;
+            // Skip subscription notifications, which carry no id.
+            if response["id"] != json!(id) {
+                continue;
+            }
+            if !response["error"].is_null() {
+                let message = response["error"]["message"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| response["error"].to_string());
+                let lowercase = message.to_lowercase();
+                return Err(if lowercase.contains("no such") || lowercase.contains("not found") {
+                    ChainSourceError::NotFound(format!("{}: {}", method, message))
+                } else {
+                    ChainSourceError::Backend(format!("{}: {}", method, message))
+                });
+            }
+            return Ok(response["result"].clone());
+        }
+    }
+
+    async fn get_header(&self, height: u64) -> Result<Header, ChainSourceError> {
+        let hex = self.call("blockchain.block.header", json!([height])).await?;
+        decode_hex(hex.as_str().unwrap_or_default(), "header")
+    }
+}
+
+/// Electrum indexes outputs by the reversed SHA256 of their script.
+pub fn script_hash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).to_byte_array();
+    hash.reverse();
+    hex::encode(hash)
+}
+
+fn decode_hex<T: bitcoin::consensus::Decodable>(hex: &str, what: &str) -> Result<T, ChainSourceError> {
+    let bytes = hex::decode(hex)
+        .map_err(|e| ChainSourceError::Backend(format!("invalid {} hex: {}", what, e)))?;
+    deserialize(&bytes).map_err(|e| ChainSourceError::Backend(format!("invalid {}: {}", what, e)))
+}
+
+#[async_trait]
+impl ChainSource for ElectrumSource {
+    fn name(&self) -> &'static str {
+        "electrum"
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, ChainSourceError> {
+        let hex = self
+            .call("blockchain.transaction.get", json!([txid.to_string()]))
+            .await?;
+        decode_hex(hex.as_str().unwrap_or_default(), "transaction")
+    }
+
+    /// Fetched from the node, as Electrum servers don't serve full blocks.
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, ChainSourceError> {
+        self.blocks.get_block(hash).await
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, ChainSourceError> {
+        Ok(self.get_header(height).await?.block_hash())
+    }
+
+    async fn get_tip(&self) -> Result<ChainTip, ChainSourceError> {
+        let result = self.call("blockchain.headers.subscribe", json!([])).await?;
+        let tip: HeaderNotification = serde_json::from_value(result)?;
+        let header: Header = decode_hex(&tip.hex, "header")?;
+        Ok(ChainTip {
+            height: tip.height,
+            hash: header.block_hash(),
+        })
+    }
+
+    /// Looks the output up through its script hash: unspent if listed by
+    /// `listunspent`, otherwise spent by whichever history entry consumes it.
+    async fn get_outpoint_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<OutpointStatus, ChainSourceError> {
+        let funding = self.get_transaction(&outpoint.txid).await?;
+        let output = funding
+            .output
+            .get(outpoint.vout as usize)
+            .ok_or_else(|| ChainSourceError::NotFound(outpoint.to_string()))?;
+        let scripthash = script_hash(&output.script_pubkey);
+
+        let unspent: Vec<ScripthashUnspent> = serde_json::from_value(
+            self.call("blockchain.scripthash.listunspent", json!([scripthash]))
+                .await?,
+        )?;
+        if unspent
+            .iter()
+            .any(|u| u.tx_hash == outpoint.txid && u.tx_pos == outpoint.vout)
+        {
+            return Ok(OutpointStatus::Unspent);
+        }
+
+        let history: Vec<ScripthashHistory> = serde_json::from_value(
+            self.call("blockchain.scripthash.get_history", json!([scripthash]))
+                .await?,
+        )?;
+        for entry in history.iter().filter(|h| h.tx_hash != outpoint.txid) {
+            let tx = self.get_transaction(&entry.tx_hash).await?;
+            if tx.input.iter().any(|input| input.previous_output == *outpoint) {
+                return Ok(OutpointStatus::Spent {
+                    spending_txid: Some(entry.tx_hash),
+                });
+            }
+        }
+        Ok(OutpointStatus::Spent {
+            spending_txid: None,
+        })
+    }
+}
@@ -0,0 +1,333 @@
+use async_trait::async_trait;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::{Block, BlockHash, OutPoint, Transaction, Txid};
+use dotenvy::dotenv;
+use reqwest::{header, StatusCode};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
+
+use crate::chain_source::{ChainSource, ChainSourceError, ChainTip, OutpointStatus};
+
+impl From<reqwest::Error> for ChainSourceError {
+    fn from(e: reqwest::Error) -> Self {
+        ChainSourceError::Backend(e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct OutspendResponse {
+    spent: bool,
+    txid: Option<Txid>,
+}
+
+/// Limits for talking to a (usually public) Esplora server.
+#[derive(Debug, Clone)]
+pub struct EsploraConfig {
+    /// Sustained request rate.
+    pub requests_per_second: f64,
+    /// Requests allowed in a burst before the rate applies.
+    pub burst: u32,
+    /// Retries after a 429, a 5xx or a connection error.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Raw transactions kept in memory.
+    pub cache_size: usize,
+}
+
+impl Default for EsploraConfig {
+    fn default() -> Self {
+        EsploraConfig {
+            requests_per_second: 5.0,
+            burst: 10,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            cache_size: 10_000,
+        }
+    }
+}
+
+impl EsploraConfig {
+    /// Reads `ESPLORA_REQUESTS_PER_SECOND`, `ESPLORA_BURST`,
+    /// `ESPLORA_MAX_RETRIES` and `ESPLORA_CACHE_SIZE`, keeping the default for
+    /// anything unset or unparsable.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        fn var<T: FromStr>(name: &str) -> Option<T> {
+            env::var(name).ok()?.parse().ok()
+        }
+
+        let default = EsploraConfig::default();
+        EsploraConfig {
+            requests_per_second: var("ESPLORA_REQUESTS_PER_SECOND")
+                .filter(|rate: &f64| *rate > 0.0)
+                .unwrap_or(default.requests_per_second),
+            burst: var("ESPLORA_BURST").unwrap_or(default.burst),
+            max_retries: var("ESPLORA_MAX_RETRIES").unwrap_or(default.max_retries),
+            cache_size: var("ESPLORA_CACHE_SIZE").unwrap_or(default.cache_size),
+            ..default
+        }
+    }
+}
+
+/// Token bucket shared by every request of one client. Callers reserve a
+/// token up front and sleep off any deficit, so waiters are served in order.
+struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(refill_per_sec: f64, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        TokenBucket {
+            capacity,
+            refill_per_sec,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.refill_per_sec)
+                .min(self.capacity);
+            *last = now;
+            *tokens -= 1.0;
+            if *tokens < 0.0 {
+                Some(Duration::from_secs_f64(-*tokens / self.refill_per_sec))
+            } else {
+                None
+            }
+        };
+        if let Some(wait) = wait {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Bounded raw transaction cache, evicting the oldest entry when full.
+struct TxCache {
+    capacity: usize,
+    entries: HashMap<Txid, Transaction>,
+    order: VecDeque<Txid>,
+}
+
+impl TxCache {
+    fn new(capacity: usize) -> Self {
+        TxCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, txid: &Txid) -> Option<Transaction> {
+        self.entries.get(txid).cloned()
+    }
+
+    fn insert(&mut self, txid: Txid, tx: Transaction) {
+        if self.capacity == 0 || self.entries.insert(txid, tx).is_some() {
+            return;
+        }
+        self.order.push_back(txid);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+type InFlight = Arc<OnceCell<Result<Transaction, ChainSourceError>>>;
+
+/// Esplora-compatible REST API such as mempool.space or blockstream.info.
+/// Requests are rate limited and retried with backoff, concurrent lookups of
+/// the same transaction share one request, and fetched transactions are
+/// cached.
+pub struct EsploraSource {
+    base_url: String,
+    http: reqwest::Client,
+    config: EsploraConfig,
+    limiter: TokenBucket,
+    cache: Mutex<TxCache>,
+    in_flight: Mutex<HashMap<Txid, InFlight>>,
+}
+
+impl EsploraSource {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_config(base_url, EsploraConfig::default())
+    }
+
+    pub fn with_config(base_url: impl Into<String>, config: EsploraConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap_or_default();
+        EsploraSource {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http,
+            limiter: TokenBucket::new(config.requests_per_second, config.burst),
+            cache: Mutex::new(TxCache::new(config.cache_size)),
+            in_flight: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    async fn get_bytes(&self, path: &str) -> Result<Vec<u8>, ChainSourceError> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            self.limiter.acquire().await;
+            let (error, retry_after) = match self.http.get(&url).send().await {
+                Ok(response) => match response.status() {
+                    StatusCode::OK => return Ok(response.bytes().await?.to_vec()),
+                    StatusCode::NOT_FOUND => {
+                        return Err(ChainSourceError::NotFound(path.to_string()))
+                    }
+                    status if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() => {
+                        let retry_after = response
+                            .headers()
+                            .get(header::RETRY_AFTER)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| value.trim().parse().ok())
+                            .map(Duration::from_secs);
+                        (format!("{} returned {}", url, status), retry_after)
+                    }
+                    status => {
+                        return Err(ChainSourceError::Backend(format!(
+                            "{} returned {}",
+                            url, status
+                        )))
+                    }
+                },
+                Err(e) if e.is_connect() || e.is_timeout() => (e.to_string(), None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if attempt >= self.config.max_retries {
+                return Err(ChainSourceError::Backend(format!(
+                    "{} (gave up after {} attempts)",
+                    error,
+                    attempt + 1
+                )));
+            }
+            attempt += 1;
+            let delay = retry_after.unwrap_or(backoff).min(self.config.max_backoff);
+            eprintln!("⚠️ {}, retrying in {:?}", error, delay);
+            sleep(delay).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+        }
+    }
+
+    async fn get_text(&self, path: &str) -> Result<String, ChainSourceError> {
+        let bytes = self.get_bytes(path).await?;
+        String::from_utf8(bytes)
+            .map(|text| text.trim().to_string())
+            .map_err(|e| ChainSourceError::Backend(format!("{} returned invalid UTF-8: {}", path, e)))
+    }
+
+    async fn fetch_transaction(&self, txid: Txid) -> Result<Transaction, ChainSourceError> {
+        let hex = self.get_text(&format!("/tx/{}/hex", txid)).await?;
+        let bytes = hex::decode(&hex)
+            .map_err(|e| ChainSourceError::Backend(format!("invalid hex for tx {}: {}", txid, e)))?;
+        let tx: Transaction = deserialize(&bytes)
+            .map_err(|e| ChainSourceError::Backend(format!("invalid tx {}: {}", txid, e)))?;
+        self.cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(txid, tx.clone());
+        Ok(tx)
+    }
+}
+
+#[async_trait]
+impl ChainSource for EsploraSource {
+    fn name(&self) -> &'static str {
+        "esplora"
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction, ChainSourceError> {
+        let txid = *txid;
+        if let Some(tx) = self.cache.lock().unwrap_or_else(|e| e.into_inner()).get(&txid) {
+            return Ok(tx);
+        }
+
+        // Whoever registers the txid first fetches it, later callers wait for
+        // that result instead of sending their own request.
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(txid)
+            .or_default()
+            .clone();
+        let result = cell
+            .get_or_init(|| self.fetch_transaction(txid))
+            .await
+            .clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if in_flight
+            .get(&txid)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(&txid);
+        }
+        result
+    }
+
+    async fn get_block(&self, hash: &BlockHash) -> Result<Block, ChainSourceError> {
+        let bytes = self.get_bytes(&format!("/block/{}/raw", hash)).await?;
+        deserialize(&bytes)
+            .map_err(|e| ChainSourceError::Backend(format!("invalid block {}: {}", hash, e)))
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, ChainSourceError> {
+        let text = self.get_text(&format!("/block-height/{}", height)).await?;
+        BlockHash::from_str(&text)
+            .map_err(|e| ChainSourceError::Backend(format!("invalid block hash {:?}: {}", text, e)))
+    }
+
+    async fn get_tip(&self) -> Result<ChainTip, ChainSourceError> {
+        let height = self.get_text("/blocks/tip/height").await?;
+        let height = height
+            .parse()
+            .map_err(|e| ChainSourceError::Backend(format!("invalid tip height {:?}: {}", height, e)))?;
+        let hash = self.get_text("/blocks/tip/hash").await?;
+        let hash = BlockHash::from_str(&hash)
+            .map_err(|e| ChainSourceError::Backend(format!("invalid tip hash {:?}: {}", hash, e)))?;
+        Ok(ChainTip { height, hash })
+    }
+
+    async fn get_outpoint_status(
+        &self,
+        outpoint: &OutPoint,
+    ) -> Result<OutpointStatus, ChainSourceError> {
+        let bytes = self
+            .get_bytes(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))
+            .await?;
+        let outspend: OutspendResponse = serde_json::from_slice(&bytes)
+            .map_err(|e| ChainSourceError::Backend(format!("invalid outspend for {}: {}", outpoint, e)))?;
+        Ok(if outspend.spent {
+            OutpointStatus::Spent {
+                spending_txid: outspend.txid,
+            }
+        } else {
+            OutpointStatus::Unspent
+        })
+    }
+}
@@ -0,0 +1,253 @@
+use bitcoin::address::Address;
+use bitcoin::{Network, OutPoint, PublicKey, ScriptBuf, Txid};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use crate::db_operations;
+use crate::descriptor::WatchedDescriptor;
+use crate::models::{Label, RecordType};
+use crate::network;
+use crate::xpub::ExtendedKey;
+
+static LABELS: Lazy<RwLock<LabelIndex>> = Lazy::new(|| RwLock::new(LabelIndex::default()));
+
+/// Labels longer than this are truncated on import, as BIP-329 suggests.
+const MAX_LABEL_CHARS: usize = 255;
+
+/// Every user's labels by `(nostr_pubkey, type, ref)`, kept in memory so
+/// notifications can name what they are about.
+#[derive(Debug, Default)]
+pub struct LabelIndex {
+    labels: HashMap<(String, String, String), String>,
+}
+
+impl LabelIndex {
+    /// Sets a label, or clears it when empty.
+    pub fn set(&mut self, label: &Label) {
+        let key = (
+            label.nostr_pubkey.clone(),
+            label.label_type.clone(),
+            label.reference.clone(),
+        );
+        if label.label.is_empty() {
+            self.labels.remove(&key);
+        } else {
+            self.labels.insert(key, label.label.clone());
+        }
+    }
+
+    pub fn get(&self, user: &str, label_type: &str, reference: &str) -> Option<&str> {
+        self.labels
+            .get(&(user.to_string(), label_type.to_string(), reference.to_string()))
+            .map(String::as_str)
+    }
+
+    /// `shown` preceded by the label `user` gave its reference, if any.
+    pub fn name(&self, user: &str, label_type: &str, reference: &str, shown: &str) -> String {
+        match self.get(user, label_type, reference) {
+            Some(label) => format!("{} ({})", label, shown),
+            None => shown.to_string(),
+        }
+    }
+
+    /// Names the records behind the labels of a match, as in "Cold storage
+    /// #2 (bc1q...)".
+    pub fn describe_records(&self, user: &str, records: &[String], network: Network) -> String {
+        records
+            .iter()
+            .map(|record| match RecordType::parse(record, network) {
+                Ok(parsed) => {
+                    let (label_type, reference) = reference(&parsed, network);
+                    self.name(user, label_type, &reference, record)
+                }
+                Err(_) => record.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+/// The BIP-329 type and reference a record is labelled under. Scripts
+/// without an address and descriptors have no BIP-329 type and use their
+/// record kind, which other wallets skip on import.
+pub fn reference(record: &RecordType, network: Network) -> (&'static str, String) {
+    match record {
+        RecordType::Address(addr) => ("addr", addr.to_string()),
+        RecordType::Script(script) => match Address::from_script(script, network) {
+            Ok(addr) => ("addr", addr.to_string()),
+            Err(_) => ("script", script.to_hex_string()),
+        },
+        RecordType::Xpub(key) => ("xpub", key.encoded().to_string()),
+        RecordType::Utxo(outpoint) => ("output", outpoint.to_string()),
+        RecordType::Descriptor(descriptor) => ("descriptor", descriptor.to_string()),
+        RecordType::Pubkey(key) => ("pubkey", key.to_string()),
+        RecordType::Txid(watch) => ("tx", watch.txid.to_string()),
+    }
+}
+
+/// The reference of a label in the form it is stored and looked up in.
+/// `None` for types this monitor does not know.
+fn normalize_reference(
+    label_type: &str,
+    reference: &str,
+    network: Network,
+) -> Option<Result<String, &'static str>> {
+    let normalized = match label_type {
+        "tx" => Txid::from_str(reference)
+            .map(|txid| txid.to_string())
+            .map_err(|_| "Invalid txid"),
+        "addr" => Address::from_str(reference)
+            .map_err(|_| "Invalid address")
+            .and_then(|addr| {
+                addr.require_network(network)
+                    .map_err(|_| "Address belongs to a different network")
+            })
+            .map(|addr| addr.to_string()),
+        "pubkey" => PublicKey::from_str(reference)
+            .map(|key| key.to_string())
+            .map_err(|_| "Invalid public key"),
+        "input" | "output" => OutPoint::from_str(reference)
+            .map(|outpoint| outpoint.to_string())
+            .map_err(|_| "Invalid outpoint"),
+        "xpub" => ExtendedKey::parse(reference).map(|key| key.encoded().to_string()),
+        "script" => ScriptBuf::from_hex(reference)
+            .map(|script| script.to_hex_string())
+            .map_err(|_| "Invalid script"),
+        "descriptor" => {
+            WatchedDescriptor::parse(reference, network).map(|descriptor| descriptor.to_string())
+        }
+        _ => return None,
+    };
+    Some(normalized)
+}
+
+/// A parsed BIP-329 export.
+#[derive(Debug, Default)]
+pub struct LabelImport {
+    pub labels: Vec<Label>,
+    /// Lines of a type this monitor does not know.
+    pub skipped: usize,
+    /// Line numbers, from 1, that could not be read.
+    pub invalid: Vec<usize>,
+}
+
+/// Reads a BIP-329 JSON Lines export for `user`. A reference labelled twice
+/// keeps its last label.
+pub fn parse_export(user: &str, jsonl: &str, network: Network) -> LabelImport {
+    let mut import = LabelImport::default();
+    let mut positions: HashMap<(String, String), usize> = HashMap::new();
+    for (number, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(mut label) = serde_json::from_str::<Label>(line) else {
+            import.invalid.push(number + 1);
+            continue;
+        };
+        match normalize_reference(&label.label_type, &label.reference, network) {
+            Some(Ok(reference)) => label.reference = reference,
+            Some(Err(_)) => {
+                import.invalid.push(number + 1);
+                continue;
+            }
+            None => {
+                import.skipped += 1;
+                continue;
+            }
+        }
+        label.nostr_pubkey = user.to_string();
+        label.label = label.label.chars().take(MAX_LABEL_CHARS).collect();
+
+        let key = (label.label_type.clone(), label.reference.clone());
+        match positions.get(&key) {
+            Some(position) => import.labels[*position] = label,
+            None => {
+                positions.insert(key, import.labels.len());
+                import.labels.push(label);
+            }
+        }
+    }
+    import
+}
+
+/// Writes labels as a BIP-329 JSON Lines export.
+pub fn write_export(labels: &[Label]) -> String {
+    labels
+        .iter()
+        .filter_map(|label| serde_json::to_string(label).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Loads every stored label. Runs once at startup.
+pub fn load() {
+    let rows = match db_operations::get_all_labels() {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch labels from DB: {}", e);
+            return;
+        }
+    };
+    let mut index = LABELS.write().unwrap_or_else(|e| e.into_inner());
+    for label in rows.iter() {
+        index.set(label);
+    }
+    println!("✅ Loaded {} label(s)", index.len());
+}
+
+/// Stores labels and makes them show in notifications.
+pub fn store(labels: Vec<Label>) -> Result<usize, diesel::result::Error> {
+    let stored = db_operations::store_labels(labels.clone())?;
+    let mut index = LABELS.write().unwrap_or_else(|e| e.into_inner());
+    for label in labels.iter() {
+        index.set(label);
+    }
+    Ok(stored)
+}
+
+/// Labels `record` for `user`.
+pub fn label_record(user: &str, record: &RecordType, label: &str) -> Result<usize, diesel::result::Error> {
+    let (label_type, reference) = reference(record, network::get_network());
+    store(vec![Label {
+        nostr_pubkey: user.to_string(),
+        label_type: label_type.to_string(),
+        reference,
+        label: label.chars().take(MAX_LABEL_CHARS).collect(),
+        origin: None,
+        spendable: None,
+    }])
+}
+
+pub fn describe_records(user: &str, records: &[String]) -> String {
+    LABELS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .describe_records(user, records, network::get_network())
+}
+
+pub fn describe_tx(user: &str, txid: impl fmt::Display) -> String {
+    let txid = txid.to_string();
+    LABELS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .name(user, "tx", &txid, &txid)
+}
+
+pub fn describe_output(user: &str, outpoint: &OutPoint) -> String {
+    let outpoint = outpoint.to_string();
+    LABELS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .name(user, "output", &outpoint, &outpoint)
+}
@@ -1,39 +1,83 @@
-use actix_web::{App, HttpServer, Result};
+use actix_web::{web, App, HttpServer, Result};
 use bitcoin::address::Address;
 use bitcoin::consensus::encode::deserialize;
+use bitcoin::key::XOnlyPublicKey;
 use bitcoin::network::Network;
-use bitcoin::Transaction;
-use hex;
-use models::{GenTransaction, InputTrans};
-use serde_json::Value;
-use std::collections::{HashMap, HashSet};
-use std::io::{Error, ErrorKind};
-use std::process::{exit, Command};
-use std::str::FromStr;
+use bitcoin::{Block, OutPoint, Script, ScriptBuf, Transaction, Txid};
+use prevout_cache::CachedOutputs;
+use watch_index::WatchUpdate;
+use rpc::RpcError;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Arc;
+use std::thread;
 use tokio::task;
-use zmq;
 
+pub mod backfill;
+pub mod chain_source;
+pub mod chain_tracker;
+pub mod confirmations;
 pub mod db;
 pub mod db_operations;
+pub mod derivation;
+pub mod descriptor;
+pub mod electrum;
+pub mod esplora;
+pub mod labels;
 pub mod models;
+pub mod network;
 pub mod nostr_notify;
+pub mod pipeline;
+pub mod prevout_cache;
+pub mod pubkey;
+pub mod rescan;
 pub mod routes;
+pub mod rpc;
 pub mod schema;
-pub mod tests;
-
-// Maps nostr_pubkey -> [Bitcoin RecordType]
-type Pikachus = HashMap<String, Vec<RecordType>>;
+pub mod spent_script;
+#[cfg(test)]
+mod tests;
+pub mod timelock;
+pub mod tx_watch;
+pub mod utxo_index;
+pub mod wallet_import;
+pub mod watch_index;
+pub mod xpub;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    // Records and addresses are parsed for the node's network, so it has to
+    // be known before anything is loaded or the routes start.
+    let (node_network, is_pruned) =
+        inspect_bitcoin_node().map_err(Error::other)?;
+    network::init_network(node_network).map_err(Error::other)?;
+    chain_source::init_chain_source(is_pruned).map_err(Error::other)?;
+
+    // Load watched records before the routes can change them.
+    derivation::load();
+    watch_index::load();
+    tx_watch::load();
+    labels::load();
+    watch_index::spawn_updater();
+    utxo_index::load();
+    timelock::load();
+
     task::spawn(async move {
         println!("🚀 HTTP server running at 127.0.0.1:9090");
         if let Err(e) = HttpServer::new(move || {
             App::new()
+                // Label exports of busy wallets exceed the default 256 KiB.
+                .app_data(web::PayloadConfig::new(16 * 1024 * 1024))
                 .service(routes::index)
                 .service(routes::store_user)
                 .service(routes::store_monitored_addresses)
                 .service(routes::get_monitored_addresses)
+                .service(routes::remove_monitored_address)
+                .service(routes::import_wallet)
+                .service(routes::get_timelocks)
+                .service(routes::get_balances)
+                .service(routes::export_labels)
+                .service(routes::import_labels)
         })
         .bind("127.0.0.1:9090")
         .expect("Failed to bind to port 9090")
@@ -44,7 +88,6 @@ async fn main() -> Result<()> {
         }
     });
 
-    let is_pruned = is_bitcoin_node_pruned()?;
     let context = zmq::Context::new();
     let subscriber = context.socket(zmq::SUB).expect("Failed to create socket");
 
@@ -56,196 +99,285 @@ async fn main() -> Result<()> {
     subscriber
         .set_subscribe(b"rawtx")
         .expect("Failed to subscribe to rawtx");
+    subscriber
+        .set_subscribe(b"rawblock")
+        .expect("Failed to subscribe to rawblock");
 
-    println!("Listening for Bitcoin transactions on {}", zmq_url);
-
-    loop {
-        let topic = subscriber.recv_string(0);
-        match topic {
-            Ok(Ok(_)) => {
-                let tx_data = subscriber.recv_bytes(0).expect("Failed to receive tx data");
-                let tx_hex = hex::encode(&tx_data);
+    // Subscribe before catching up so blocks found meanwhile are queued.
+    rescan::catch_up().await;
 
-                // println!("New transaction received:: {:?}", tx_hex);
+    let config = pipeline::PipelineConfig::from_env();
+    println!(
+        "Listening for Bitcoin transactions and blocks on {} with {} workers",
+        zmq_url, config.workers
+    );
 
-                if let Ok(tx) = deserialize::<Transaction>(&tx_data) {
-                    find_address_match(tx, is_pruned).await;
-                } else {
-                    println!("Failed to decode transaction.");
-                }
+    let on_tx: pipeline::Handler = Arc::new(|data| {
+        Box::pin(async move {
+            if let Ok(tx) = deserialize::<Transaction>(&data) {
+                find_address_match(tx).await;
+            } else {
+                println!("Failed to decode transaction.");
             }
-            Ok(Err(_)) => println!("Received non-UTF8 topic:"),
-            Err(e) => eprintln!("Error receiving message: {}", e),
-        }
+        })
+    });
+    let on_block: pipeline::Handler = Arc::new(|data| {
+        Box::pin(async move {
+            if let Ok(block) = deserialize::<Block>(&data) {
+                confirmations::process_block(block).await;
+            } else {
+                println!("Failed to decode block.");
+            }
+        })
+    });
+    let (intake, blocks) = pipeline::start(config, on_tx, on_block);
+    pipeline::spawn_stats_logger();
+    thread::spawn(move || pipeline::read_zmq(subscriber, intake));
+
+    if let Err(e) = blocks.await {
+        eprintln!("❌ Block processing stopped: {}", e);
     }
+    Ok(())
 }
 
-fn process_outputs(tx: &Transaction) -> Vec<Address> {
+/// Address of every output by index, `None` where the script has none.
+fn process_outputs(tx: &Transaction) -> Vec<Option<String>> {
     // println!("\n🔹 **Detecting DELIVERY (Receiver) Addresses**:");
-    let mut outs: Vec<Address> = [].to_vec();
+    let mut outs: Vec<Option<String>> = [].to_vec();
     for (i, output) in tx.output.iter().enumerate() {
-        if let Ok(addr) = Address::from_script(&output.script_pubkey, Network::Bitcoin) {
+        if let Ok(addr) = Address::from_script(&output.script_pubkey, network::get_network()) {
             // println!(
             //     "Output {}: Address = {} (Amount: {} sats)",
             //     i, addr, output.value
             // );
-            outs.push(addr);
+            outs.push(Some(addr.to_string()));
         } else {
             println!("Output {}: Could not decode address", i);
+            outs.push(None);
         }
     }
     outs
 }
 
-async fn process_inputs(tx: &Transaction, is_pruned: bool) -> Vec<InputTrans> {
+/// The output an input spends, as recorded in its parent transaction.
+struct SpentOutput {
+    prev_txid: Txid,
+    script: ScriptBuf,
+    address: Option<String>,
+}
+
+/// The output spent by each input, `None` for coinbase inputs and parents
+/// that could not be found.
+async fn process_inputs(tx: &Transaction) -> Vec<Option<SpentOutput>> {
     // println!("\n🔹 **Detecting SOURCING (Sender) Addresses**:");
     let mut inputs = Vec::new();
-    for (i, input) in tx.input.iter().enumerate() {
-        let prev_txid = input.previous_output.txid.to_string();
+    for input in tx.input.iter() {
+        // Coinbase inputs have no previous transaction to look up.
+        if input.previous_output.is_null() {
+            inputs.push(None);
+            continue;
+        }
+        let prev_txid = input.previous_output.txid;
+        let vout = input.previous_output.vout as usize;
 
         // println!("Input {}: Spends from previous TXID {}", i, prev_txid);
 
-        if let Some(prev_tx) = fetch_previous_tx(&prev_txid, &is_pruned).await {
-            // println!("Fetched previous transaction: {}", prev_txid);
-            let temp = process_outputs(&prev_tx);
-            inputs.push(InputTrans {
-                txid: prev_txid,
-                output_address: temp,
-            });
-        } else {
-            // println!("❌ Failed to fetch previous transaction for {}", prev_txid);
+        // Watched outputs are known by outpoint, and most other spends reveal
+        // enough to rebuild the script they spend.
+        if let Some(script) = utxo_index::script_of(&input.previous_output) {
+            let address = Address::from_script(&script, network::get_network())
+                .ok()
+                .map(|addr| addr.to_string());
+            inputs.push(Some(SpentOutput {
+                prev_txid,
+                script,
+                address,
+            }));
+            continue;
+        }
+        if let Some(script) = spent_script::reconstruct(input) {
+            spent_script::record(true);
+            let address = Address::from_script(&script, network::get_network())
+                .ok()
+                .map(|addr| addr.to_string());
+            inputs.push(Some(SpentOutput {
+                prev_txid,
+                script,
+                address,
+            }));
+            continue;
         }
+        spent_script::record(false);
+
+        let spent = prevout_cache::lookup(&prev_txid).await.and_then(|prev| {
+            Some(SpentOutput {
+                prev_txid,
+                script: prev.output_script.get(vout)?.clone(),
+                address: prev.output_address.get(vout).cloned().flatten(),
+            })
+        });
+        inputs.push(spent);
     }
     inputs
 }
 
-async fn find_address_match(tx: Transaction, is_pruned: bool) {
-    let inputs = process_inputs(&tx, is_pruned).await;
-    let outputs = process_outputs(&tx);
-    let genesis = GenTransaction {
-        txid: tx.compute_txid().to_string(),
-        output_address: outputs,
-        input_address: inputs,
-    };
-    // println!("{:?}", genesis);
-    let pikachus = process_tagged_addresses_from_db();
-    let genesis_outs: HashSet<_> = genesis.output_address.iter().collect();
+async fn find_address_match(tx: Transaction) {
+    let inputs = process_inputs(&tx).await;
+    let txid = tx.compute_txid();
+    prevout_cache::remember(
+        txid,
+        CachedOutputs {
+            output_address: process_outputs(&tx),
+            output_script: tx.output.iter().map(|o| o.script_pubkey.clone()).collect(),
+            input_address: Some(
+                inputs
+                    .iter()
+                    .map(|spent| spent.as_ref().and_then(|s| s.address.clone()))
+                    .collect(),
+            ),
+        },
+    );
     println!(
-        "Users to Monitor on behalf::{}, TX input addrs::{}, TX output addrs::{} ",
-        pikachus.len(),
-        genesis_outs.len(),
-        genesis.input_address.len()
+        "TX outputs::{}, TX inputs::{} ",
+        tx.output.len(),
+        inputs.iter().flatten().count()
     );
 
-    pikachus.iter().for_each(|(user, user_addrs)| {
-        let matching_outs: Vec<_> = user_addrs
-            .iter()
-            .filter(|addr| genesis_outs.contains(addr))
-            .map(|f| f.to_string())
-            .collect();
-
-        if !matching_outs.is_empty() {
-           let message = format!(
-                "Your watch list Address {:?} has been spent in this tx {}",
-                matching_outs, genesis.txid
-            );
-            db_operations::store_matched_address(user.clone(), (*matching_outs).to_vec(), genesis.txid.clone(),None);
-            nostr_notify::send_message(message,user.to_string());
-        }
-
-        genesis.input_address.iter().for_each(|f|{
-            let genesis_ins:Vec<_> =f.output_address.iter().collect();
-            let matching_ins:Vec<_>=user_addrs.iter().filter(|addr| genesis_ins.contains(addr)).map(|f| f.to_string()).collect();
-            if !matching_ins.is_empty(){
-                let message = format!(
-                    "Your watch list Address {:?} has been spent as an input for this tx {}. The input was previously funnded by this tx: {}",
-                    matching_ins, genesis.txid,f.txid
-                );
-                db_operations::store_matched_address(user.clone(), (*matching_ins).to_vec(), genesis.txid.clone(),Some(f.txid.clone()));
-            nostr_notify::send_message(message,user.to_string());
-            }
-
-        });
-    });
-}
-
-async fn fetch_previous_tx(prev_txid: &str, is_pruned: &bool) -> Option<Transaction> {
-    if *is_pruned {
-        let url = format!("https://mempool.space/api/tx/{}/hex", prev_txid);
-        // println!("{}", url);
-        let response = reqwest::get(&url).await;
-        match response {
-            Ok(res) => {
-                if res.status() == 200 {
-                    let txt = res.text().await.ok().unwrap();
-                    let tx_bytes = hex::decode(&txt.trim()).ok().unwrap();
-                    return deserialize(&tx_bytes).ok();
+    // Workers finish in any order, so the index is changed in arrival order
+    // to never see a spend before the output it spends.
+    let matched_outs = pipeline::in_turn(|| {
+        let mut matched_outs: HashMap<String, Vec<String>> = HashMap::new();
+        for (vout, output) in tx.output.iter().enumerate() {
+            for (user, labels) in watch_index::match_scripts([output.script_pubkey.as_script()]) {
+                for label in labels {
+                    utxo_index::add(
+                        OutPoint::new(txid, vout as u32),
+                        utxo_index::Coin {
+                            user: user.clone(),
+                            record: label.clone(),
+                            script: output.script_pubkey.clone(),
+                            value: output.value,
+                            block_height: None,
+                        },
+                    );
+                    let matching_outs = matched_outs.entry(user.clone()).or_default();
+                    if !matching_outs.contains(&label) {
+                        matching_outs.push(label);
+                    }
                 }
             }
-            Err(e) => eprintln!("Error receiving message: {}", e),
         }
-        None
-    } else {
-        let output = Command::new("bitcoin-cli")
-            .arg("getrawtransaction")
-            .arg(prev_txid)
-            .arg("1")
-            .output()
-            .expect("Failed to execute bitcoin-cli");
-
-        if output.status.success() {
-            return deserialize(&output.stdout).ok();
-        } else {
-            None
+        for input in tx.input.iter() {
+            utxo_index::spend(&input.previous_output, txid);
         }
+        matched_outs
+    })
+    .await;
+    for (user, matching_outs) in matched_outs {
+        let message = format!(
+            "Your watch list Address {} has been spent in this tx {}.{}",
+            labels::describe_records(&user, &matching_outs),
+            labels::describe_tx(&user, txid),
+            utxo_index::describe_balance(&user, &matching_outs, Some(&tx))
+        );
+        db_operations::store_matched_address(user.clone(), matching_outs, txid.to_string(), None);
+        nostr_notify::send_message(message, user);
     }
-}
 
-/// Checks if the local bitcoind node is pruned or not
-fn is_bitcoin_node_pruned() -> Result<bool,Error> {
-    let output = Command::new("bitcoin-cli")
-        .arg("getblockchaininfo")
-        .output()?;
-
-            if output.status.success() {
-                let raw_json = String::from_utf8_lossy(&output.stdout);
-                let json: Value = serde_json::from_str(&raw_json).expect("Failed to parse JSON");
-                println!("✅ Bitcoin node network:: {}", json["chain"]);
-                if let Some(pruned) = json["pruned"].as_bool() {
-                    if pruned {
-                        println!(
-                            "✅ Bitcoin node is pruned. Prune height: {}",
-                            json["pruneheight"]
-                        );
-                        return Ok(true);
-                    }
-                }
-                Ok(false)
-            } else {
-                println!("{:?}", String::from_utf8_lossy(&output.stderr));
-                Err(Error::new(ErrorKind::Other, String::from_utf8_lossy(&output.stderr)))
-            }
+    // One notification per funding transaction, covering every output of it
+    // this transaction spends.
+    let mut by_parent: Vec<(Txid, Vec<&Script>)> = Vec::new();
+    for spent in inputs.iter().flatten() {
+        match by_parent.iter_mut().find(|(parent, _)| *parent == spent.prev_txid) {
+            Some((_, scripts)) => scripts.push(spent.script.as_script()),
+            None => by_parent.push((spent.prev_txid, vec![spent.script.as_script()])),
+        }
+    }
+    for (parent, scripts) in by_parent {
+        for (user, matching_ins) in watch_index::match_scripts(scripts) {
+            let message = format!(
+                "Your watch list Address {} has been spent as an input for this tx {}. The input was previously funnded by this tx: {}.{}",
+                labels::describe_records(&user, &matching_ins),
+                labels::describe_tx(&user, txid),
+                labels::describe_tx(&user, parent),
+                utxo_index::describe_balance(&user, &matching_ins, Some(&tx))
+            );
+            db_operations::store_matched_address(user.clone(), matching_ins, txid.to_string(), Some(parent.to_string()));
+            nostr_notify::send_message(message, user);
+        }
     }
 
-fn process_tagged_addresses_from_db() -> Pikachus {
-    let all_addr = db_operations::get_all_tagged_addresses();
-    let mut pikachus = Pikachus::default();
-
-    if let Ok(t) = all_addr {
-        for e in t {
-            let entry = pikachus
-                .entry(e.nostr_pubkey.clone())
-                .or_insert_with(Vec::new);
-            if let Ok(addr) = Address::from_str(&e.address) {
-                entry.push(Address::assume_checked(addr));
-            } else {
-                eprintln!("❌ Failed to parse address::{}", e.address);
-            }
+    // Keys in multisig and script-path spends, which no script derived from
+    // a watched key matches.
+    let revealed_keys: Vec<XOnlyPublicKey> =
+        tx.input.iter().flat_map(pubkey::revealed_keys).collect();
+    for (user, matching_keys) in watch_index::match_keys(revealed_keys.iter()) {
+        let message = format!(
+            "Your watched public key {} appears in a script spent by this tx {}.{}",
+            labels::describe_records(&user, &matching_keys),
+            labels::describe_tx(&user, txid),
+            utxo_index::describe_balance(&user, &matching_keys, Some(&tx))
+        );
+        db_operations::store_matched_address(user.clone(), matching_keys, txid.to_string(), None);
+        nostr_notify::send_message(message, user);
+    }
+
+    tx_watch::process_tx(&tx);
+
+    let spent_outpoints = tx.input.iter().map(|input| &input.previous_output);
+    let utxo_matches = watch_index::match_outpoints(spent_outpoints);
+    if !utxo_matches.is_empty() {
+        let destinations = describe_outputs(&tx);
+        for (user, matching_utxos) in utxo_matches {
+            let message = format!(
+                "Your watched UTXO {} has been spent in this tx {}. The funds went to: {}.{}",
+                labels::describe_records(&user, &matching_utxos),
+                labels::describe_tx(&user, txid),
+                destinations,
+                utxo_index::describe_balance(&user, &matching_utxos, Some(&tx))
+            );
+            db_operations::store_matched_address(user.clone(), matching_utxos, txid.to_string(), None);
+            nostr_notify::send_message(message, user);
         }
-    } else {
-        eprintln!("❌ Failed to fetch addresses from DB.");
     }
 
-    pikachus
+    // Keep watching past the gap limit of xpubs and descriptors whose
+    // addresses got used.
+    let seen_scripts = tx
+        .output
+        .iter()
+        .map(|output| output.script_pubkey.as_script())
+        .chain(inputs.iter().flatten().map(|spent| spent.script.as_script()));
+    for record in derivation::note_used(seen_scripts) {
+        watch_index::send(WatchUpdate::Refresh { record });
+    }
+}
+
+/// Where a transaction sends its funds: each output's address, or its
+/// script when it has none, with the amount.
+fn describe_outputs(tx: &Transaction) -> String {
+    tx.output
+        .iter()
+        .map(|output| {
+            let destination = Address::from_script(&output.script_pubkey, network::get_network())
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| output.script_pubkey.to_hex_string());
+            format!("{} ({} BTC)", destination, output.value.to_btc())
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Returns the network the local bitcoind node runs on and whether it is pruned
+fn inspect_bitcoin_node() -> Result<(Network, bool), RpcError> {
+    let info = rpc::get_rpc_client()?.get_blockchain_info()?;
+    println!("✅ Bitcoin node network:: {}", info.chain);
+    if info.pruned {
+        println!(
+            "✅ Bitcoin node is pruned. Prune height: {:?}",
+            info.prune_height
+        );
+    }
+    Ok((info.chain, info.pruned))
 }
 
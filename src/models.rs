@@ -1,24 +1,47 @@
 use bitcoin::address::Address;
+use bitcoin::{Network, OutPoint, PublicKey, ScriptBuf};
 use diesel::deserialize::{self, FromSql};
+use diesel::dsl;
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
-use diesel::serialize::{self, Output, ToSql};
-use serde::Serialize;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Text;
+use diesel::{AsExpression, FromSqlRow};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
 
+use crate::derivation;
+use crate::descriptor::WatchedDescriptor;
+use crate::network;
+use crate::pubkey;
+use crate::tx_watch::TxWatch;
+use crate::schema::user_addresses;
+use crate::xpub::ExtendedKey;
+
+/// A transaction the monitor processed: the address of every output and of
+/// the output spent by every input, `None` where there is no address, and
+/// the hex script of every output.
 #[derive(Debug, Insertable, Queryable, Serialize)]
 #[diesel(table_name = crate::schema::gen_transactions)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct GenTransaction {
     pub txid: String,
-    pub output_address: Vec<Address>,
-    pub input_address: Vec<InputTrans>,
+    pub output_address: Vec<Option<String>>,
+    pub input_address: Vec<Option<String>>,
+    pub output_script: Vec<String>,
 }
 
+/// A transaction fetched because one of its outputs was spent, with the
+/// address and hex script of every output.
 #[derive(Debug, Insertable, Queryable, Serialize)]
 #[diesel(table_name = crate::schema::input_transactions)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct InputTrans {
     pub txid: String,
-    pub output_address: Vec<Address>,
+    pub output_address: Vec<Option<String>>,
+    pub output_script: Vec<String>,
 }
 
 #[derive(Debug, Insertable, Queryable, Serialize)]
@@ -29,14 +52,44 @@ pub struct User {
     pub nostr_pubkey: String,
 }
 
-#[derive(Debug, Insertable, Queryable, Serialize)]
-#[diesel(table_name = crate::schema::user_addresses)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+/// A record a user watches. Stored as its kind and its normalised value,
+/// so it is read back as the record it was saved as.
+#[derive(Debug, Serialize)]
 pub struct UserAddress {
     pub nostr_pubkey: String,
     pub record: RecordType,
 }
 
+impl Queryable<(Text, Text, Text), Pg> for UserAddress {
+    type Row = (String, String, String);
+
+    fn build((nostr_pubkey, record, kind): Self::Row) -> deserialize::Result<Self> {
+        let record = RecordType::from_kind(&kind, &record, network::get_network())?;
+        Ok(UserAddress {
+            nostr_pubkey,
+            record,
+        })
+    }
+}
+
+impl Insertable<user_addresses::table> for UserAddress {
+    type Values = <(
+        dsl::Eq<user_addresses::nostr_pubkey, String>,
+        dsl::Eq<user_addresses::record, RecordType>,
+        dsl::Eq<user_addresses::kind, &'static str>,
+    ) as Insertable<user_addresses::table>>::Values;
+
+    fn values(self) -> Self::Values {
+        let kind = self.record.kind();
+        (
+            user_addresses::nostr_pubkey.eq(self.nostr_pubkey),
+            user_addresses::record.eq(self.record),
+            user_addresses::kind.eq(kind),
+        )
+            .values()
+    }
+}
+
 #[derive(Debug, Insertable, Queryable, Serialize)]
 #[diesel(table_name = crate::schema::matched_addresses)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -44,43 +97,236 @@ pub struct MatchedEvent {
     pub nostr_pubkey:String,
     pub txid: String,
     pub prev_txid: Option<String>,
-    pub record: Vec<RecordType>,
+    /// Labels of the records that matched, as shown in the notification.
+    pub record: Vec<String>,
 
 }
 
+/// An output paying to a record a user watches. `spent_by` is set once a
+/// transaction spending it is seen, and `spent_height` once that confirms.
+#[derive(Debug, Clone, Insertable, Queryable, Serialize)]
+#[diesel(table_name = crate::schema::watched_utxos)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WatchedUtxo {
+    pub txid: String,
+    pub vout: i32,
+    pub nostr_pubkey: String,
+    pub record: String,
+    pub script_pubkey: String,
+    pub value: i64,
+    pub block_hash: Option<String>,
+    pub block_height: Option<i32>,
+    pub spent_by: Option<String>,
+    pub spent_height: Option<i32>,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-enum RecordType {
+/// How far the scripts derived from an xpub or descriptor record have been
+/// used: the index after the last used one on `chain`.
+#[derive(Debug, Insertable, Queryable, Serialize)]
+#[diesel(table_name = crate::schema::derivation_windows)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DerivationWindow {
+    pub record: String,
+    pub chain: i32,
+    pub next_index: i32,
+}
+
+/// A BIP-329 label, serialised as one line of a BIP-329 export. The owner
+/// is not part of the export.
+#[derive(Debug, Clone, PartialEq, Insertable, Queryable, Serialize, Deserialize)]
+#[diesel(table_name = crate::schema::labels)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Label {
+    #[serde(skip)]
+    pub nostr_pubkey: String,
+    /// `tx`, `addr`, `pubkey`, `input`, `output` or `xpub`.
+    #[serde(rename = "type")]
+    pub label_type: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
+
+#[derive(Debug, Insertable, Queryable, Serialize)]
+#[diesel(table_name = crate::schema::chain_checkpoint)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Checkpoint {
+    pub id: i32,
+    pub block_height: i32,
+    pub block_hash: String,
+}
+
+/// Something a user asked to watch. Serialised as the string it was parsed
+/// from, and stored in `TEXT` columns the same way.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, AsExpression, FromSqlRow)]
+#[serde(try_from = "String", into = "String")]
+#[diesel(sql_type = Text)]
+pub enum RecordType {
     Address(Address),
-    Xpub(String),
-    Utxo(String),
-    Descriptor(String),
+    /// A raw scriptPubKey, for outputs without an address form such as P2PK
+    /// or bare multisig. Written as hex.
+    Script(ScriptBuf),
+    /// An account-level extended public key, watched through the addresses
+    /// derived from it.
+    Xpub(ExtendedKey),
+    /// A single output, written as `txid:vout`.
+    Utxo(OutPoint),
+    Descriptor(Box<WatchedDescriptor>),
+    /// A bare public key, watched in every standard script it can be paid
+    /// to and in multisig and script-path spends that reveal it. Written as
+    /// hex.
+    Pubkey(PublicKey),
+    /// A transaction, followed through the mempool and its confirmations.
+    Txid(TxWatch),
+}
+
+const MAINNET_XPUB_PREFIXES: [&str; 3] = ["xpub", "ypub", "zpub"];
+const TESTNET_XPUB_PREFIXES: [&str; 3] = ["tpub", "upub", "vpub"];
+
+impl RecordType {
+    /// Parses a record, requiring addresses and extended keys to belong to
+    /// `network`.
+    pub fn parse(s: &str, network: Network) -> Result<Self, &'static str> {
+        if let Ok(addr) = Address::from_str(s) {
+            return addr
+                .require_network(network)
+                .map(RecordType::Address)
+                .map_err(|_| "Address belongs to a different network");
+        }
+
+        let (ours, theirs) = match network {
+            Network::Bitcoin => (MAINNET_XPUB_PREFIXES, TESTNET_XPUB_PREFIXES),
+            _ => (TESTNET_XPUB_PREFIXES, MAINNET_XPUB_PREFIXES),
+        };
+        if ours.iter().any(|prefix| s.starts_with(prefix)) {
+            ExtendedKey::parse(s).map(RecordType::Xpub)
+        } else if theirs.iter().any(|prefix| s.starts_with(prefix)) {
+            Err("Extended public key belongs to a different network")
+        } else if let Ok(key) = PublicKey::from_str(s) {
+            Ok(RecordType::Pubkey(key))
+        } else if let Ok(watch) = TxWatch::parse(s) {
+            // 32 bytes of hex are far more likely a txid than a script.
+            Ok(RecordType::Txid(watch))
+        } else if let Ok(script) = ScriptBuf::from_hex(s) {
+            if script.is_empty() {
+                return Err("Invalid RecordType string");
+            }
+            Ok(RecordType::Script(script))
+        } else if let Ok(outpoint) = OutPoint::from_str(s) {
+            Ok(RecordType::Utxo(outpoint))
+        } else if s.contains('(') {
+            WatchedDescriptor::parse(s, network)
+                .map(|descriptor| RecordType::Descriptor(Box::new(descriptor)))
+        } else {
+            Err("Invalid RecordType string")
+        }
+    }
+
+    /// Parses a record stored as `kind`, without guessing the kind from the
+    /// value.
+    pub fn from_kind(kind: &str, value: &str, network: Network) -> Result<Self, &'static str> {
+        match kind {
+            "address" => Address::from_str(value)
+                .map_err(|_| "Invalid address")?
+                .require_network(network)
+                .map(RecordType::Address)
+                .map_err(|_| "Address belongs to a different network"),
+            "script" => ScriptBuf::from_hex(value)
+                .map(RecordType::Script)
+                .map_err(|_| "Invalid script"),
+            "xpub" => ExtendedKey::parse(value).map(RecordType::Xpub),
+            "utxo" => OutPoint::from_str(value)
+                .map(RecordType::Utxo)
+                .map_err(|_| "Invalid outpoint"),
+            "descriptor" => WatchedDescriptor::parse(value, network)
+                .map(|descriptor| RecordType::Descriptor(Box::new(descriptor))),
+            "pubkey" => PublicKey::from_str(value)
+                .map(RecordType::Pubkey)
+                .map_err(|_| "Invalid public key"),
+            "txid" => TxWatch::parse(value).map(RecordType::Txid),
+            _ => Err("Unknown record kind"),
+        }
+    }
+
+    /// What the record is, as stored next to it in `user_addresses.kind`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RecordType::Address(_) => "address",
+            RecordType::Script(_) => "script",
+            RecordType::Xpub(_) => "xpub",
+            RecordType::Utxo(_) => "utxo",
+            RecordType::Descriptor(_) => "descriptor",
+            RecordType::Pubkey(_) => "pubkey",
+            RecordType::Txid(_) => "txid",
+        }
+    }
+
+    /// The output scripts this record watches. Empty for records that
+    /// cannot be matched by script yet.
+    pub fn script_pubkeys(&self) -> Vec<ScriptBuf> {
+        match self {
+            RecordType::Address(addr) => vec![addr.script_pubkey()],
+            RecordType::Script(script) => vec![script.clone()],
+            RecordType::Xpub(_) | RecordType::Descriptor(_) => derivation::watched_scripts(self),
+            RecordType::Pubkey(key) => pubkey::scripts(key),
+            _ => Vec::new(),
+        }
+    }
+
+    /// How the record is shown in notifications: a script is shown as its
+    /// address when it has one.
+    pub fn label(&self) -> String {
+        match self {
+            RecordType::Script(script) => Address::from_script(script, network::get_network())
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| script.to_hex_string()),
+            _ => self.to_string(),
+        }
+    }
 }
 
 impl TryFrom<String> for RecordType {
     type Error=&'static str;
     fn try_from(s: String)-> Result<Self,Self::Error>{
-        if s.starts_with("1") || s.starts_with("3") || s.starts_with("bc1") {
-            Ok(RecordType::Address(Address::assume_checked(Address::from_str(&s)?)?))
-        } else if s.starts_with("xpub") || s.starts_with("ypub") || s.starts_with("zpub") {
-            Ok(RecordType::Xpub(s))
-        } else if s.contains(':') && s.len() > 65 {
-            Ok(RecordType::Utxo(s))
-        } else if s.starts_with("wpkh(") || s.starts_with("sh(") || s.starts_with("multi(") {
-            Ok(RecordType::Descriptor(s))
-        } else {
-            Err("Invalid RecordType string")
+        RecordType::parse(&s, network::get_network())
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::Address(addr) => write!(f, "{}", addr),
+            RecordType::Script(script) => write!(f, "{}", script.to_hex_string()),
+            RecordType::Utxo(outpoint) => write!(f, "{}", outpoint),
+            RecordType::Xpub(key) => write!(f, "{}", key),
+            RecordType::Descriptor(descriptor) => write!(f, "{}", descriptor),
+            RecordType::Pubkey(key) => write!(f, "{}", key),
+            RecordType::Txid(watch) => write!(f, "{}", watch),
         }
     }
 }
 
 impl From<RecordType> for String{
     fn from(record: RecordType) -> Self {
-        match record {
-            RecordType::Address(s) => s,
-            RecordType::Xpub(s) => s,
-            RecordType::Utxo(s) => s,
-            RecordType::Descriptor(s) => s,
-        }
+        record.to_string()
     }
-}
\ No newline at end of file
+}
+
+impl ToSql<Text, Pg> for RecordType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.to_string().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for RecordType {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        let s = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(RecordType::try_from(s)?)
+    }
+}
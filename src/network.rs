@@ -0,0 +1,74 @@
+use bitcoin::Network;
+use dotenvy::dotenv;
+use once_cell::sync::OnceCell;
+use std::env;
+use std::str::FromStr;
+
+static GLOBAL_NETWORK: OnceCell<Network> = OnceCell::new();
+
+/// Parses a network name as bitcoind (`main`, `test`, ...) or rust-bitcoin
+/// (`bitcoin`, `testnet`, ...) spell it.
+pub fn parse_network(name: &str) -> Option<Network> {
+    let name = name.trim().to_lowercase();
+    Network::from_core_arg(&name)
+        .ok()
+        .or_else(|| Network::from_str(&name).ok())
+        .or_else(|| (name == "mainnet").then_some(Network::Bitcoin))
+}
+
+/// The network pinned with `BITCOIN_NETWORK`, if any.
+pub fn configured_network() -> Result<Option<Network>, String> {
+    dotenv().ok();
+
+    match env::var("BITCOIN_NETWORK") {
+        Ok(name) => parse_network(&name)
+            .map(Some)
+            .ok_or_else(|| format!("unknown BITCOIN_NETWORK {:?}", name)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Fixes the network for this process to the one the node runs on. A
+/// deployment pinned to another network with `BITCOIN_NETWORK` refuses to
+/// start rather than watching the wrong chain.
+pub fn init_network(node_network: Network) -> Result<Network, String> {
+    if let Some(configured) = configured_network()? {
+        if configured != node_network {
+            return Err(format!(
+                "BITCOIN_NETWORK is {} but the node is running on {}",
+                configured, node_network
+            ));
+        }
+    }
+    let network = *GLOBAL_NETWORK.get_or_init(|| node_network);
+    println!("✅ Monitoring the {} network", network);
+    Ok(network)
+}
+
+/// The network addresses are decoded and validated for. Mainnet until
+/// `init_network` has run.
+pub fn get_network() -> Network {
+    GLOBAL_NETWORK.get().copied().unwrap_or(Network::Bitcoin)
+}
+
+/// bitcoind's default RPC port on `network`.
+pub fn default_rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Testnet => 18332,
+        Network::Testnet4 => 48332,
+        Network::Signet => 38332,
+        Network::Regtest => 18443,
+        _ => 8332,
+    }
+}
+
+/// The mempool.space Esplora API for `network`. There is none for regtest.
+pub fn default_esplora_url(network: Network) -> Option<&'static str> {
+    match network {
+        Network::Bitcoin => Some("https://mempool.space/api"),
+        Network::Testnet => Some("https://mempool.space/testnet/api"),
+        Network::Testnet4 => Some("https://mempool.space/testnet4/api"),
+        Network::Signet => Some("https://mempool.space/signet/api"),
+        _ => None,
+    }
+}
@@ -3,10 +3,9 @@ use nostr_sdk::prelude::*;
 use nostr_sdk::{Client, Keys};
 use once_cell::sync::Lazy;
 use std::thread;
-use std::{env, str::FromStr};
+use std::env;
 use tokio::runtime::Runtime;
 use tokio::sync::OnceCell;
-use tokio::task;
 
 static GLOBAL_NOSTR_CLIENT: Lazy<OnceCell<Client>> = Lazy::new(OnceCell::new);
 
@@ -0,0 +1,401 @@
+use dotenvy::dotenv;
+use futures_util::future::BoxFuture;
+use once_cell::sync::Lazy;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, fmt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+static PIPELINE_STATS: Lazy<Arc<PipelineStats>> = Lazy::new(|| Arc::new(PipelineStats::default()));
+
+/// How long a block waits for the transactions received before it.
+const BLOCK_BARRIER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a transaction waits for the ones received before it to take
+/// their turn.
+const TURN_TIMEOUT: Duration = Duration::from_secs(60);
+
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+tokio::task_local! {
+    /// Sequence number of the transaction the running handler was given.
+    static TURN: (u64, Arc<Progress>);
+}
+
+/// Processes one raw ZMQ message body.
+pub type Handler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// What happens to a transaction arriving while the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard it and count it as dropped. Keeps the ZMQ socket drained.
+    Drop,
+    /// Wait for room and count it as delayed. ZMQ buffers meanwhile and
+    /// drops at its own high-water mark if the backlog persists.
+    Queue,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub workers: usize,
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            workers: 4,
+            queue_size: 10_000,
+            overflow: OverflowPolicy::Queue,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Reads `PIPELINE_WORKERS`, `PIPELINE_QUEUE_SIZE` and
+    /// `PIPELINE_OVERFLOW` (`drop` or `queue`), keeping the default for
+    /// anything unset or invalid.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let default = PipelineConfig::default();
+        let positive = |name: &str| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .filter(|value| *value > 0)
+        };
+        let overflow = match env::var("PIPELINE_OVERFLOW").map(|v| v.to_lowercase()) {
+            Ok(policy) if policy == "drop" => OverflowPolicy::Drop,
+            Ok(policy) if policy == "queue" => OverflowPolicy::Queue,
+            Ok(policy) => {
+                eprintln!("❌ Unknown PIPELINE_OVERFLOW {:?}, using queue", policy);
+                OverflowPolicy::Queue
+            }
+            Err(_) => default.overflow,
+        };
+        PipelineConfig {
+            workers: positive("PIPELINE_WORKERS").unwrap_or(default.workers),
+            queue_size: positive("PIPELINE_QUEUE_SIZE").unwrap_or(default.queue_size),
+            overflow,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    pub received: AtomicU64,
+    pub processed: AtomicU64,
+    /// Discarded because the queue was full under `OverflowPolicy::Drop`.
+    pub dropped: AtomicU64,
+    /// Held up at the reader because the queue was full under
+    /// `OverflowPolicy::Queue`.
+    pub delayed: AtomicU64,
+    /// The handler panicked.
+    pub failed: AtomicU64,
+    pub blocks: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub received: u64,
+    pub processed: u64,
+    pub dropped: u64,
+    pub delayed: u64,
+    pub failed: u64,
+    pub blocks: u64,
+}
+
+impl PipelineStats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            blocks: self.blocks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} txs received, {} processed, {} dropped, {} delayed, {} failed, {} blocks",
+            self.received, self.processed, self.dropped, self.delayed, self.failed, self.blocks
+        )
+    }
+}
+
+/// Counters of the running pipeline.
+pub fn stats() -> StatsSnapshot {
+    PIPELINE_STATS.snapshot()
+}
+
+/// Sequence numbers of transactions handed to the workers but not finished,
+/// so a block can wait for every transaction that arrived before it, and of
+/// those that have not taken their turn yet.
+#[derive(Default)]
+struct Progress {
+    pending: Mutex<BTreeSet<u64>>,
+    turns: Mutex<BTreeSet<u64>>,
+    notify: Notify,
+}
+
+impl Progress {
+    fn start(&self, seq: u64) {
+        for set in [&self.pending, &self.turns] {
+            set.lock().unwrap_or_else(|e| e.into_inner()).insert(seq);
+        }
+    }
+
+    fn finish(&self, seq: u64) {
+        for set in [&self.pending, &self.turns] {
+            set.lock().unwrap_or_else(|e| e.into_inner()).remove(&seq);
+        }
+        self.notify.notify_waiters();
+    }
+
+    fn end_turn(&self, seq: u64) {
+        self.turns
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&seq);
+        self.notify.notify_waiters();
+    }
+
+    /// Resolves once every transaction numbered below `seq` is finished.
+    async fn wait_before(&self, seq: u64) {
+        Self::wait_for(&self.pending, &self.notify, seq).await
+    }
+
+    /// Resolves once every transaction numbered below `seq` has taken its
+    /// turn or finished without one.
+    async fn wait_turn(&self, seq: u64) {
+        Self::wait_for(&self.turns, &self.notify, seq).await
+    }
+
+    async fn wait_for(set: &Mutex<BTreeSet<u64>>, notify: &Notify, seq: u64) {
+        loop {
+            // Registered before checking, so a finish in between still wakes us.
+            let notified = notify.notified();
+            let oldest = set.lock().unwrap_or_else(|e| e.into_inner()).first().copied();
+            if oldest.is_none_or(|oldest| oldest >= seq) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Runs `f` once every transaction received before the current one has run
+/// its own, so changes to shared state apply in arrival order whichever
+/// worker gets there first. Outside a transaction handler `f` runs at once.
+pub async fn in_turn<T>(f: impl FnOnce() -> T) -> T {
+    let Ok((seq, progress)) = TURN.try_with(|(seq, progress)| (*seq, progress.clone())) else {
+        return f();
+    };
+    if time::timeout(TURN_TIMEOUT, progress.wait_turn(seq)).await.is_err() {
+        eprintln!(
+            "⚠️ Earlier transactions still running after {:?}, taking turn anyway",
+            TURN_TIMEOUT
+        );
+    }
+    let result = f();
+    progress.end_turn(seq);
+    result
+}
+
+struct TxJob {
+    seq: u64,
+    raw: Vec<u8>,
+}
+
+/// Entry point of the pipeline, owned by the thread reading ZMQ. Its
+/// methods block, so it must not be used from async code.
+pub struct Intake {
+    txs: mpsc::Sender<TxJob>,
+    blocks: mpsc::UnboundedSender<(u64, Vec<u8>)>,
+    overflow: OverflowPolicy,
+    progress: Arc<Progress>,
+    stats: Arc<PipelineStats>,
+    next_seq: u64,
+}
+
+impl Intake {
+    /// Queues a raw transaction, applying the overflow policy when the queue
+    /// is full. Returns false once the workers are gone.
+    pub fn submit_tx(&mut self, raw: Vec<u8>) -> bool {
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.progress.start(seq);
+
+        let job = match self.txs.try_send(TxJob { seq, raw }) {
+            Ok(()) => return true,
+            Err(TrySendError::Full(job)) => job,
+            Err(TrySendError::Closed(job)) => {
+                self.progress.finish(job.seq);
+                return false;
+            }
+        };
+        match self.overflow {
+            OverflowPolicy::Drop => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                self.progress.finish(seq);
+                true
+            }
+            OverflowPolicy::Queue => {
+                self.stats.delayed.fetch_add(1, Ordering::Relaxed);
+                match self.txs.blocking_send(job) {
+                    Ok(()) => true,
+                    Err(_) => {
+                        self.progress.finish(seq);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues a raw block. Blocks are never dropped and are handled in
+    /// arrival order, each after the transactions received before it.
+    pub fn submit_block(&mut self, raw: Vec<u8>) -> bool {
+        self.blocks.send((self.next_seq, raw)).is_ok()
+    }
+}
+
+/// Spawns the transaction workers and the block task. Returns the intake to
+/// feed them and the block task's handle, which runs until the intake is
+/// dropped.
+pub fn start(config: PipelineConfig, on_tx: Handler, on_block: Handler) -> (Intake, JoinHandle<()>) {
+    start_with_stats(config, on_tx, on_block, PIPELINE_STATS.clone())
+}
+
+pub fn start_with_stats(
+    config: PipelineConfig,
+    on_tx: Handler,
+    on_block: Handler,
+    stats: Arc<PipelineStats>,
+) -> (Intake, JoinHandle<()>) {
+    let (tx_sender, tx_receiver) = mpsc::channel::<TxJob>(config.queue_size.max(1));
+    let (block_sender, mut block_receiver) = mpsc::unbounded_channel::<(u64, Vec<u8>)>();
+    let tx_receiver = Arc::new(tokio::sync::Mutex::new(tx_receiver));
+    let progress = Arc::new(Progress::default());
+
+    for _ in 0..config.workers.max(1) {
+        let (receiver, on_tx, progress, stats) = (
+            tx_receiver.clone(),
+            on_tx.clone(),
+            progress.clone(),
+            stats.clone(),
+        );
+        task::spawn(async move {
+            loop {
+                let job = receiver.lock().await.recv().await;
+                let Some(job) = job else { break };
+                // Run in its own task so a panicking handler does not take
+                // the worker down with it.
+                let turn = (job.seq, progress.clone());
+                if task::spawn(TURN.scope(turn, on_tx(job.raw))).await.is_err() {
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    stats.processed.fetch_add(1, Ordering::Relaxed);
+                }
+                progress.finish(job.seq);
+            }
+        });
+    }
+
+    let block_progress = progress.clone();
+    let block_stats = stats.clone();
+    let blocks = task::spawn(async move {
+        while let Some((seq, raw)) = block_receiver.recv().await {
+            if time::timeout(BLOCK_BARRIER_TIMEOUT, block_progress.wait_before(seq))
+                .await
+                .is_err()
+            {
+                eprintln!(
+                    "⚠️ Earlier transactions still running after {:?}, processing block anyway",
+                    BLOCK_BARRIER_TIMEOUT
+                );
+            }
+            if task::spawn(on_block(raw)).await.is_err() {
+                eprintln!("❌ Block handler panicked");
+            }
+            block_stats.blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    let intake = Intake {
+        txs: tx_sender,
+        blocks: block_sender,
+        overflow: config.overflow,
+        progress,
+        stats,
+        next_seq: 0,
+    };
+    (intake, blocks)
+}
+
+/// Logs the counters every minute while anything changes.
+pub fn spawn_stats_logger() {
+    task::spawn(async {
+        let mut last = stats();
+        let mut interval = time::interval(STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let current = stats();
+            if current != last {
+                println!("📊 Pipeline: {}", current);
+                last = current;
+            }
+        }
+    });
+}
+
+/// Reads `rawtx` and `rawblock` messages from `subscriber` into `intake`
+/// until the pipeline shuts down. Blocks the calling thread.
+pub fn read_zmq(subscriber: zmq::Socket, mut intake: Intake) {
+    loop {
+        let topic = subscriber.recv_string(0);
+        match topic {
+            Ok(Ok(topic)) => {
+                let data = match subscriber.recv_bytes(0) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        eprintln!("Error receiving message body: {}", e);
+                        continue;
+                    }
+                };
+                // Discard the trailing sequence number frame.
+                while subscriber.get_rcvmore().unwrap_or(false) {
+                    let _ = subscriber.recv_bytes(0);
+                }
+
+                let accepted = match topic.as_str() {
+                    "rawtx" => intake.submit_tx(data),
+                    "rawblock" => intake.submit_block(data),
+                    _ => {
+                        println!("Ignoring unexpected topic:: {}", topic);
+                        true
+                    }
+                };
+                if !accepted {
+                    eprintln!("❌ Pipeline stopped, no longer reading ZMQ");
+                    return;
+                }
+            }
+            Ok(Err(_)) => println!("Received non-UTF8 topic:"),
+            Err(e) => eprintln!("Error receiving message: {}", e),
+        }
+    }
+}
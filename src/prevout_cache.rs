@@ -0,0 +1,317 @@
+use bitcoin::{ScriptBuf, Txid};
+use dotenvy::dotenv;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::{env, fmt};
+
+use crate::models::{GenTransaction, InputTrans};
+use crate::{chain_source, db_operations, process_outputs};
+
+pub const DEFAULT_CAPACITY: usize = 50_000;
+
+/// Lookups between two statistics log lines.
+const STATS_INTERVAL: u64 = 10_000;
+
+/// Days spilled transactions are kept in Postgres. Outputs left unspent for
+/// longer are fetched again from the chain source when they are spent.
+pub const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+static RETENTION_DAYS: Lazy<i64> = Lazy::new(|| {
+    dotenv().ok();
+    env::var("PREVOUT_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+});
+
+static PREVOUT_CACHE: Lazy<PrevoutCache> = Lazy::new(|| {
+    dotenv().ok();
+    let capacity = env::var("PREVOUT_CACHE_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_CAPACITY);
+    PrevoutCache::new(capacity)
+});
+
+/// What the monitor needs to know about a transaction whose outputs may be
+/// spent later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedOutputs {
+    /// Address of every output by index, `None` where the script has none.
+    pub output_address: Vec<Option<String>>,
+    /// Script of every output by index, so outputs without an address can
+    /// still be matched.
+    pub output_script: Vec<ScriptBuf>,
+    /// Addresses spent by each input. Only known for transactions the
+    /// monitor processed itself, which spill to `gen_transactions`; the rest
+    /// spill to `input_transactions`.
+    pub input_address: Option<Vec<Option<String>>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub memory_hits: u64,
+    pub db_hits: u64,
+    pub misses: u64,
+    pub spilled: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lookups = self.memory_hits + self.db_hits + self.misses;
+        let hit_rate = if lookups == 0 {
+            0.0
+        } else {
+            (self.memory_hits + self.db_hits) as f64 * 100.0 / lookups as f64
+        };
+        write!(
+            f,
+            "{} memory hits, {} database hits, {} misses ({:.1}% hit rate), {} spilled",
+            self.memory_hits, self.db_hits, self.misses, hit_rate, self.spilled
+        )
+    }
+}
+
+struct Entry {
+    outputs: CachedOutputs,
+    /// Already stored in Postgres, so eviction needs no write.
+    persisted: bool,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Txid, Entry>,
+    /// `last_used` stamp -> txid, oldest first.
+    order: BTreeMap<u64, Txid>,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, txid: Txid) -> Option<&Entry> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&txid)?;
+        self.order.remove(&entry.last_used);
+        entry.last_used = self.clock;
+        self.order.insert(self.clock, txid);
+        Some(entry)
+    }
+}
+
+/// In-memory LRU of transaction outputs. Entries pushed out of memory are
+/// handed back to the caller to spill to Postgres.
+pub struct PrevoutCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    memory_hits: AtomicU64,
+    db_hits: AtomicU64,
+    misses: AtomicU64,
+    spilled: AtomicU64,
+}
+
+impl PrevoutCache {
+    pub fn new(capacity: usize) -> Self {
+        PrevoutCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            memory_hits: AtomicU64::new(0),
+            db_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            spilled: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached outputs of `txid` and marks them recently used.
+    pub fn get(&self, txid: &Txid) -> Option<CachedOutputs> {
+        let mut lru = self.lru.lock().unwrap_or_else(|e| e.into_inner());
+        let outputs = lru.touch(*txid).map(|entry| entry.outputs.clone());
+        if outputs.is_some() {
+            self.memory_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        outputs
+    }
+
+    /// Adds or refreshes `txid` and returns the evicted entries that are not
+    /// in Postgres yet.
+    pub fn insert(
+        &self,
+        txid: Txid,
+        outputs: CachedOutputs,
+        persisted: bool,
+    ) -> Vec<(Txid, CachedOutputs)> {
+        let mut lru = self.lru.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = lru.entries.get_mut(&txid) {
+            // A processed transaction knows its inputs, a fetched one doesn't.
+            if outputs.input_address.is_some() && entry.outputs.input_address.is_none() {
+                entry.outputs = outputs;
+                entry.persisted = persisted;
+            }
+            lru.touch(txid);
+            return Vec::new();
+        }
+
+        lru.clock += 1;
+        let last_used = lru.clock;
+        lru.order.insert(last_used, txid);
+        lru.entries.insert(
+            txid,
+            Entry {
+                outputs,
+                persisted,
+                last_used,
+            },
+        );
+
+        let mut evicted = Vec::new();
+        while lru.entries.len() > self.capacity {
+            let Some((_, oldest)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = lru.entries.remove(&oldest) {
+                if !entry.persisted {
+                    evicted.push((oldest, entry.outputs));
+                }
+            }
+        }
+        self.spilled.fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        evicted
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            memory_hits: self.memory_hits.load(Ordering::Relaxed),
+            db_hits: self.db_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Outputs of the transaction `txid`, looked up in memory, then in Postgres,
+/// and only then fetched from the chain source.
+pub async fn lookup(txid: &Txid) -> Option<CachedOutputs> {
+    let cache = &*PREVOUT_CACHE;
+    let outputs = match cache.get(txid) {
+        Some(outputs) => Some(outputs),
+        None => match load_from_db(txid) {
+            Some(outputs) => {
+                cache.db_hits.fetch_add(1, Ordering::Relaxed);
+                cache.insert(*txid, outputs.clone(), true);
+                Some(outputs)
+            }
+            None => {
+                cache.misses.fetch_add(1, Ordering::Relaxed);
+                fetch(txid).await
+            }
+        },
+    };
+    log_stats(cache);
+    outputs
+}
+
+/// Keeps a processed transaction around, since its children usually follow
+/// shortly and can then be matched without fetching it.
+pub fn remember(txid: Txid, outputs: CachedOutputs) {
+    spill(PREVOUT_CACHE.insert(txid, outputs, false));
+}
+
+pub fn stats() -> CacheStats {
+    PREVOUT_CACHE.stats()
+}
+
+/// Deletes spilled transactions older than `PREVOUT_RETENTION_DAYS`, so the
+/// tables don't grow forever. Runs with every block.
+pub fn prune() {
+    match db_operations::prune_cached_outputs(*RETENTION_DAYS) {
+        Ok(0) => {}
+        Ok(pruned) => println!("🔄 Pruned {} spilled transaction(s) from the prevout cache", pruned),
+        Err(e) => eprintln!("❌ Failed to prune spilled transactions: {}", e),
+    }
+}
+
+fn load_from_db(txid: &Txid) -> Option<CachedOutputs> {
+    match db_operations::get_cached_outputs(txid.to_string()) {
+        // Rows stored before scripts were kept can't match script records,
+        // so they count as misses and are fetched again.
+        Ok(Some((_, output_script))) if output_script.is_empty() => None,
+        Ok(outputs) => outputs.and_then(|(output_address, output_script)| {
+            let output_script = output_script
+                .iter()
+                .map(|script| ScriptBuf::from_hex(script).ok())
+                .collect::<Option<Vec<_>>>()?;
+            Some(CachedOutputs {
+                output_address,
+                output_script,
+                input_address: None,
+            })
+        }),
+        Err(e) => {
+            eprintln!("❌ Failed to read cached outputs of {}: {}", txid, e);
+            None
+        }
+    }
+}
+
+async fn fetch(txid: &Txid) -> Option<CachedOutputs> {
+    let source = chain_source::get_chain_source();
+    match source.get_transaction(txid).await {
+        Ok(tx) => {
+            let outputs = CachedOutputs {
+                output_address: process_outputs(&tx),
+                output_script: tx.output.iter().map(|o| o.script_pubkey.clone()).collect(),
+                input_address: None,
+            };
+            spill(PREVOUT_CACHE.insert(*txid, outputs.clone(), false));
+            Some(outputs)
+        }
+        Err(e) => {
+            eprintln!("❌ Failed to fetch {} from {}: {}", txid, source.name(), e);
+            None
+        }
+    }
+}
+
+fn spill(evicted: Vec<(Txid, CachedOutputs)>) {
+    if evicted.is_empty() {
+        return;
+    }
+    let mut processed = Vec::new();
+    let mut fetched = Vec::new();
+    for (txid, outputs) in evicted {
+        let output_script = outputs
+            .output_script
+            .iter()
+            .map(|script| script.to_hex_string())
+            .collect();
+        match outputs.input_address {
+            Some(input_address) => processed.push(GenTransaction {
+                txid: txid.to_string(),
+                output_address: outputs.output_address,
+                input_address,
+                output_script,
+            }),
+            None => fetched.push(InputTrans {
+                txid: txid.to_string(),
+                output_address: outputs.output_address,
+                output_script,
+            }),
+        }
+    }
+    if let Err(e) = db_operations::store_gen_transactions(processed) {
+        eprintln!("❌ Failed to spill processed transactions: {}", e);
+    }
+    if let Err(e) = db_operations::store_input_transactions(fetched) {
+        eprintln!("❌ Failed to spill fetched transactions: {}", e);
+    }
+}
+
+fn log_stats(cache: &PrevoutCache) {
+    let stats = cache.stats();
+    let lookups = stats.memory_hits + stats.db_hits + stats.misses;
+    if lookups.is_multiple_of(STATS_INTERVAL) {
+        println!("📊 Prevout cache: {}", stats);
+    }
+}
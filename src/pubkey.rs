@@ -0,0 +1,74 @@
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::script::Instruction;
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::{CompressedPublicKey, PublicKey, Script, ScriptBuf, TxIn};
+use once_cell::sync::Lazy;
+
+use crate::spent_script;
+
+static SECP: Lazy<Secp256k1<VerifyOnly>> = Lazy::new(Secp256k1::verification_only);
+
+/// Every standard script paying to `key` alone: P2PK and P2PKH, plus
+/// P2WPKH, P2SH-P2WPKH and P2TR key-path (BIP86) for a compressed key.
+pub fn scripts(key: &PublicKey) -> Vec<ScriptBuf> {
+    let mut scripts = vec![
+        ScriptBuf::new_p2pk(key),
+        ScriptBuf::new_p2pkh(&key.pubkey_hash()),
+    ];
+    if let Ok(compressed) = CompressedPublicKey::try_from(*key) {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash());
+        scripts.push(ScriptBuf::new_p2sh(&p2wpkh.script_hash()));
+        scripts.push(p2wpkh);
+        scripts.push(ScriptBuf::new_p2tr(&SECP, XOnlyPublicKey::from(compressed), None));
+    }
+    scripts
+}
+
+/// Keys in the scripts `input` executes: the redeem script of a P2SH spend,
+/// the witness script of a P2WSH spend and the leaf script of a taproot
+/// script-path spend. Keys are compared without their parity, as tapscript
+/// writes them.
+///
+/// Single-key spends reveal nothing here; the script they spend already
+/// matches the key.
+pub fn revealed_keys(input: &TxIn) -> Vec<XOnlyPublicKey> {
+    let mut witness: Vec<&[u8]> = input.witness.iter().collect();
+    if witness.len() >= 2 && witness.last().and_then(|last| last.first()) == Some(&0x50) {
+        witness.pop();
+    }
+
+    let mut keys = match witness.as_slice() {
+        [.., leaf_script, control_block] if spent_script::is_control_block(control_block) => {
+            script_keys(leaf_script, true)
+        }
+        [_, .., witness_script] => script_keys(witness_script, false),
+        _ => Vec::new(),
+    };
+    if let Some(Ok(Instruction::PushBytes(redeem_script))) = input.script_sig.instructions().last() {
+        keys.extend(script_keys(redeem_script.as_bytes(), false));
+    }
+    keys
+}
+
+/// Keys pushed by `script`. A bare key, as the last push of a P2PKH or
+/// P2WPKH spend, is not a script.
+fn script_keys(script: &[u8], tapscript: bool) -> Vec<XOnlyPublicKey> {
+    if PublicKey::from_slice(script).is_ok() {
+        return Vec::new();
+    }
+    Script::from_bytes(script)
+        .instructions()
+        .filter_map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => push_key(bytes.as_bytes(), tapscript),
+            _ => None,
+        })
+        .collect()
+}
+
+fn push_key(bytes: &[u8], tapscript: bool) -> Option<XOnlyPublicKey> {
+    match bytes.len() {
+        32 if tapscript => XOnlyPublicKey::from_slice(bytes).ok(),
+        33 | 65 => PublicKey::from_slice(bytes).ok().map(XOnlyPublicKey::from),
+        _ => None,
+    }
+}
@@ -0,0 +1,127 @@
+use bitcoin::{Block, BlockHash};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use crate::chain_source::{self, ChainSourceError};
+use crate::{confirmations, db_operations, find_address_match, rpc};
+
+/// Scans every block between the stored checkpoint and the node's current
+/// tip, so transactions broadcast and mined while the monitor was down are
+/// still matched. On first start the current tip becomes the checkpoint.
+pub async fn catch_up() {
+    let checkpoint = match db_operations::get_checkpoint() {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            eprintln!("❌ Failed to load checkpoint, skipping catch-up: {}", e);
+            return;
+        }
+    };
+
+    let source = chain_source::get_chain_source();
+    let tip = match source.get_tip().await {
+        Ok(tip) => tip.height,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch chain tip, skipping catch-up: {}", e);
+            return;
+        }
+    };
+
+    let Some(checkpoint) = checkpoint else {
+        match source.get_block_hash(tip).await {
+            Ok(hash) => {
+                println!("No checkpoint found, starting from tip {} ({})", tip, hash);
+                if let Err(e) = db_operations::store_checkpoint(tip as i32, hash.to_string()) {
+                    eprintln!("❌ Failed to store initial checkpoint: {}", e);
+                }
+            }
+            Err(e) => eprintln!("❌ Failed to fetch tip hash: {}", e),
+        }
+        return;
+    };
+
+    let start = match resolve_checkpoint(&checkpoint.block_hash, checkpoint.block_height).await {
+        Some(height) => height,
+        None => return,
+    };
+
+    if start >= tip {
+        println!("✅ Checkpoint {} is at the chain tip, nothing to catch up", start);
+        return;
+    }
+
+    println!("🔄 Catching up from height {} to {}", start + 1, tip);
+    for height in start + 1..=tip {
+        let block = match fetch_block(height).await {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!(
+                    "❌ Catch-up stopped at height {}: {}. Blocks {}..={} were not scanned.",
+                    height, e, height, tip
+                );
+                return;
+            }
+        };
+        scan_block(block).await;
+    }
+    println!("✅ Catch-up complete at height {}", tip);
+}
+
+async fn fetch_block(height: u64) -> Result<Block, ChainSourceError> {
+    let source = chain_source::get_chain_source();
+    let hash = source.get_block_hash(height).await?;
+    source.get_block(&hash).await
+}
+
+/// Runs every not yet matched transaction of `block` through the matcher,
+/// then confirms the block, which also advances the checkpoint.
+async fn scan_block(block: Block) {
+    let txids: Vec<String> = block
+        .txdata
+        .iter()
+        .map(|tx| tx.compute_txid().to_string())
+        .collect();
+    let already_matched: HashSet<String> = db_operations::get_matched_txids(txids)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+
+    for tx in block.txdata.iter() {
+        if already_matched.contains(&tx.compute_txid().to_string()) {
+            continue;
+        }
+        find_address_match(tx.clone()).await;
+    }
+    confirmations::process_block(block).await;
+}
+
+/// Returns the height to resume from. If the checkpoint block was reorganised
+/// away while the monitor was down, confirmations above the fork point are
+/// rolled back and scanning resumes from there.
+async fn resolve_checkpoint(hash: &str, height: i32) -> Option<u64> {
+    let hash = match BlockHash::from_str(hash) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("❌ Stored checkpoint hash is invalid: {}", e);
+            return None;
+        }
+    };
+    let fork_height = match rpc::with_client(move |client| client.find_fork_point(&hash)).await {
+        Ok(fork) => fork,
+        Err(e) => {
+            eprintln!("❌ Failed to locate checkpoint block {}: {}", hash, e);
+            return None;
+        }
+    };
+
+    if fork_height < height as u64 {
+        println!(
+            "⚠️ Checkpoint {} at height {} is no longer in the active chain, resuming from fork at {}",
+            hash, height, fork_height
+        );
+        match db_operations::unconfirm_matched_above(fork_height as i32) {
+            Ok(rows) => confirmations::notify_rolled_back(rows).await,
+            Err(e) => eprintln!("❌ Failed to roll back confirmations: {}", e),
+        }
+    }
+    Some(fork_height)
+}
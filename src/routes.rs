@@ -1,10 +1,16 @@
 use actix_files::NamedFile;
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Result};
-use bitcoin::Address;
-use serde_json::Value;
-use std::str::FromStr;
+use actix_web::{delete, get, post, rt, web, HttpRequest, HttpResponse, Responder, Result};
+use diesel::result::{DatabaseErrorKind, Error::DatabaseError};
+use serde_json::{json, Value};
+use std::collections::HashSet;
 
-use crate::{db_operations, nostr_notify};
+use crate::models::RecordType;
+use crate::utxo_index::Balance;
+use crate::watch_index::{self, WatchUpdate};
+use crate::{
+    backfill, db_operations, derivation, labels, network, nostr_notify, rpc, timelock, utxo_index,
+    wallet_import,
+};
 
 #[get("/")]
 pub async fn index(_req: HttpRequest) -> Result<NamedFile> {
@@ -18,7 +24,7 @@ pub async fn store_user(payload: web::Json<Value>) -> impl Responder {
         nostr_notify::send_message("Welcome to utxo monitoring. You will receive notifications here if an address makes a move.".to_string(), pubkey.to_string());
         HttpResponse::Ok().body("PubKey stored successfully")
     } else {
-        return HttpResponse::BadRequest().body("Invalid pubkey");
+        HttpResponse::BadRequest().body("Invalid pubkey")
     }
 }
 
@@ -30,10 +36,38 @@ pub async fn store_monitored_addresses(
     let pubkey: Option<String> = req.cookie("nostr_pubkey").map(|c| c.value().to_string());
 
     if let Some(pubkey) = pubkey {
-        if let Some(address) = payload.get("address").and_then(|v| v.to_string()) {
-                let addr = RecordType::try_from(addr)?;
-                db_operations::store_user_address(pubkey.clone(), addr.clone().to_string());
-                nostr_notify::send_message(format!("Address added: {}", addr), pubkey);
+        if let Some(address) = payload.get("address").and_then(|v| v.as_str()) {
+                // Rejects records for another network than the node's.
+                let addr = match RecordType::try_from(address.trim().to_string()) {
+                    Ok(addr) => addr,
+                    Err(e) => return HttpResponse::BadRequest().body(format!("Invalid address: {}", e)),
+                };
+                match db_operations::store_user_address(pubkey.clone(), addr.clone()) {
+                    Ok(_) => {}
+                    Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        return HttpResponse::Conflict().body("Address is already monitored");
+                    }
+                    Err(e) => {
+                        eprintln!("❌ Failed to store watched record {}: {}", addr, e);
+                        return HttpResponse::InternalServerError().body("Failed to store address");
+                    }
+                }
+                watch_index::send(WatchUpdate::Add {
+                    user: pubkey.clone(),
+                    record: addr.clone(),
+                });
+                nostr_notify::send_message(format!("Address added: {}", addr), pubkey.clone());
+
+                if let Some(label) = payload.get("label").and_then(|v| v.as_str()) {
+                    if let Err(e) = labels::label_record(&pubkey, &addr, label.trim()) {
+                        eprintln!("❌ Failed to store label of {}: {}", addr, e);
+                    }
+                }
+
+                // Optional block height the address was first used at; scanning
+                // starts there instead of only looking at the current UTXO set.
+                let birth_height = payload.get("birth_height").and_then(|v| v.as_u64());
+                rt::spawn(backfill::backfill_record(pubkey, addr, birth_height));
                 HttpResponse::Ok().body("Address stored successfully")
 
         } else {
@@ -44,6 +78,110 @@ pub async fn store_monitored_addresses(
     }
 }
 
+/// Watches every descriptor, xpub or address of an uploaded wallet export.
+#[post("/import-wallet")]
+pub async fn import_wallet(req: HttpRequest, body: String) -> impl Responder {
+    let Some(pubkey) = req.cookie("nostr_pubkey").map(|c| c.value().to_string()) else {
+        return HttpResponse::BadRequest().body("Pubkey not set");
+    };
+    let import = match wallet_import::parse(&body, network::get_network()) {
+        Ok(import) => import,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid wallet export: {}", e)),
+    };
+    let watched: HashSet<String> = match db_operations::get_tagged_addresses(pubkey.clone()) {
+        Ok(rows) => rows.into_iter().map(|row| row.record.to_string()).collect(),
+        Err(e) => {
+            eprintln!("❌ Failed to load watched records: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to load watched records");
+        }
+    };
+
+    let mut added = Vec::new();
+    let mut failed = Vec::new();
+    let mut already_watched = 0;
+    for record in import.records {
+        if watched.contains(&record.to_string()) {
+            already_watched += 1;
+            continue;
+        }
+        match db_operations::store_user_address(pubkey.clone(), record.clone()) {
+            Ok(_) => {}
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                already_watched += 1;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("❌ Failed to store watched record {}: {}", record, e);
+                failed.push(record.to_string());
+                continue;
+            }
+        }
+        watch_index::send(WatchUpdate::Add {
+            user: pubkey.clone(),
+            record: record.clone(),
+        });
+        if let Some(name) = import.name.as_deref() {
+            if let Err(e) = labels::label_record(&pubkey, &record, name) {
+                eprintln!("❌ Failed to store label of {}: {}", record, e);
+            }
+        }
+        added.push(record.to_string());
+        rt::spawn(backfill::backfill_record(pubkey.clone(), record, import.birth_height));
+    }
+    if !added.is_empty() {
+        nostr_notify::send_message(
+            format!(
+                "Imported {} record(s) from a {} wallet export: {}",
+                added.len(),
+                import.format,
+                added.join(", ")
+            ),
+            pubkey,
+        );
+    }
+    HttpResponse::Ok().json(json!({
+        "format": import.format,
+        "added": added,
+        "already_watched": already_watched,
+        "failed": failed,
+    }))
+}
+
+#[delete("/monitor-address")]
+pub async fn remove_monitored_address(
+    req: HttpRequest,
+    payload: web::Json<Value>,
+) -> impl Responder {
+    let Some(pubkey) = req.cookie("nostr_pubkey").map(|c| c.value().to_string()) else {
+        return HttpResponse::BadRequest().body("Pubkey not set");
+    };
+    let Some(address) = payload.get("address").and_then(|v| v.as_str()) else {
+        return HttpResponse::BadRequest().body("Invalid payload: missing 'address'");
+    };
+    let addr = match RecordType::try_from(address.trim().to_string()) {
+        Ok(addr) => addr,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid address: {}", e)),
+    };
+
+    match db_operations::remove_user_address(pubkey.clone(), addr.clone()) {
+        Ok(0) => HttpResponse::NotFound().body("Address is not monitored"),
+        Ok(_) => {
+            watch_index::send(WatchUpdate::Remove {
+                user: pubkey.clone(),
+                record: addr.clone(),
+            });
+            utxo_index::forget(&pubkey, &addr.label());
+            derivation::forget(&addr);
+            nostr_notify::send_message(format!("Address removed: {}", addr), pubkey);
+            HttpResponse::Ok().body("Address removed successfully")
+        }
+        Err(e) => {
+            eprintln!("❌ Failed to remove address {}: {}", addr, e);
+            HttpResponse::InternalServerError().body("Failed to remove address")
+        }
+    }
+}
+
 #[get("/monitor-address")]
 pub async fn get_monitored_addresses(req: HttpRequest) -> impl Responder {
     let pubkey = req.cookie("nostr_pubkey").map(|c| c.value().to_string());
@@ -61,3 +199,120 @@ pub async fn get_monitored_addresses(req: HttpRequest) -> impl Responder {
     }
     HttpResponse::Ok().json(Vec::<String>::new())
 }
+
+#[get("/timelocks")]
+pub async fn get_timelocks(req: HttpRequest) -> impl Responder {
+    let Some(pubkey) = req.cookie("nostr_pubkey").map(|c| c.value().to_string()) else {
+        return HttpResponse::BadRequest().body("Pubkey not set");
+    };
+    let tip = rpc::with_client(|client| {
+        let info = client.get_blockchain_info()?;
+        Ok((info.blocks, info.median_time))
+    })
+    .await;
+    let (height, mtp) = match tip {
+        Ok(tip) => tip,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch chain tip: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to fetch chain tip");
+        }
+    };
+
+    let locks: Vec<Value> = timelock::maturities(utxo_index::holdings(&pubkey), height as u32, mtp as u32)
+        .into_iter()
+        .map(|(outpoint, coin, lock, left)| {
+            json!({
+                "outpoint": outpoint.to_string(),
+                "record": coin.record,
+                "value": coin.value.to_sat(),
+                "lock": lock.to_string(),
+                "blocks_left": left,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(locks)
+}
+
+/// Confirmed and unconfirmed balance, in sats, of each watched record and
+/// of all of them together.
+#[get("/balances")]
+pub async fn get_balances(req: HttpRequest) -> impl Responder {
+    let Some(pubkey) = req.cookie("nostr_pubkey").map(|c| c.value().to_string()) else {
+        return HttpResponse::BadRequest().body("Pubkey not set");
+    };
+    let mut balances = utxo_index::balances(&pubkey);
+    // Records holding nothing are listed too, apart from transactions and
+    // single UTXOs, which outputs are not paid to.
+    match db_operations::get_tagged_addresses(pubkey.clone()) {
+        Ok(rows) => {
+            for row in rows {
+                if !matches!(row.record, RecordType::Txid(_) | RecordType::Utxo(_)) {
+                    balances.entry(row.record.label()).or_default();
+                }
+            }
+        }
+        Err(e) => eprintln!("❌ Failed to load watched records: {}", e),
+    }
+
+    // An output paying to several records counts once towards the total.
+    let mut total = Balance::default();
+    let mut counted = HashSet::new();
+    for (outpoint, coin) in utxo_index::holdings(&pubkey) {
+        if counted.insert(outpoint) {
+            total.add(&coin);
+        }
+    }
+    let records: Vec<Value> = balances
+        .into_iter()
+        .map(|(record, balance)| {
+            json!({
+                "record": record,
+                "confirmed": balance.confirmed.to_sat(),
+                "unconfirmed": balance.unconfirmed.to_sat(),
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({
+        "confirmed": total.confirmed.to_sat(),
+        "unconfirmed": total.unconfirmed.to_sat(),
+        "records": records,
+    }))
+}
+
+/// The user's labels as a BIP-329 JSON Lines export.
+#[get("/labels")]
+pub async fn export_labels(req: HttpRequest) -> impl Responder {
+    let Some(pubkey) = req.cookie("nostr_pubkey").map(|c| c.value().to_string()) else {
+        return HttpResponse::BadRequest().body("Pubkey not set");
+    };
+    match db_operations::get_labels(pubkey) {
+        Ok(rows) => HttpResponse::Ok()
+            .content_type("application/jsonl")
+            .insert_header(("Content-Disposition", "attachment; filename=\"labels.jsonl\""))
+            .body(labels::write_export(&rows)),
+        Err(e) => {
+            eprintln!("❌ Failed to load labels: {}", e);
+            HttpResponse::InternalServerError().body("Failed to load labels")
+        }
+    }
+}
+
+/// Imports a BIP-329 JSON Lines export, replacing labels of the same
+/// references.
+#[post("/labels")]
+pub async fn import_labels(req: HttpRequest, body: String) -> impl Responder {
+    let Some(pubkey) = req.cookie("nostr_pubkey").map(|c| c.value().to_string()) else {
+        return HttpResponse::BadRequest().body("Pubkey not set");
+    };
+    let import = labels::parse_export(&pubkey, &body, network::get_network());
+    let imported = import.labels.len();
+    if let Err(e) = labels::store(import.labels) {
+        eprintln!("❌ Failed to store labels: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to store labels");
+    }
+    HttpResponse::Ok().json(json!({
+        "imported": imported,
+        "skipped": import.skipped,
+        "invalid_lines": import.invalid,
+    }))
+}
@@ -0,0 +1,255 @@
+use bitcoin::{Block, BlockHash, Network, OutPoint, Script, Transaction, Txid};
+use bitcoincore_rpc::bitcoincore_rpc_json::{
+    GetBlockchainInfoResult, ScanTxOutRequest, ScanTxOutResult,
+};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use dotenvy::dotenv;
+use once_cell::sync::OnceCell;
+use std::path::PathBuf;
+use std::{env, fmt};
+use tokio::task;
+
+use crate::network;
+
+static GLOBAL_RPC_CLIENT: OnceCell<ChainClient> = OnceCell::new();
+
+/// bitcoind error code for unknown transactions, blocks and keys.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// The client could not be built from the supplied configuration.
+    Config(String),
+    /// The node could not be reached or answered with something unparseable.
+    Transport(bitcoincore_rpc::Error),
+    /// The node answered with a JSON-RPC error.
+    Rpc { code: i32, message: String },
+    /// The requested transaction or block is unknown to the node.
+    NotFound(String),
+    /// The blocking task running the call panicked or was cancelled.
+    Task(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Config(e) => write!(f, "invalid RPC configuration: {}", e),
+            RpcError::Transport(e) => write!(f, "RPC transport error: {}", e),
+            RpcError::Rpc { code, message } => write!(f, "RPC error {}: {}", code, message),
+            RpcError::NotFound(what) => write!(f, "not found on node: {}", what),
+            RpcError::Task(e) => write!(f, "RPC task failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<bitcoincore_rpc::Error> for RpcError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        match e {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err)) => {
+                if err.code == RPC_INVALID_ADDRESS_OR_KEY {
+                    RpcError::NotFound(err.message)
+                } else {
+                    RpcError::Rpc {
+                        code: err.code,
+                        message: err.message,
+                    }
+                }
+            }
+            other => RpcError::Transport(other),
+        }
+    }
+}
+
+/// Connection settings for the bitcoind JSON-RPC interface.
+#[derive(Debug, Clone)]
+pub struct RpcConfig {
+    pub url: String,
+    pub auth: RpcAuth,
+}
+
+#[derive(Debug, Clone)]
+pub enum RpcAuth {
+    None,
+    UserPass(String, String),
+    CookieFile(PathBuf),
+}
+
+impl RpcConfig {
+    /// Reads `BITCOIN_RPC_URL` and either `BITCOIN_RPC_COOKIE` or
+    /// `BITCOIN_RPC_USER`/`BITCOIN_RPC_PASSWORD` from the environment. Without
+    /// a URL, the default local port of `BITCOIN_NETWORK` is used.
+    pub fn from_env() -> Result<Self, RpcError> {
+        dotenv().ok();
+
+        let url = env::var("BITCOIN_RPC_URL").unwrap_or_else(|_| {
+            let network = network::configured_network()
+                .ok()
+                .flatten()
+                .unwrap_or(Network::Bitcoin);
+            format!("http://127.0.0.1:{}", network::default_rpc_port(network))
+        });
+        let auth = match (
+            env::var("BITCOIN_RPC_COOKIE"),
+            env::var("BITCOIN_RPC_USER"),
+            env::var("BITCOIN_RPC_PASSWORD"),
+        ) {
+            (Ok(cookie), _, _) => RpcAuth::CookieFile(PathBuf::from(cookie)),
+            (Err(_), Ok(user), Ok(pass)) => RpcAuth::UserPass(user, pass),
+            (Err(_), Ok(_), Err(_)) => {
+                return Err(RpcError::Config(
+                    "BITCOIN_RPC_USER is set but BITCOIN_RPC_PASSWORD is missing".into(),
+                ))
+            }
+            _ => RpcAuth::None,
+        };
+        Ok(RpcConfig { url, auth })
+    }
+}
+
+/// Thin wrapper around a single long-lived `bitcoincore_rpc::Client`.
+///
+/// The underlying transport keeps its TCP socket open between calls, so one
+/// instance should be shared rather than rebuilt per request.
+pub struct ChainClient {
+    client: Client,
+}
+
+impl ChainClient {
+    pub fn new(config: RpcConfig) -> Result<Self, RpcError> {
+        let auth = match config.auth {
+            RpcAuth::None => Auth::None,
+            RpcAuth::UserPass(user, pass) => Auth::UserPass(user, pass),
+            RpcAuth::CookieFile(path) => Auth::CookieFile(path),
+        };
+        let client = Client::new(&config.url, auth).map_err(|e| match e {
+            bitcoincore_rpc::Error::InvalidCookieFile | bitcoincore_rpc::Error::Io(_) => {
+                RpcError::Config(format!("unable to read cookie file: {}", e))
+            }
+            other => RpcError::from(other),
+        })?;
+        Ok(ChainClient { client })
+    }
+
+    pub fn get_blockchain_info(&self) -> Result<GetBlockchainInfoResult, RpcError> {
+        Ok(self.client.get_blockchain_info()?)
+    }
+
+    /// Fetches a transaction from the mempool or, with `-txindex`, the chain.
+    pub fn get_raw_transaction(&self, txid: &Txid) -> Result<Transaction, RpcError> {
+        self.client
+            .get_raw_transaction(txid, None)
+            .map_err(RpcError::from)
+            .map_err(|e| match e {
+                RpcError::NotFound(_) => RpcError::NotFound(txid.to_string()),
+                other => other,
+            })
+    }
+
+    /// Block a transaction confirmed in and its confirmations, `None` while
+    /// it is unconfirmed. Needs `-txindex` for transactions outside the
+    /// mempool.
+    pub fn get_transaction_block(&self, txid: &Txid) -> Result<Option<(BlockHash, u32)>, RpcError> {
+        let info = self
+            .client
+            .get_raw_transaction_info(txid, None)
+            .map_err(RpcError::from)
+            .map_err(|e| match e {
+                RpcError::NotFound(_) => RpcError::NotFound(txid.to_string()),
+                other => other,
+            })?;
+        Ok(info.blockhash.zip(info.confirmations))
+    }
+
+    pub fn get_block_count(&self) -> Result<u64, RpcError> {
+        Ok(self.client.get_block_count()?)
+    }
+
+    pub fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError> {
+        Ok(self.client.get_block_hash(height)?)
+    }
+
+    pub fn get_block(&self, hash: &BlockHash) -> Result<Block, RpcError> {
+        self.client
+            .get_block(hash)
+            .map_err(RpcError::from)
+            .map_err(|e| match e {
+                RpcError::NotFound(_) => RpcError::NotFound(hash.to_string()),
+                other => other,
+            })
+    }
+
+    /// Height of the given block in the node's view of the chain.
+    pub fn get_block_height(&self, hash: &BlockHash) -> Result<u64, RpcError> {
+        Ok(self.client.get_block_header_info(hash)?.height as u64)
+    }
+
+    /// Median time past of the given block, which time-based timelocks are
+    /// measured against.
+    pub fn get_median_time_past(&self, hash: &BlockHash) -> Result<u64, RpcError> {
+        self.client
+            .get_block_header_info(hash)?
+            .median_time
+            .map(|time| time as u64)
+            .ok_or_else(|| RpcError::NotFound(format!("median time of {}", hash)))
+    }
+
+    /// Walks back from `hash` until reaching a block on the active chain and
+    /// returns its height.
+    pub fn find_fork_point(&self, hash: &BlockHash) -> Result<u64, RpcError> {
+        let mut header = self.client.get_block_header_info(hash)?;
+        while header.confirmations < 0 {
+            let prev = header
+                .previous_block_hash
+                .ok_or_else(|| RpcError::NotFound(format!("parent of {}", header.hash)))?;
+            header = self.client.get_block_header_info(&prev)?;
+        }
+        Ok(header.height as u64)
+    }
+
+    /// Whether the output exists and is unspent, counting mempool spends.
+    pub fn is_unspent(&self, outpoint: &OutPoint) -> Result<bool, RpcError> {
+        Ok(self
+            .client
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .is_some())
+    }
+
+    /// Looks up the current unspent outputs paying to `script` in the node's
+    /// UTXO set. Works on pruned nodes but can take minutes on mainnet.
+    pub fn scan_utxos(&self, script: &Script) -> Result<ScanTxOutResult, RpcError> {
+        let descriptor = format!("raw({})", script.to_hex_string());
+        Ok(self
+            .client
+            .scan_tx_out_set_blocking(&[ScanTxOutRequest::Single(descriptor)])?)
+    }
+
+    pub fn is_in_mempool(&self, txid: &Txid) -> Result<bool, RpcError> {
+        match self.client.get_mempool_entry(txid) {
+            Ok(_) => Ok(true),
+            Err(e) => match RpcError::from(e) {
+                RpcError::NotFound(_) => Ok(false),
+                other => Err(other),
+            },
+        }
+    }
+}
+
+/// Returns the shared RPC client, building it from the environment on first use.
+pub fn get_rpc_client() -> Result<&'static ChainClient, RpcError> {
+    GLOBAL_RPC_CLIENT.get_or_try_init(|| ChainClient::new(RpcConfig::from_env()?))
+}
+
+/// Runs `f` against the shared client on the blocking thread pool, as the
+/// underlying transport is synchronous.
+pub async fn with_client<T, F>(f: F) -> Result<T, RpcError>
+where
+    T: Send + 'static,
+    F: FnOnce(&ChainClient) -> Result<T, RpcError> + Send + 'static,
+{
+    task::spawn_blocking(move || f(get_rpc_client()?))
+        .await
+        .map_err(|e| RpcError::Task(e.to_string()))?
+}
@@ -1,10 +1,20 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    chain_checkpoint (id) {
+        id -> Int4,
+        block_height -> Int4,
+        block_hash -> Text,
+    }
+}
+
 diesel::table! {
     gen_transactions (txid) {
         txid -> Text,
         output_address -> Array<Nullable<Text>>,
         input_address -> Array<Nullable<Text>>,
+        output_script -> Array<Text>,
+        spilled_at -> Timestamptz,
     }
 }
 
@@ -12,6 +22,19 @@ diesel::table! {
     input_transactions (txid) {
         txid -> Text,
         output_address -> Array<Nullable<Text>>,
+        output_script -> Array<Text>,
+        spilled_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    labels (nostr_pubkey, label_type, reference) {
+        nostr_pubkey -> Text,
+        label_type -> Text,
+        reference -> Text,
+        label -> Text,
+        origin -> Nullable<Text>,
+        spendable -> Nullable<Bool>,
     }
 }
 
@@ -21,14 +44,17 @@ diesel::table! {
         nostr_pubkey -> Text,
         txid -> Text,
         prev_txid -> Nullable<Text>,
-        address -> Array<Nullable<Text>>,
+        record -> Array<Text>,
+        block_hash -> Nullable<Text>,
+        block_height -> Nullable<Int4>,
     }
 }
 
 diesel::table! {
-    user_addresses (nostr_pubkey, address) {
+    user_addresses (nostr_pubkey, record) {
         nostr_pubkey -> Text,
-        address -> Text,
+        record -> Text,
+        kind -> Text,
     }
 }
 
@@ -38,12 +64,41 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    watched_utxos (txid, vout, nostr_pubkey) {
+        txid -> Text,
+        vout -> Int4,
+        nostr_pubkey -> Text,
+        record -> Text,
+        script_pubkey -> Text,
+        value -> Int8,
+        block_hash -> Nullable<Text>,
+        block_height -> Nullable<Int4>,
+        spent_by -> Nullable<Text>,
+        spent_height -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    derivation_windows (record, chain) {
+        record -> Text,
+        chain -> Int4,
+        next_index -> Int4,
+    }
+}
+
+diesel::joinable!(labels -> users (nostr_pubkey));
 diesel::joinable!(user_addresses -> users (nostr_pubkey));
+diesel::joinable!(watched_utxos -> users (nostr_pubkey));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    chain_checkpoint,
     gen_transactions,
     input_transactions,
+    labels,
     matched_addresses,
     user_addresses,
     users,
+    derivation_windows,
+    watched_utxos,
 );
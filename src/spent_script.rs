@@ -0,0 +1,127 @@
+use bitcoin::script::Instruction;
+use bitcoin::{CompressedPublicKey, PublicKey, Script, ScriptBuf, TxIn};
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Inputs between two statistics log lines.
+const STATS_INTERVAL: u64 = 10_000;
+
+static SPENT_SCRIPT_STATS: Lazy<SpentScriptStats> = Lazy::new(SpentScriptStats::default);
+
+#[derive(Debug, Default)]
+pub struct SpentScriptStats {
+    /// Spent scripts rebuilt from the input itself, each a lookup saved.
+    pub reconstructed: AtomicU64,
+    /// Inputs whose spent script had to be looked up.
+    pub looked_up: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub reconstructed: u64,
+    pub looked_up: u64,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inputs = self.reconstructed + self.looked_up;
+        let saved = if inputs == 0 {
+            0.0
+        } else {
+            self.reconstructed as f64 * 100.0 / inputs as f64
+        };
+        write!(
+            f,
+            "{} reconstructed ({:.1}% of lookups saved), {} looked up",
+            self.reconstructed, saved, self.looked_up
+        )
+    }
+}
+
+/// Counters of spent script reconstruction since startup.
+pub fn stats() -> StatsSnapshot {
+    StatsSnapshot {
+        reconstructed: SPENT_SCRIPT_STATS.reconstructed.load(Ordering::Relaxed),
+        looked_up: SPENT_SCRIPT_STATS.looked_up.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts one input, reconstructed or looked up.
+pub fn record(reconstructed: bool) {
+    let counter = if reconstructed {
+        &SPENT_SCRIPT_STATS.reconstructed
+    } else {
+        &SPENT_SCRIPT_STATS.looked_up
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+
+    let stats = stats();
+    if (stats.reconstructed + stats.looked_up).is_multiple_of(STATS_INTERVAL) {
+        println!("📊 Spent scripts: {}", stats);
+    }
+}
+
+/// Rebuilds the script of the output `input` spends from its witness and
+/// scriptSig. Handles P2WPKH, P2PKH, P2SH-wrapped segwit and P2WSH spends;
+/// anything else (P2PK, bare multisig, plain P2SH, taproot) or anything that
+/// could be read more than one way returns `None` and has to be looked up.
+pub fn reconstruct(input: &TxIn) -> Option<ScriptBuf> {
+    let witness: Vec<&[u8]> = input.witness.iter().collect();
+    let pushes = pushes(&input.script_sig)?;
+
+    match (pushes.as_slice(), witness.as_slice()) {
+        // P2WPKH: <signature> <compressed pubkey> in the witness.
+        ([], [sig, pubkey]) if is_signature(sig) && pubkey.len() == 33 => {
+            let pubkey = CompressedPublicKey::from_slice(pubkey).ok()?;
+            Some(ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()))
+        }
+        // P2WSH: the witness script comes last.
+        ([], [_, .., witness_script]) if !is_taproot_script_path(witness_script) => {
+            Some(ScriptBuf::new_p2wsh(&Script::from_bytes(witness_script).wscript_hash()))
+        }
+        // P2PKH: <signature> <pubkey> in the scriptSig.
+        ([sig, pubkey], []) if is_signature(sig) => {
+            let pubkey = PublicKey::from_slice(pubkey).ok()?;
+            Some(ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()))
+        }
+        // P2SH-P2WPKH and P2SH-P2WSH: the scriptSig only pushes the witness
+        // program it wraps.
+        ([redeem_script], [_, ..]) => {
+            let redeem_script = Script::from_bytes(redeem_script);
+            redeem_script
+                .is_witness_program()
+                .then(|| ScriptBuf::new_p2sh(&redeem_script.script_hash()))
+        }
+        _ => None,
+    }
+}
+
+/// The data pushed by a push-only scriptSig.
+fn pushes(script_sig: &Script) -> Option<Vec<&[u8]>> {
+    script_sig
+        .instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes.as_bytes()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A DER signature followed by its sighash byte.
+fn is_signature(bytes: &[u8]) -> bool {
+    (9..=73).contains(&bytes.len()) && bytes[0] == 0x30
+}
+
+/// A taproot control block or annex, which a P2WSH witness script could be
+/// mistaken for.
+fn is_taproot_script_path(last: &[u8]) -> bool {
+    let annex = last.first() == Some(&0x50);
+    annex || is_control_block(last)
+}
+
+/// The last witness element of a taproot script-path spend, after the
+/// annex if there is one.
+pub fn is_control_block(bytes: &[u8]) -> bool {
+    bytes.len() >= 33 && (bytes.len() - 33).is_multiple_of(32) && bytes[0] & 0xfe == 0xc0
+}
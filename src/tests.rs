@@ -1,8 +1,60 @@
-use bitcoin::{consensus::deserialize, Transaction};
+use bitcoin::consensus::deserialize;
+use bitcoin::consensus::encode::{serialize, serialize_hex};
+use bitcoin::hash_types::TxMerkleNode;
+use bitcoin::hashes::Hash;
+use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_CHECKSIG, OP_PUSHBYTES_0, OP_PUSHNUM_1, OP_PUSHNUM_2};
+use bitcoin::opcodes::all::{OP_CLTV, OP_CSV, OP_DROP};
+use bitcoin::pow::CompactTarget;
+use bitcoin::script::{Builder, PushBytes};
+use bitcoin::{absolute, block, relative, transaction, Address, Amount, Block, BlockHash, Network};
+use bitcoin::{CompressedPublicKey, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Txid};
+use bitcoin::{WPubkeyHash, Witness};
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::task;
 
-use crate::{find_address_match, nostr_notify};
+use crate::chain_source::{
+    BitcoindSource, ChainSource, ChainSourceError, ChainTip, OutpointStatus,
+};
+use crate::chain_tracker::{ChainTracker, TipUpdate, TrackedBlock, REORG_WINDOW};
+use crate::electrum::{script_hash, ElectrumSource};
+use crate::esplora::{EsploraConfig, EsploraSource};
+use crate::models::RecordType;
+use crate::labels::{self, LabelIndex};
+use crate::network::parse_network;
+use crate::pipeline::{
+    in_turn, start_with_stats, Handler, Intake, OverflowPolicy, PipelineConfig, PipelineStats,
+};
+use crate::prevout_cache::{CachedOutputs, PrevoutCache};
+use crate::rpc::{ChainClient, RpcAuth, RpcConfig, RpcError};
+use crate::{pubkey, spent_script};
+use crate::utxo_index::{Balance, Coin, UtxoIndex};
+use crate::wallet_import;
+use crate::watch_index::{WatchIndex, WatchUpdate};
+use crate::derivation::DerivationWindows;
+use crate::descriptor::WatchedDescriptor;
+use crate::timelock::{script_timelocks, Timelock};
+use crate::tx_watch::{PendingTxs, TxWatch};
+use crate::xpub::ExtendedKey;
+use crate::{chain_source, find_address_match, nostr_notify};
 
-pub async fn test_site() {
+/// BIP32 test vector 1 master key, as xpub and tpub.
+const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+const TPUB: &str = "tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp";
+
+const MOCK_TX_HEX: &str = "010000000001016423840692dd02b1aa0e92c86063164dd51a70aca4e5ee0508bd27bb1a01ff7b0200000000ffffffff0142580000000000001976a914a6f376c5edaee2f0d828ced5b0968a6145d27b7788ac024730440220661117cf61bbc77793d661c0cffea1678b1e6c92099c32a312bf1dc09d6e19550220167c71601941e4c57b7c95fb162420ddb975a0c883af9d759db5376ea06c5214012102816fe7c2f6e6a6263107fe9f49ef48a049f14b86925e27c474721f546faf003400000000";
+
+#[tokio::test]
+#[ignore = "needs a database, a node and Nostr relays"]
+async fn test_site() {
     // Addresses in the tx below
     //
     // bc1qvnd67dh2ped0tpmlrwhpcqalmg5d0yaaenvefz
@@ -21,13 +73,1600 @@ pub async fn test_site() {
         "01000000000101987b134bb2696009b2017c3606282db8e3a763553e0a3db90a9bf06350b12fee1100000023220020e950ab259450b6fe5ae4e32744177e3b33e966a49898f8261d886888c21c236cffffffff02ab3e0000000000002200204d054bc4b426a8a166ab6af20f4753436b8c80d1a87bba78dd7d160e945c31f4a82c030000000000160014a2454be60624642a8c05bdc51eb637edc76267dd03004730440220630b01390bd95db8a92f154607fb2d1a8d445bd72772e526978ce578e43dde46022020af032ccd53a31b81d7ca5aff42376b7a944f75b720a20dec1d5ee7186aa89e0125512103bff9fbb87a23082ffc2c0aacaa48d0353b92bfa6bb9a2a1306ba410f612b453d51ae00000000",
     ];
 
+    chain_source::init_chain_source(true).expect("Failed to select chain source");
     nostr_notify::get_nostr_client().await;
     for hx in mock_tx_hex {
         let tx_data = hex::decode(hx).unwrap();
         if let Ok(tx) = deserialize::<Transaction>(&tx_data) {
-            find_address_match(tx, true).await;
+            find_address_match(tx).await;
         } else {
             println!("Failed to decode transaction.");
         }
     }
 }
+
+/// Spawns a minimal HTTP server on a random local port that answers every
+/// request with `handler(path, body)` as `(status, body)`. Returns the server
+/// URL and a counter of accepted TCP connections.
+pub fn spawn_mock_http_server<F>(handler: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&str, &[u8]) -> (u16, Vec<u8>) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock HTTP server");
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let handler = Arc::new(handler);
+
+    let counter = connections.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let handler = handler.clone();
+            thread::spawn(move || serve_mock_http_connection(stream, handler.as_ref()));
+        }
+    });
+    (url, connections)
+}
+
+fn serve_mock_http_connection<F>(stream: TcpStream, handler: &F)
+where
+    F: Fn(&str, &[u8]) -> (u16, Vec<u8>),
+{
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        let (status, response) = handler(&path, &body);
+        let head = format!(
+            "HTTP/1.1 {} MOCK\r\nContent-Length: {}\r\n\r\n",
+            status,
+            response.len()
+        );
+        if writer.write_all(head.as_bytes()).is_err() || writer.write_all(&response).is_err() {
+            return;
+        }
+    }
+}
+
+/// JSON-RPC over HTTP on top of `spawn_mock_http_server`, answering every
+/// call with `handler(method, params)`.
+pub fn spawn_mock_rpc_server<F>(handler: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&str, &Value) -> Result<Value, (i32, String)> + Send + Sync + 'static,
+{
+    spawn_mock_http_server(move |_path, body| {
+        let request: Value = serde_json::from_slice(body).unwrap_or(Value::Null);
+        (200, mock_rpc_response(&request, &handler).to_string().into_bytes())
+    })
+}
+
+/// Newline-delimited JSON-RPC over TCP, as spoken by Electrum servers.
+pub fn spawn_mock_electrum_server<F>(handler: F) -> String
+where
+    F: Fn(&str, &Value) -> Result<Value, (i32, String)> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock Electrum server");
+    let addr = format!("tcp://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let mut writer = stream.try_clone().unwrap();
+                for line in BufReader::new(stream).lines() {
+                    let Ok(line) = line else { return };
+                    let request: Value = serde_json::from_str(&line).unwrap_or(Value::Null);
+                    let mut response = mock_rpc_response(&request, handler.as_ref()).to_string();
+                    response.push('\n');
+                    if writer.write_all(response.as_bytes()).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+fn mock_rpc_response<F>(request: &Value, handler: &F) -> Value
+where
+    F: Fn(&str, &Value) -> Result<Value, (i32, String)>,
+{
+    let method = request["method"].as_str().unwrap_or_default();
+    match handler(method, &request["params"]) {
+        Ok(result) => json!({ "result": result, "error": null, "id": request["id"] }),
+        Err((code, message)) => json!({
+            "result": null,
+            "error": { "code": code, "message": message },
+            "id": request["id"],
+        }),
+    }
+}
+
+/// Exercises the RPC client against the mock server: typed responses, error
+/// mapping and reuse of a single connection across calls.
+#[test]
+fn test_rpc_client() {
+    let known_tx_hex = MOCK_TX_HEX;
+    let known_tx: Transaction = deserialize(&hex::decode(known_tx_hex).unwrap()).unwrap();
+    let known_txid = known_tx.compute_txid();
+
+    let (url, connections) = spawn_mock_rpc_server(move |method, params| match method {
+        "getblockchaininfo" => Ok(json!({
+            "chain": "main",
+            "blocks": 880000,
+            "headers": 880000,
+            "bestblockhash": "000000000000000000015d5a6d8a1ae5c5a1e2b47a1cbd9b2c7f2a0bd5a1e3b2",
+            "difficulty": 1.0,
+            "mediantime": 1738000000,
+            "verificationprogress": 1.0,
+            "initialblockdownload": false,
+            "chainwork": "00",
+            "size_on_disk": 1000,
+            "pruned": true,
+            "pruneheight": 870000,
+            "warnings": "",
+        })),
+        "getnetworkinfo" => Ok(json!({ "version": 280000 })),
+        "getrawtransaction" if params[0] == json!(known_txid.to_string()) => {
+            Ok(json!(known_tx_hex))
+        }
+        "getrawtransaction" => Err((
+            -5,
+            "No such mempool or blockchain transaction".to_string(),
+        )),
+        _ => Err((-32601, "Method not found".to_string())),
+    });
+
+    let client = ChainClient::new(RpcConfig {
+        url,
+        auth: RpcAuth::UserPass("user".into(), "pass".into()),
+    })
+    .expect("Failed to build RPC client");
+
+    let info = client.get_blockchain_info().expect("getblockchaininfo failed");
+    assert!(info.pruned);
+    assert_eq!(info.prune_height, Some(870000));
+
+    let tx = client.get_raw_transaction(&known_txid).expect("getrawtransaction failed");
+    assert_eq!(tx.compute_txid(), known_txid);
+
+    let missing = Txid::from_str(&"00".repeat(32)).unwrap();
+    match client.get_raw_transaction(&missing) {
+        Err(RpcError::NotFound(txid)) => assert_eq!(txid, missing.to_string()),
+        other => panic!("expected NotFound, got {:?}", other.map(|t| t.compute_txid())),
+    }
+
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+/// Feeds a synthetic chain with a two-block fork through the tip tracker.
+#[test]
+fn test_chain_tracker_reorg() {
+    let hash = |n: u8| BlockHash::from_byte_array([n; 32]);
+
+    // a0 <- a1 <- a2 <- a3
+    //              \
+    //               b3 <- b4
+    let mut tracker = ChainTracker::new(REORG_WINDOW);
+    assert_eq!(tracker.connect(hash(1), hash(0)), TipUpdate::UnknownParent);
+    assert!(tracker.reset(100, hash(0)).is_empty());
+    assert_eq!(tracker.connect(hash(1), hash(0)), TipUpdate::Connected { height: 101 });
+    assert_eq!(tracker.connect(hash(2), hash(1)), TipUpdate::Connected { height: 102 });
+    assert_eq!(tracker.connect(hash(3), hash(2)), TipUpdate::Connected { height: 103 });
+    assert_eq!(tracker.connect(hash(2), hash(1)), TipUpdate::AlreadyKnown { height: 102 });
+
+    assert_eq!(
+        tracker.connect(hash(13), hash(1)),
+        TipUpdate::Reorg {
+            height: 102,
+            disconnected: vec![
+                TrackedBlock { height: 103, hash: hash(3) },
+                TrackedBlock { height: 102, hash: hash(2) },
+            ],
+        }
+    );
+    assert_eq!(tracker.connect(hash(14), hash(13)), TipUpdate::Connected { height: 103 });
+    assert_eq!(
+        tracker.tip(),
+        Some(TrackedBlock { height: 103, hash: hash(14) })
+    );
+
+    // A block whose parent fell out of the window cannot be placed.
+    let mut small = ChainTracker::new(2);
+    small.reset(0, hash(0));
+    small.connect(hash(1), hash(0));
+    small.connect(hash(2), hash(1));
+    assert_eq!(small.connect(hash(21), hash(0)), TipUpdate::UnknownParent);
+    assert_eq!(
+        small.reset(1, hash(21)),
+        vec![
+            TrackedBlock { height: 2, hash: hash(2) },
+            TrackedBlock { height: 1, hash: hash(1) },
+        ]
+    );
+}
+
+struct ChainFixture {
+    tx: Transaction,
+    spender: Transaction,
+    block: Block,
+    height: u64,
+}
+
+/// A funding transaction, a child spending its first output, and a block
+/// containing both.
+fn chain_fixture() -> ChainFixture {
+    let tx: Transaction = deserialize(&hex::decode(MOCK_TX_HEX).unwrap()).unwrap();
+    let spender = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx.compute_txid(), 0),
+            ..TxIn::default()
+        }],
+        output: vec![TxOut {
+            value: Amount::from_sat(21_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([7; 20])),
+        }],
+    };
+    let block = Block {
+        header: block::Header {
+            version: block::Version::TWO,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1_738_000_000,
+            bits: CompactTarget::from_consensus(0x1d00ffff),
+            nonce: 0,
+        },
+        txdata: vec![tx.clone(), spender.clone()],
+    };
+    ChainFixture {
+        tx,
+        spender,
+        block,
+        height: 880_000,
+    }
+}
+
+/// Runs the same queries against every backend, each talking to a local
+/// stand-in server, and checks they agree.
+#[tokio::test]
+async fn test_chain_sources() {
+    let fixture = Arc::new(chain_fixture());
+    let missing = Txid::from_byte_array([0; 32]);
+
+    let f = fixture.clone();
+    let (rpc_url, _) = spawn_mock_rpc_server(move |method, params| {
+        let txid_param = params[0].as_str().unwrap_or_default();
+        match method {
+            "getnetworkinfo" => Ok(json!({ "version": 280000 })),
+            "getrawtransaction" => [&f.tx, &f.spender]
+                .into_iter()
+                .find(|tx| tx.compute_txid().to_string() == txid_param)
+                .map(|tx| json!(serialize_hex(tx)))
+                .ok_or((-5, "No such mempool or blockchain transaction".to_string())),
+            "getblockcount" => Ok(json!(f.height)),
+            "getblockhash" if params[0] == json!(f.height) => {
+                Ok(json!(f.block.block_hash().to_string()))
+            }
+            "getblock" if txid_param == f.block.block_hash().to_string() => {
+                Ok(json!(serialize_hex(&f.block)))
+            }
+            "gettxout" if txid_param == f.spender.compute_txid().to_string() => Ok(json!({
+                "bestblock": f.block.block_hash().to_string(),
+                "confirmations": 1,
+                "value": 0.00021,
+                "scriptPubKey": {
+                    "asm": "",
+                    "hex": f.spender.output[0].script_pubkey.to_hex_string(),
+                },
+                "coinbase": false,
+            })),
+            "gettxout" => Ok(Value::Null),
+            _ => Err((-8, format!("unexpected call {}", method))),
+        }
+    });
+    let rpc_client = ChainClient::new(RpcConfig {
+        url: rpc_url,
+        auth: RpcAuth::None,
+    })
+    .expect("Failed to build RPC client");
+    let rpc_client: &'static ChainClient = Box::leak(Box::new(rpc_client));
+    let bitcoind = BitcoindSource::new(rpc_client);
+
+    let f = fixture.clone();
+    let (esplora_url, _) = spawn_mock_http_server(move |path, _body| {
+        let txid = f.tx.compute_txid();
+        let spender_txid = f.spender.compute_txid();
+        let block_hash = f.block.block_hash();
+        let ok = |body: String| (200, body.into_bytes());
+        if path == format!("/tx/{}/hex", txid) {
+            ok(serialize_hex(&f.tx))
+        } else if path == format!("/tx/{}/hex", spender_txid) {
+            ok(serialize_hex(&f.spender))
+        } else if path == format!("/block/{}/raw", block_hash) {
+            (200, serialize(&f.block))
+        } else if path == "/blocks/tip/height" {
+            ok(f.height.to_string())
+        } else if path == "/blocks/tip/hash" || path == format!("/block-height/{}", f.height) {
+            ok(block_hash.to_string())
+        } else if path == format!("/tx/{}/outspend/0", txid) {
+            ok(json!({ "spent": true, "txid": spender_txid.to_string(), "vin": 0 }).to_string())
+        } else if path == format!("/tx/{}/outspend/0", spender_txid) {
+            ok(json!({ "spent": false }).to_string())
+        } else {
+            (404, b"Transaction not found".to_vec())
+        }
+    });
+    let esplora = EsploraSource::new(esplora_url);
+
+    let f = fixture.clone();
+    let electrum_url = spawn_mock_electrum_server(move |method, params| {
+        let txid = f.tx.compute_txid();
+        let spender_txid = f.spender.compute_txid();
+        let header_hex = serialize_hex(&f.block.header);
+        let param = params[0].as_str().unwrap_or_default().to_string();
+        match method {
+            "server.version" => Ok(json!(["mock", "1.4"])),
+            "blockchain.transaction.get" => [&f.tx, &f.spender]
+                .into_iter()
+                .find(|tx| tx.compute_txid().to_string() == param)
+                .map(|tx| json!(serialize_hex(tx)))
+                .ok_or((2, "No such mempool or blockchain transaction".to_string())),
+            "blockchain.headers.subscribe" => Ok(json!({ "height": f.height, "hex": header_hex })),
+            "blockchain.block.header" if params[0] == json!(f.height) => Ok(json!(header_hex)),
+            "blockchain.scripthash.listunspent" => {
+                if param == script_hash(&f.spender.output[0].script_pubkey) {
+                    Ok(json!([{ "tx_hash": spender_txid.to_string(), "tx_pos": 0, "height": f.height, "value": 21000 }]))
+                } else {
+                    Ok(json!([]))
+                }
+            }
+            "blockchain.scripthash.get_history" => Ok(json!([
+                { "tx_hash": txid.to_string(), "height": f.height },
+                { "tx_hash": spender_txid.to_string(), "height": f.height },
+            ])),
+            _ => Err((1, format!("unexpected call {}", method))),
+        }
+    });
+    let electrum = ElectrumSource::new(&electrum_url, BitcoindSource::new(rpc_client))
+        .expect("Failed to build Electrum source");
+
+    let sources: [&dyn ChainSource; 3] = [&bitcoind, &esplora, &electrum];
+    let txid = fixture.tx.compute_txid();
+    let spender_txid = fixture.spender.compute_txid();
+    let block_hash = fixture.block.block_hash();
+    for source in sources {
+        let name = source.name();
+        let tx = source.get_transaction(&txid).await.expect(name);
+        assert_eq!(tx.compute_txid(), txid, "{}", name);
+        assert!(
+            matches!(source.get_transaction(&missing).await, Err(ChainSourceError::NotFound(_))),
+            "{}: missing tx should be NotFound",
+            name
+        );
+
+        let tip = source.get_tip().await.expect(name);
+        assert_eq!(tip, ChainTip { height: fixture.height, hash: block_hash }, "{}", name);
+        assert_eq!(source.get_block_hash(fixture.height).await.expect(name), block_hash, "{}", name);
+
+        let block = source.get_block(&block_hash).await.expect(name);
+        assert_eq!(block.txdata.len(), 2, "{}", name);
+
+        let spent = source.get_outpoint_status(&OutPoint::new(txid, 0)).await.expect(name);
+        let expected_spender = if name == "bitcoind" { None } else { Some(spender_txid) };
+        assert_eq!(spent, OutpointStatus::Spent { spending_txid: expected_spender }, "{}", name);
+        let unspent = source
+            .get_outpoint_status(&OutPoint::new(spender_txid, 0))
+            .await
+            .expect(name);
+        assert_eq!(unspent, OutpointStatus::Unspent, "{}", name);
+    }
+}
+
+/// Checks the Esplora client's retries, request coalescing, cache bound and
+/// rate limit against a stand-in server that starts out throttling.
+#[tokio::test]
+async fn test_esplora_client() {
+    let fixture = Arc::new(chain_fixture());
+    let txid = fixture.tx.compute_txid();
+    let spender_txid = fixture.spender.compute_txid();
+    let missing = Txid::from_byte_array([0; 32]);
+    let garbage = Txid::from_byte_array([0xff; 32]);
+    let requests = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+
+    let (f, counts) = (fixture.clone(), requests.clone());
+    let (url, _) = spawn_mock_http_server(move |path, _body| {
+        let count = {
+            let mut counts = counts.lock().unwrap();
+            let count = counts.entry(path.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if path == format!("/tx/{}/hex", txid) {
+            // Slow enough for concurrent lookups to overlap.
+            thread::sleep(Duration::from_millis(50));
+            match count {
+                1 => (429, b"Too Many Requests".to_vec()),
+                2 => (503, b"Service Unavailable".to_vec()),
+                _ => (200, serialize_hex(&f.tx).into_bytes()),
+            }
+        } else if path == format!("/tx/{}/hex", spender_txid) {
+            (200, serialize_hex(&f.spender).into_bytes())
+        } else if path == "/blocks/tip/height" {
+            (500, b"Internal Server Error".to_vec())
+        } else if path == format!("/tx/{}/hex", garbage) {
+            (200, b"not hex".to_vec())
+        } else {
+            (404, b"Transaction not found".to_vec())
+        }
+    });
+    let hits = |path: String| requests.lock().unwrap().get(&path).copied().unwrap_or(0);
+
+    let esplora = EsploraSource::with_config(
+        url.clone(),
+        EsploraConfig {
+            requests_per_second: 1000.0,
+            burst: 100,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+            cache_size: 1,
+        },
+    );
+
+    let lookups = join_all((0..5).map(|_| esplora.get_transaction(&txid))).await;
+    for tx in lookups {
+        assert_eq!(tx.expect("retried lookup should succeed").compute_txid(), txid);
+    }
+    assert_eq!(hits(format!("/tx/{}/hex", txid)), 3, "one request per attempt, shared by all callers");
+
+    esplora.get_transaction(&txid).await.unwrap();
+    assert_eq!(hits(format!("/tx/{}/hex", txid)), 3, "second lookup should be cached");
+
+    // A cache of one entry evicts the first transaction.
+    esplora.get_transaction(&spender_txid).await.unwrap();
+    esplora.get_transaction(&txid).await.unwrap();
+    assert_eq!(hits(format!("/tx/{}/hex", txid)), 4);
+
+    assert!(matches!(
+        esplora.get_transaction(&missing).await,
+        Err(ChainSourceError::NotFound(_))
+    ));
+    assert_eq!(hits(format!("/tx/{}/hex", missing)), 1, "404 is not retried");
+
+    assert!(matches!(
+        esplora.get_transaction(&garbage).await,
+        Err(ChainSourceError::Backend(_))
+    ));
+
+    assert!(matches!(esplora.get_tip().await, Err(ChainSourceError::Backend(_))));
+    assert_eq!(hits("/blocks/tip/height".to_string()), 4, "initial attempt plus max_retries");
+
+    let throttled = EsploraSource::with_config(
+        url,
+        EsploraConfig {
+            requests_per_second: 50.0,
+            burst: 1,
+            ..EsploraConfig::default()
+        },
+    );
+    let started = Instant::now();
+    for n in 1..=6u8 {
+        let _ = throttled.get_transaction(&Txid::from_byte_array([n; 32])).await;
+    }
+    assert!(
+        started.elapsed() >= Duration::from_millis(95),
+        "6 requests at 50/s with a burst of 1 took only {:?}",
+        started.elapsed()
+    );
+}
+
+/// Checks the prevout cache's LRU order, which evictions need spilling, and
+/// the hit counter.
+#[test]
+fn test_prevout_cache() {
+    let txid = |n: u8| Txid::from_byte_array([n; 32]);
+    let fetched = |addr: &str| CachedOutputs {
+        output_address: vec![Some(addr.to_string()), None],
+        output_script: vec![ScriptBuf::new(), ScriptBuf::new()],
+        input_address: None,
+    };
+    let cache = PrevoutCache::new(2);
+
+    assert!(cache.insert(txid(1), fetched("a"), false).is_empty());
+    assert!(cache.insert(txid(2), fetched("b"), true).is_empty());
+    assert_eq!(cache.get(&txid(1)), Some(fetched("a")), "1 is now most recently used");
+    assert_eq!(cache.get(&txid(9)), None);
+
+    // 2 is evicted but already persisted, so nothing needs spilling.
+    assert!(cache.insert(txid(3), fetched("c"), false).is_empty());
+    assert_eq!(cache.get(&txid(2)), None);
+
+    // Processing 3 upgrades the fetched entry with its input addresses.
+    let processed = CachedOutputs {
+        output_address: vec![Some("c".to_string()), None],
+        output_script: vec![ScriptBuf::new(), ScriptBuf::new()],
+        input_address: Some(vec![Some("a".to_string())]),
+    };
+    assert!(cache.insert(txid(3), processed.clone(), false).is_empty());
+
+    let evicted = cache.insert(txid(4), fetched("d"), false);
+    assert_eq!(evicted, vec![(txid(1), fetched("a"))]);
+    let evicted = cache.insert(txid(5), fetched("e"), false);
+    assert_eq!(evicted, vec![(txid(3), processed)]);
+
+    let stats = cache.stats();
+    assert_eq!(stats.memory_hits, 1);
+    assert_eq!(stats.spilled, 2);
+}
+
+/// Checks that records only parse for the network they belong to.
+#[test]
+fn test_record_network() {
+    let mainnet = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+    let testnet = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    let regtest = Address::from_script(
+        &ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([7; 20])),
+        Network::Regtest,
+    )
+    .unwrap()
+    .to_string();
+
+    assert!(matches!(
+        RecordType::parse(mainnet, Network::Bitcoin),
+        Ok(RecordType::Address(_))
+    ));
+    assert!(RecordType::parse(mainnet, Network::Signet).is_err());
+    assert!(RecordType::parse(testnet, Network::Bitcoin).is_err());
+    // Testnet, testnet4 and signet share their address prefixes.
+    for network in [Network::Testnet, Network::Testnet4, Network::Signet] {
+        assert!(RecordType::parse(testnet, network).is_ok(), "{}", network);
+    }
+    assert!(RecordType::parse(&regtest, Network::Regtest).is_ok());
+    assert!(RecordType::parse(&regtest, Network::Testnet).is_err());
+
+    assert!(matches!(
+        RecordType::parse(TPUB, Network::Regtest),
+        Ok(RecordType::Xpub(_))
+    ));
+    assert!(RecordType::parse(XPUB, Network::Regtest).is_err());
+    assert!(RecordType::parse(TPUB, Network::Bitcoin).is_err());
+
+    let record = RecordType::parse(mainnet, Network::Bitcoin).unwrap();
+    assert_eq!(serde_json::to_string(&record).unwrap(), format!("\"{}\"", mainnet));
+
+    assert_eq!(parse_network("main"), Some(Network::Bitcoin));
+    assert_eq!(parse_network("test"), Some(Network::Testnet));
+    assert_eq!(parse_network("testnet4"), Some(Network::Testnet4));
+    assert_eq!(parse_network("Signet"), Some(Network::Signet));
+    assert_eq!(parse_network("regtest"), Some(Network::Regtest));
+    assert_eq!(parse_network("liquid"), None);
+}
+
+/// Checks that raw scripts without an address form can be watched and are
+/// shown as an address when they have one.
+#[test]
+fn test_script_records() {
+    // The first output ever mined, paying to a bare public key.
+    let p2pk = "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac";
+    let multisig = Builder::new()
+        .push_opcode(OP_PUSHNUM_1)
+        .push_slice([2u8; 33])
+        .push_slice([3u8; 33])
+        .push_opcode(OP_PUSHNUM_2)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([7; 20]));
+
+    let record = RecordType::parse(p2pk, Network::Bitcoin).unwrap();
+    assert!(matches!(record, RecordType::Script(_)));
+    assert_eq!(record.to_string(), p2pk);
+    assert_eq!(record.label(), p2pk, "P2PK has no address");
+    assert!(RecordType::parse("", Network::Bitcoin).is_err());
+
+    let bare = RecordType::parse(&multisig.to_hex_string(), Network::Bitcoin).unwrap();
+    assert_eq!(bare.script_pubkeys(), vec![multisig.clone()]);
+    let segwit = RecordType::parse(&p2wpkh.to_hex_string(), Network::Bitcoin).unwrap();
+    assert_eq!(
+        segwit.label(),
+        Address::from_script(&p2wpkh, Network::Bitcoin).unwrap().to_string()
+    );
+
+    let mut index = WatchIndex::default();
+    index.insert("alice".into(), record.clone());
+    index.insert("bob".into(), bare.clone());
+    index.insert("carol".into(), segwit.clone());
+    let tx_scripts = [record.script_pubkeys().remove(0), multisig, p2wpkh];
+    let matches = index.match_scripts(tx_scripts.iter().map(|s| s.as_script()));
+    assert_eq!(matches["alice"], vec![p2pk.to_string()]);
+    assert_eq!(matches["bob"], vec![bare.to_string()]);
+    assert_eq!(matches["carol"], vec![segwit.label()]);
+    assert!(index.remove("carol", &segwit));
+}
+
+/// Checks that `txid:vout` records parse and are matched by the outpoint an
+/// input spends.
+#[test]
+fn test_utxo_records() {
+    let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    let outpoint = OutPoint::new(Txid::from_str(txid).unwrap(), 1);
+    let record = RecordType::parse(&format!("{}:1", txid), Network::Bitcoin).unwrap();
+    assert_eq!(record, RecordType::Utxo(outpoint));
+    assert_eq!(record.to_string(), format!("{}:1", txid));
+    assert!(record.script_pubkeys().is_empty());
+    assert!(RecordType::parse(&format!("{}:x", txid), Network::Bitcoin).is_err());
+    assert!(RecordType::parse("4a5e:1", Network::Bitcoin).is_err());
+
+    let mut index = WatchIndex::default();
+    index.insert("alice".into(), record.clone());
+    index.insert("bob".into(), record.clone());
+    let other = OutPoint::new(outpoint.txid, 0);
+    let matches = index.match_outpoints([&other, &outpoint]);
+    assert_eq!(matches.len(), 2);
+    assert_eq!(matches["alice"], vec![record.to_string()]);
+    assert!(index.match_outpoints([&other]).is_empty());
+
+    assert!(index.remove("alice", &record));
+    let matches = index.match_outpoints([&outpoint]);
+    assert_eq!(matches.keys().collect::<Vec<_>>(), vec!["bob"]);
+}
+
+/// Checks addresses derived from account keys against the BIP49, BIP84 and
+/// BIP86 test vectors, and that the gap limit window follows usage.
+#[test]
+fn test_xpub_records() {
+    let zpub = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    let ypub = "ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLDWCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP";
+    let bip86 = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ#tr";
+    let address = |script: &ScriptBuf| Address::from_script(script, Network::Bitcoin).unwrap().to_string();
+    let first = |key: &str, chain: u32| {
+        let key = ExtendedKey::parse(key).unwrap();
+        address(&key.scripts(chain, 0..1)[0])
+    };
+
+    assert_eq!(first(zpub, 0), "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu");
+    assert_eq!(first(zpub, 1), "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el");
+    assert_eq!(first(ypub, 0), "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf");
+    assert_eq!(
+        first(bip86, 0),
+        "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+    );
+    assert!(ExtendedKey::parse(&format!("{}#p2wsh", zpub)).is_err());
+    assert!(ExtendedKey::parse(&zpub[..zpub.len() - 1]).is_err(), "bad checksum");
+    let record = RecordType::parse(zpub, Network::Bitcoin).unwrap();
+    assert!(matches!(record, RecordType::Xpub(_)));
+    assert_eq!(record.to_string(), zpub);
+
+    let key = ExtendedKey::parse(zpub).unwrap();
+    let mut windows = DerivationWindows::default();
+    let scripts = windows.extend(&record, 5);
+    assert_eq!(scripts.len(), 10, "both chains up to the gap limit");
+    let receive_3 = key.scripts(0, 3..4).remove(0);
+    let change_0 = key.scripts(1, 0..1).remove(0);
+    assert!(scripts.contains(&receive_3) && scripts.contains(&change_0));
+
+    let moved = windows.mark_used([receive_3.as_script(), change_0.as_script()]);
+    assert_eq!(moved, vec![record.clone()]);
+    assert_eq!(windows.next_unused(&record), vec![4, 1]);
+    assert!(windows.mark_used([receive_3.as_script()]).is_empty(), "already used");
+    let scripts = windows.extend(&record, 5);
+    assert_eq!(scripts.len(), 9 + 6);
+    assert!(scripts.contains(&key.scripts(0, 8..9).remove(0)));
+    assert!(!scripts.contains(&key.scripts(0, 9..10).remove(0)));
+
+    windows.forget(&record);
+    assert_eq!(windows.origin(&receive_3), None, "forgotten scripts have no origin");
+    assert!(windows.next_unused(&record).is_empty());
+    assert_eq!(windows.extend(&record, 5).len(), 10, "derived afresh");
+}
+
+/// Checks descriptor parsing, checksums and networks, and the scripts of
+/// ranged, multipath, multisig and taproot descriptors.
+#[test]
+fn test_descriptor_records() {
+    // The BIP84 and BIP86 test vector account keys.
+    let bip84 = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+    let bip86 = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+    let parse = |s: &str| WatchedDescriptor::parse(s, Network::Bitcoin);
+    let address = |script: &ScriptBuf| Address::from_script(script, Network::Bitcoin).unwrap().to_string();
+
+    let wpkh = parse(&format!("wpkh([73c5da0a/84'/0'/0']{}/<0;1>/*)", bip84)).unwrap();
+    assert_eq!(wpkh.chain_count(), 2);
+    assert_eq!(
+        address(&wpkh.scripts(0, 0..1)[0]),
+        "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+    );
+    assert_eq!(
+        address(&wpkh.scripts(1, 0..1)[0]),
+        "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"
+    );
+    assert_eq!(wpkh.scripts(0, 0..20).len(), 20);
+    assert!(wpkh.scripts(2, 0..1).is_empty());
+
+    let tr = parse(&format!("tr({}/0/*)", bip86)).unwrap();
+    assert_eq!(
+        address(&tr.scripts(0, 0..1)[0]),
+        "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+    );
+
+    // Key order doesn't matter to sortedmulti.
+    let vault = parse(&format!("wsh(sortedmulti(2,{}/0/*,{}/0/*))", bip84, bip86)).unwrap();
+    let reordered = parse(&format!("wsh(sortedmulti(2,{}/0/*,{}/0/*))", bip86, bip84)).unwrap();
+    assert_eq!(vault.scripts(0, 0..5), reordered.scripts(0, 0..5));
+    assert!(vault.scripts(0, 0..1)[0].is_p2wsh());
+    let nested = parse(&format!("sh(wpkh({}/0/*))", bip84)).unwrap();
+    assert!(nested.scripts(0, 0..1)[0].is_p2sh());
+
+    // Checksums are checked when given and always written out.
+    let written = vault.to_string();
+    assert!(written.contains('#'));
+    assert_eq!(parse(&written).unwrap(), vault);
+    let (body, checksum) = written.split_once('#').unwrap();
+    let corrupted = format!("{}#{}", body, checksum.chars().rev().collect::<String>());
+    assert!(parse(&corrupted).is_err());
+
+    let single = parse("pkh(02e493dbf1c10d80f3581e4904930b1404cc6c13900ee0758474fa94abe8c4cd13)").unwrap();
+    assert_eq!(single.scripts(0, 0..20).len(), 1, "no wildcard, one script");
+    assert!(single.scripts(0, 1..20).is_empty());
+
+    assert!(parse(&format!("wpkh({}/0/*')", bip84)).is_err(), "hardened wildcard");
+    assert!(WatchedDescriptor::parse(&format!("wpkh({}/0/*)", TPUB), Network::Bitcoin).is_err());
+    assert!(WatchedDescriptor::parse(&format!("wpkh({}/0/*)", TPUB), Network::Testnet).is_ok());
+    assert!(parse("wpkh(").is_err());
+
+    let record = RecordType::parse(&written, Network::Bitcoin).unwrap();
+    assert!(matches!(record, RecordType::Descriptor(_)));
+    assert_eq!(record.to_string(), written);
+}
+
+/// Checks that every record is read back from its stored kind and value as
+/// the record it was saved as.
+#[test]
+fn test_record_kinds() {
+    let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    let records = [
+        "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4".to_string(),
+        "21033B9B137EE87D5A812D6F506EFDD37F0AFFA7FFC310711C06C7F3E097C9447C52AC".to_string(),
+        XPUB.to_string(),
+        format!("{}:1", txid),
+        format!("wpkh({}/0/*)", XPUB),
+        "0279BE667EF9DCBBAC55A06295CE870B07029BFCDB2DCE28D959F2815B16F81798".to_string(),
+        format!("{}#3", txid),
+    ];
+    let kinds = ["address", "script", "xpub", "utxo", "descriptor", "pubkey", "txid"];
+    for (text, kind) in records.iter().zip(kinds) {
+        let record = RecordType::parse(text, Network::Bitcoin).unwrap();
+        assert_eq!(record.kind(), kind);
+        let stored = record.to_string();
+        let restored = RecordType::from_kind(kind, &stored, Network::Bitcoin).unwrap();
+        assert_eq!(restored, record, "{} round-trips", kind);
+        assert_eq!(restored.to_string(), stored);
+    }
+
+    // Stored values are normalised, so the same record entered in another
+    // case is the same row.
+    let address = RecordType::parse(&records[0], Network::Bitcoin).unwrap();
+    assert_eq!(address.to_string(), records[0].to_lowercase());
+    let script = RecordType::parse(&records[1], Network::Bitcoin).unwrap();
+    assert_eq!(script.to_string(), records[1].to_lowercase());
+
+    // The kind decides, not the shape of the value.
+    let hex = RecordType::from_kind("script", txid, Network::Bitcoin).unwrap();
+    assert!(matches!(hex, RecordType::Script(_)));
+    assert!(RecordType::from_kind("utxo", txid, Network::Bitcoin).is_err());
+    assert!(RecordType::from_kind("address", &records[0], Network::Testnet).is_err());
+    assert!(RecordType::from_kind("label", &records[0], Network::Bitcoin).is_err());
+}
+
+/// Checks that a bare public key watches every standard script paying to it
+/// and is found in multisig and script-path spends.
+#[test]
+fn test_pubkey_records() {
+    // The generator point, the key of private key 1.
+    let compressed = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    let record = RecordType::parse(compressed, Network::Bitcoin).unwrap();
+    assert!(matches!(record, RecordType::Pubkey(_)));
+    assert_eq!(record.to_string(), compressed);
+    assert_eq!(record.kind(), "pubkey");
+
+    let addresses: Vec<String> = record
+        .script_pubkeys()
+        .iter()
+        .filter_map(|script| Address::from_script(script, Network::Bitcoin).ok())
+        .map(|addr| addr.to_string())
+        .collect();
+    assert_eq!(
+        addresses,
+        [
+            "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+            "3JvL6Ymt8MVWiCNHC7oWU6nLeHNJKLZGLN",
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            "bc1pmfr3p9j00pfxjh0zmgp99y8zftmd3s5pmedqhyptwy6lm87hf5sspknck9",
+        ],
+        "P2PK has no address"
+    );
+    assert_eq!(record.script_pubkeys().len(), 5);
+
+    let uncompressed = RecordType::parse(
+        "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8",
+        Network::Bitcoin,
+    )
+    .unwrap();
+    assert_eq!(uncompressed.script_pubkeys().len(), 2, "no segwit for uncompressed keys");
+
+    let key = bitcoin::PublicKey::from_str(compressed).unwrap();
+    let other = bitcoin::PublicKey::from_str(
+        "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+    )
+    .unwrap();
+    let multisig = Builder::new()
+        .push_opcode(OP_PUSHNUM_1)
+        .push_key(&other)
+        .push_key(&key)
+        .push_opcode(OP_PUSHNUM_2)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let sig = [0x30; 71].to_vec();
+    let input = |script_sig: ScriptBuf, witness: Vec<Vec<u8>>| TxIn {
+        script_sig,
+        witness: Witness::from_slice(&witness),
+        ..TxIn::default()
+    };
+
+    let p2wsh = input(ScriptBuf::new(), vec![vec![], sig.clone(), multisig.to_bytes()]);
+    let p2sh = input(
+        Builder::new()
+            .push_opcode(OP_PUSHBYTES_0)
+            .push_slice(<&PushBytes>::try_from(sig.as_slice()).unwrap())
+            .push_slice(<&PushBytes>::try_from(multisig.as_bytes()).unwrap())
+            .into_script(),
+        vec![],
+    );
+    let x_only = bitcoin::key::XOnlyPublicKey::from(key);
+    let leaf = Builder::new()
+        .push_x_only_key(&x_only)
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+    let mut control_block = vec![0xc0];
+    control_block.extend(other.inner.x_only_public_key().0.serialize());
+    let script_path = input(ScriptBuf::new(), vec![[0x01; 64].to_vec(), leaf.to_bytes(), control_block]);
+    for spend in [&p2wsh, &p2sh, &script_path] {
+        assert!(pubkey::revealed_keys(spend).contains(&x_only));
+    }
+
+    // Single-key spends are matched by the script they spend instead.
+    let p2wpkh = input(ScriptBuf::new(), vec![sig.clone(), key.to_bytes()]);
+    let p2pkh = input(
+        Builder::new()
+            .push_slice(<&PushBytes>::try_from(sig.as_slice()).unwrap())
+            .push_key(&key)
+            .into_script(),
+        vec![],
+    );
+    assert!(pubkey::revealed_keys(&p2wpkh).is_empty());
+    assert!(pubkey::revealed_keys(&p2pkh).is_empty());
+
+    let mut index = WatchIndex::default();
+    index.insert("alice".into(), record.clone());
+    let matches = index.match_keys(pubkey::revealed_keys(&p2wsh).iter());
+    assert_eq!(matches["alice"], vec![compressed.to_string()]);
+    let matches = index.match_scripts([record.script_pubkeys()[3].as_script()]);
+    assert_eq!(matches["alice"], vec![compressed.to_string()]);
+    assert!(index.remove("alice", &record));
+    assert!(index.match_keys([x_only].iter()).is_empty());
+}
+
+/// Checks that txid records parse with an optional depth, are matched by
+/// txid and that a conflicting transaction is recognised as a replacement.
+#[test]
+fn test_txid_records() {
+    let tx: Transaction = deserialize(&hex::decode(MOCK_TX_HEX).unwrap()).unwrap();
+    let txid = tx.compute_txid();
+
+    let record = RecordType::parse(&txid.to_string(), Network::Bitcoin).unwrap();
+    assert_eq!(record, RecordType::Txid(TxWatch { txid, depth: None }));
+    assert_eq!(record.kind(), "txid");
+    assert!(record.script_pubkeys().is_empty());
+    let deep = RecordType::parse(&format!("{}#6", txid), Network::Bitcoin).unwrap();
+    assert_eq!(deep, RecordType::Txid(TxWatch { txid, depth: Some(6) }));
+    assert_eq!(deep.to_string(), format!("{}#6", txid));
+    assert!(RecordType::parse(&format!("{}#0", txid), Network::Bitcoin).is_err());
+    assert!(RecordType::parse(&format!("{}#six", txid), Network::Bitcoin).is_err());
+
+    let mut index = WatchIndex::default();
+    index.insert("alice".into(), record.clone());
+    index.insert("bob".into(), deep.clone());
+    let matches = index.match_txids([&txid]);
+    assert_eq!(matches["alice"], vec![txid.to_string()]);
+    assert_eq!(matches["bob"], vec![format!("{}#6", txid)]);
+    let mut watched = index.watched_txs();
+    watched.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        watched,
+        vec![
+            ("alice".to_string(), TxWatch { txid, depth: None }),
+            ("bob".to_string(), TxWatch { txid, depth: Some(6) }),
+        ]
+    );
+    assert!(index.remove("alice", &record));
+    assert!(!index.match_txids([&txid]).contains_key("alice"));
+
+    let mut pending = PendingTxs::default();
+    assert!(pending.see(&tx));
+    assert!(!pending.see(&tx), "seen once");
+    assert!(pending.replaced_by(&tx).is_empty(), "not its own replacement");
+
+    let mut replacement = tx.clone();
+    replacement.output[0].value -= Amount::from_sat(1000);
+    assert_eq!(pending.replaced_by(&replacement), vec![txid]);
+    assert!(pending.unconfirmed().is_empty());
+
+    let mut pending = PendingTxs::default();
+    pending.see(&tx);
+    assert!(pending.confirm(&txid, 100));
+    assert!(pending.replaced_by(&replacement).is_empty(), "confirmed");
+    assert!(!pending.see(&tx), "confirmed already");
+    pending.prune(100);
+    assert!(pending.see(&tx), "forgotten once buried");
+
+    // A depth is told once, also when blocks were missed, and again after
+    // the transaction lost its confirmation.
+    let six = TxWatch { txid, depth: Some(6) };
+    assert!(!pending.reach_depth("bob", six, 5));
+    assert!(pending.reach_depth("bob", six, 7));
+    assert!(!pending.reach_depth("bob", six, 8));
+    pending.clear_depth("bob", six);
+    assert!(pending.reach_depth("bob", six, 6));
+    pending.keep_depths(&[]);
+    assert!(pending.reach_depth("bob", six, 6), "forgotten with the watch");
+}
+
+/// Checks which spends reveal the script they spend and which have to be
+/// looked up.
+#[test]
+fn test_spent_script() {
+    let tx: Transaction = deserialize(&hex::decode(MOCK_TX_HEX).unwrap()).unwrap();
+    let pubkey = CompressedPublicKey::from_slice(&tx.input[0].witness[1]).unwrap();
+    let sig = tx.input[0].witness[0].to_vec();
+    let input = |script_sig: ScriptBuf, witness: Vec<Vec<u8>>| TxIn {
+        script_sig,
+        witness: Witness::from_slice(&witness),
+        ..TxIn::default()
+    };
+    let push = |items: &[&[u8]]| {
+        items
+            .iter()
+            .fold(Builder::new(), |builder, item| {
+                builder.push_slice(<&PushBytes>::try_from(*item).unwrap())
+            })
+            .into_script()
+    };
+
+    // P2WPKH, as spent by the mock transaction.
+    assert_eq!(
+        spent_script::reconstruct(&tx.input[0]),
+        Some(ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()))
+    );
+
+    let p2pkh = input(push(&[&sig, &pubkey.to_bytes()]), vec![]);
+    assert_eq!(
+        spent_script::reconstruct(&p2pkh),
+        Some(ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()))
+    );
+
+    let redeem_script = ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash());
+    let nested = input(
+        push(&[redeem_script.as_bytes()]),
+        vec![sig.clone(), pubkey.to_bytes().to_vec()],
+    );
+    assert_eq!(
+        spent_script::reconstruct(&nested),
+        Some(ScriptBuf::new_p2sh(&redeem_script.script_hash()))
+    );
+
+    let witness_script = Builder::new()
+        .push_opcode(OP_PUSHNUM_1)
+        .push_slice(pubkey.to_bytes())
+        .push_opcode(OP_PUSHNUM_1)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let p2wsh = input(
+        ScriptBuf::new(),
+        vec![vec![], sig.clone(), witness_script.to_bytes()],
+    );
+    assert_eq!(
+        spent_script::reconstruct(&p2wsh),
+        Some(ScriptBuf::new_p2wsh(&witness_script.wscript_hash()))
+    );
+
+    // Nothing in these identifies the spent script.
+    let p2pk = input(push(&[&sig]), vec![]);
+    let key_path = input(ScriptBuf::new(), vec![vec![1; 64]]);
+    let mut control_block = vec![0xc0];
+    control_block.extend([2; 32]);
+    let script_path = input(ScriptBuf::new(), vec![vec![1; 64], vec![0x51], control_block]);
+    let bare_p2sh = input(push(&[&[], &sig, witness_script.as_bytes()]), vec![]);
+    for (name, input) in [
+        ("p2pk", p2pk),
+        ("key path", key_path),
+        ("script path", script_path),
+        ("p2sh", bare_p2sh),
+    ] {
+        assert_eq!(spent_script::reconstruct(&input), None, "{}", name);
+    }
+
+    spent_script::record(true);
+    spent_script::record(false);
+    let stats = spent_script::stats();
+    assert!(stats.reconstructed >= 1 && stats.looked_up >= 1);
+}
+
+/// Checks that watched outputs are found by outpoint, follow confirmations
+/// and reorgs, and leave a user's holdings once spent or forgotten. A spend
+/// can be undone until it is buried.
+#[test]
+fn test_utxo_index() {
+    let funding = Txid::from_byte_array([1; 32]);
+    let other = Txid::from_byte_array([2; 32]);
+    let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([7; 20]));
+    let coin = |user: &str, record: &str, sats: u64| Coin {
+        user: user.to_string(),
+        record: record.to_string(),
+        script: script.clone(),
+        value: Amount::from_sat(sats),
+        block_height: None,
+    };
+    let mut index = UtxoIndex::default();
+
+    assert!(index.insert(OutPoint::new(funding, 0), coin("alice", "a", 1_000)));
+    assert!(index.insert(OutPoint::new(funding, 0), coin("bob", "a", 1_000)));
+    assert!(!index.insert(OutPoint::new(funding, 0), coin("bob", "a", 1_000)));
+    assert!(index.insert(OutPoint::new(funding, 3), coin("alice", "b", 2_000)));
+    assert!(index.insert(OutPoint::new(other, 0), coin("alice", "a", 3_000)));
+    assert_eq!(index.len(), 3);
+    assert_eq!(index.get(&OutPoint::new(funding, 1)), None);
+
+    // Only the outputs of the confirmed transaction are touched.
+    assert_eq!(index.confirm(funding, 100), 3);
+    assert_eq!(index.confirm(funding, 100), 0, "already confirmed");
+    assert_eq!(index.get(&OutPoint::new(other, 0)).unwrap()[0].block_height, None);
+    index.confirm(other, 101);
+    index.unconfirm_from(101);
+    let heights: Vec<_> = index.holdings("alice").iter().map(|(_, c)| c.block_height).collect();
+    assert_eq!(heights, vec![Some(100), Some(100), None]);
+
+    let spender = Txid::from_byte_array([3; 32]);
+    let replacement = Txid::from_byte_array([4; 32]);
+    let spent = index.spend(&OutPoint::new(funding, 0), spender);
+    assert_eq!(spent.len(), 2, "held by both users");
+    assert!(index.spend(&OutPoint::new(funding, 0), spender).is_empty(), "seen again");
+    assert_eq!(index.holdings("bob"), vec![]);
+    assert_eq!(index.spend(&OutPoint::new(funding, 0), replacement).len(), 2);
+    assert_eq!(index.pending_spenders().into_iter().collect::<Vec<_>>(), vec![replacement]);
+
+    // An evicted spend gives the output back.
+    assert!(index.unspend(spender).is_empty(), "replaced already");
+    assert_eq!(index.unspend(replacement), vec![OutPoint::new(funding, 0)]);
+    assert_eq!(index.holdings("bob").len(), 1);
+
+    // A confirmed spend survives reorgs above the spend and is dropped once
+    // buried.
+    index.spend(&OutPoint::new(funding, 0), spender);
+    assert!(index.confirm_spend(&OutPoint::new(funding, 0), spender, 102));
+    assert!(index.spend(&OutPoint::new(funding, 0), replacement).is_empty());
+    index.unconfirm_from(103);
+    index.prune(102);
+    assert_eq!(index.get(&OutPoint::new(funding, 0)), None);
+    assert_eq!(index.holdings("bob"), vec![]);
+
+    index.forget("alice", "a");
+    let held: Vec<_> = index.holdings("alice").into_iter().map(|(o, _)| o).collect();
+    assert_eq!(held, vec![OutPoint::new(funding, 3)]);
+}
+
+/// Checks that balances add up per record and confirmation state, and that
+/// a transaction's own outputs or spends can be left out to get the balance
+/// on either side of it.
+#[test]
+fn test_balances() {
+    let funding = Txid::from_byte_array([1; 32]);
+    let spending = Txid::from_byte_array([2; 32]);
+    let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([7; 20]));
+    let coin = |user: &str, record: &str, sats: u64, block_height| Coin {
+        user: user.to_string(),
+        record: record.to_string(),
+        script: script.clone(),
+        value: Amount::from_sat(sats),
+        block_height,
+    };
+    let mut index = UtxoIndex::default();
+    index.insert(OutPoint::new(funding, 0), coin("alice", "a", 50_000_000, Some(100)));
+    index.insert(OutPoint::new(funding, 1), coin("alice", "b", 1_000, Some(100)));
+    index.insert(OutPoint::new(funding, 1), coin("bob", "b", 1_000, Some(100)));
+    // Alice's spend of funding:0 pays change back to "a".
+    index.insert(OutPoint::new(spending, 1), coin("alice", "a", 12_340_000, None));
+
+    let balances = index.balances("alice");
+    assert_eq!(balances.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+    assert_eq!(
+        balances["a"],
+        Balance {
+            confirmed: Amount::from_sat(50_000_000),
+            unconfirmed: Amount::from_sat(12_340_000),
+        }
+    );
+    assert_eq!(index.balances("bob")["b"].total(), Amount::from_sat(1_000));
+    assert!(index.balances("carol").is_empty());
+
+    index.spend(&OutPoint::new(funding, 0), spending);
+    assert_eq!(index.balances("alice")["a"].confirmed, Amount::ZERO);
+
+    let records = ["a".to_string()];
+    let before = index.balance("alice", &records, |outpoint, spender| {
+        outpoint.txid != spending && spender.is_none_or(|spender| spender == spending)
+    });
+    let after = index.balance("alice", &records, |_, spender| spender.is_none());
+    assert_eq!(before.to_string(), "0.5 BTC");
+    assert_eq!(after.to_string(), "0.1234 BTC (0.1234 BTC unconfirmed)");
+    assert_eq!(index.balance("alice", &["c".to_string()], |_, _| true), Balance::default());
+}
+
+/// Checks the pipeline's overflow policies, that a block waits for the
+/// transactions received before it, that a panicking handler only fails
+/// its own transaction, and that handlers take their turn in arrival order.
+#[tokio::test]
+async fn test_pipeline() {
+    let log = Arc::new(Mutex::new(Vec::<String>::new()));
+    let logging_handler = |delay: Duration| -> Handler {
+        let log = log.clone();
+        Arc::new(move |raw| {
+            let log = log.clone();
+            Box::pin(async move {
+                let label = String::from_utf8(raw).unwrap();
+                if label == "panic" {
+                    panic!("handler failure");
+                }
+                tokio::time::sleep(delay).await;
+                log.lock().unwrap().push(label);
+            })
+        })
+    };
+    let settled = |stats: &PipelineStats, expected: u64| {
+        let s = stats.snapshot();
+        s.processed + s.dropped + s.failed == expected
+    };
+    async fn wait_for(check: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("pipeline did not settle");
+    }
+    let submit = |mut intake: Intake, labels: Vec<&'static str>| {
+        task::spawn_blocking(move || {
+            for label in labels {
+                if label == "block" {
+                    intake.submit_block(label.as_bytes().to_vec());
+                } else {
+                    intake.submit_tx(label.as_bytes().to_vec());
+                }
+            }
+            intake
+        })
+    };
+    let txs = vec!["tx0", "tx1", "tx2", "tx3", "tx4"];
+
+    for overflow in [OverflowPolicy::Drop, OverflowPolicy::Queue] {
+        let stats = Arc::new(PipelineStats::default());
+        let config = PipelineConfig {
+            workers: 1,
+            queue_size: 1,
+            overflow,
+        };
+        let (intake, _) = start_with_stats(
+            config,
+            logging_handler(Duration::from_millis(50)),
+            logging_handler(Duration::ZERO),
+            stats.clone(),
+        );
+        let _intake = submit(intake, txs.clone()).await.unwrap();
+        wait_for(|| settled(&stats, 5)).await;
+
+        let s = stats.snapshot();
+        assert_eq!(s.received, 5);
+        match overflow {
+            // At most one transaction in the worker and one in the queue.
+            OverflowPolicy::Drop => {
+                assert!(s.dropped >= 3, "{:?}", s);
+                assert_eq!(s.processed, 5 - s.dropped);
+                assert_eq!(s.delayed, 0);
+            }
+            OverflowPolicy::Queue => {
+                assert!(s.delayed >= 3, "{:?}", s);
+                assert_eq!(s.processed, 5);
+                assert_eq!(s.dropped, 0);
+            }
+        }
+    }
+
+    log.lock().unwrap().clear();
+    let stats = Arc::new(PipelineStats::default());
+    let (intake, _) = start_with_stats(
+        PipelineConfig {
+            workers: 2,
+            ..PipelineConfig::default()
+        },
+        logging_handler(Duration::from_millis(50)),
+        logging_handler(Duration::ZERO),
+        stats.clone(),
+    );
+    let _intake = submit(intake, vec!["tx0", "panic", "tx1", "block", "tx2"])
+        .await
+        .unwrap();
+    wait_for(|| settled(&stats, 4) && stats.snapshot().blocks == 1).await;
+
+    let order = log.lock().unwrap().clone();
+    let position = |label: &str| order.iter().position(|l| l == label).unwrap();
+    assert!(position("block") > position("tx0"), "{:?}", order);
+    assert!(position("block") > position("tx1"), "{:?}", order);
+    assert!(order.contains(&"tx2".to_string()));
+    let s = stats.snapshot();
+    assert_eq!((s.processed, s.failed), (3, 1));
+
+    // Later transactions finish their work first but wait for their turn.
+    log.lock().unwrap().clear();
+    let turns = log.clone();
+    let in_order: Handler = Arc::new(move |raw| {
+        let log = turns.clone();
+        Box::pin(async move {
+            let label = String::from_utf8(raw).unwrap();
+            let n: u64 = label[2..].parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(80 - 20 * n)).await;
+            in_turn(|| log.lock().unwrap().push(label)).await;
+        })
+    });
+    let turn_stats = Arc::new(PipelineStats::default());
+    let (intake, _) = start_with_stats(
+        PipelineConfig {
+            workers: 4,
+            ..PipelineConfig::default()
+        },
+        in_order,
+        logging_handler(Duration::ZERO),
+        turn_stats.clone(),
+    );
+    let _intake = submit(intake, vec!["tx0", "tx1", "tx2", "tx3"]).await.unwrap();
+    wait_for(|| settled(&turn_stats, 4)).await;
+    assert_eq!(*log.lock().unwrap(), vec!["tx0", "tx1", "tx2", "tx3"]);
+}
+
+/// Checks incremental updates and script matching of the watch index, also
+/// with a large number of records.
+#[test]
+fn test_watch_index() {
+    let address = |s: &str| RecordType::parse(s, Network::Bitcoin).unwrap();
+    let script = |record: &RecordType| record.script_pubkeys().remove(0);
+    let a = address("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq");
+    let b = address("34xp4vRoCGJym3xR7yCVPFHoCNxv4Twseo");
+    let unrelated = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([9; 20]));
+    let mut index = WatchIndex::default();
+
+    assert!(index.apply(WatchUpdate::Add { user: "alice".into(), record: a.clone() }));
+    assert!(index.apply(WatchUpdate::Add { user: "alice".into(), record: b.clone() }));
+    assert!(index.apply(WatchUpdate::Add { user: "bob".into(), record: a.clone() }));
+    assert!(!index.apply(WatchUpdate::Add { user: "bob".into(), record: a.clone() }));
+    assert_eq!((index.users(), index.records()), (2, 3));
+
+    let tx_scripts = [script(&a), unrelated.clone(), script(&a)];
+    let matches = index.match_scripts(tx_scripts.iter().map(|s| s.as_script()));
+    assert_eq!(matches.len(), 2);
+    assert_eq!(matches["alice"], vec![a.to_string()], "matched once per record");
+    assert_eq!(matches["bob"], vec![a.to_string()]);
+
+    assert!(index.apply(WatchUpdate::Remove { user: "bob".into(), record: a.clone() }));
+    assert!(!index.apply(WatchUpdate::Remove { user: "bob".into(), record: a.clone() }));
+    assert_eq!((index.users(), index.records()), (1, 2));
+    let matches = index.match_scripts(tx_scripts.iter().map(|s| s.as_script()));
+    assert_eq!(matches.keys().collect::<Vec<_>>(), vec!["alice"]);
+
+    // Hundreds of thousands of records cost one lookup per script.
+    for n in 0..200_000u32 {
+        let mut hash = [0u8; 20];
+        hash[..4].copy_from_slice(&n.to_le_bytes());
+        let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array(hash));
+        let addr = Address::from_script(&script, Network::Bitcoin).unwrap();
+        index.insert(format!("user{}", n % 1000), RecordType::Address(addr));
+    }
+    assert_eq!(index.records(), 200_002);
+    let mut hash = [0u8; 20];
+    hash[..4].copy_from_slice(&123_456u32.to_le_bytes());
+    let watched = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array(hash));
+    let started = Instant::now();
+    let matches = index.match_scripts([watched.as_script(), unrelated.as_script()]);
+    assert!(started.elapsed() < Duration::from_millis(10), "{:?}", started.elapsed());
+    assert_eq!(matches.keys().collect::<Vec<_>>(), vec!["user456"]);
+}
+
+/// Checks that CLTV and CSV locks are read from spending scripts and counted
+/// down in blocks.
+#[test]
+fn test_timelocks() {
+    let key = "02e493dbf1c10d80f3581e4904930b1404cc6c13900ee0758474fa94abe8c4cd13";
+    let locked = |value: i64, op| {
+        Builder::new()
+            .push_int(value)
+            .push_opcode(op)
+            .push_opcode(OP_DROP)
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    };
+    let height = Timelock::Absolute(absolute::LockTime::from_height(800_000).unwrap());
+    let blocks = Timelock::Relative(relative::LockTime::from_height(144));
+    assert_eq!(script_timelocks(&locked(800_000, OP_CLTV)), vec![height]);
+    assert_eq!(script_timelocks(&locked(144, OP_CSV)), vec![blocks]);
+    assert!(script_timelocks(&locked(144, OP_CHECKSIG)).is_empty());
+
+    // Times past 2038 take a fifth byte to stay positive.
+    let late = locked(3_000_000_000, OP_CLTV);
+    assert_eq!(late.as_bytes()[0], 5);
+    let time = Timelock::Absolute(absolute::LockTime::from_time(3_000_000_000).unwrap());
+    assert_eq!(script_timelocks(&late), vec![time]);
+
+    // A height lock is final in the block after it.
+    assert_eq!(height.blocks_left(None, 799_990, 0), Some(10));
+    assert_eq!(height.blocks_left(None, 800_000, 0), Some(0));
+    assert_eq!(time.blocks_left(None, 0, 3_000_000_000 - 6000), Some(11));
+    assert_eq!(time.blocks_left(None, 0, 3_000_000_001), Some(0));
+    // Relative locks count from the block the output confirmed in.
+    assert_eq!(blocks.blocks_left(None, 200, 0), None);
+    assert_eq!(blocks.blocks_left(Some(100), 200, 0), Some(43));
+    assert_eq!(blocks.blocks_left(Some(100), 243, 0), Some(0));
+    let seconds = Timelock::Relative(relative::LockTime::from_512_second_intervals(10));
+    assert_eq!(seconds.blocks_left(Some(100), 100, 0), Some(8));
+    assert_eq!(blocks.to_string(), "locked for 144 blocks after confirmation");
+
+    // Descriptors are spent through their witness or leaf scripts.
+    let parse = |s: &str| WatchedDescriptor::parse(s, Network::Bitcoin).unwrap();
+    let wsh = parse(&format!("wsh(and_v(v:pk({}),older(144)))", key));
+    let locks: Vec<Timelock> = wsh
+        .spending_scripts(0, 0)
+        .iter()
+        .flat_map(|script| script_timelocks(script))
+        .collect();
+    assert_eq!(locks, vec![blocks]);
+    let tr = parse(&format!(
+        "tr({},{{and_v(v:pk({}),after(800000)),pk({})}})",
+        key.replacen("02", "03", 1),
+        key,
+        key
+    ));
+    let locks: Vec<Timelock> = tr
+        .spending_scripts(0, 0)
+        .iter()
+        .flat_map(|script| script_timelocks(script))
+        .collect();
+    assert_eq!(locks, vec![height]);
+}
+
+/// Checks that BIP-329 exports are read with normalised references, written
+/// back unchanged, and used to name records in notifications.
+#[test]
+fn test_labels() {
+    let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    let address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+    let export = format!(
+        r#"{{"type":"tx","ref":"{txid}","label":"Rent","origin":"wpkh([d34db33f/84'/0'/0'])"}}
+{{"type":"addr","ref":"{upper}","label":"Cold storage #2"}}
+
+{{"type":"output","ref":"{txid}:1","label":"Change","spendable":false}}
+{{"type":"addr","ref":"{address}","label":"{long}"}}
+{{"type":"wallet","ref":"whatever","label":"Unknown types are skipped"}}
+{{"type":"addr","ref":"tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx","label":"Testnet"}}
+not json
+{{"type":"xpub","ref":"{XPUB}","label":"Account 0"}}"#,
+        upper = address.to_uppercase(),
+        long = "x".repeat(300),
+    );
+    let import = labels::parse_export("alice", &export, Network::Bitcoin);
+    assert_eq!(import.skipped, 1);
+    assert_eq!(import.invalid, vec![7, 8]);
+    assert_eq!(import.labels.len(), 4, "the address is labelled twice");
+    assert!(import.labels.iter().all(|label| label.nostr_pubkey == "alice"));
+    let addr = import.labels.iter().find(|label| label.label_type == "addr").unwrap();
+    assert_eq!(addr.reference, address, "bech32 is lowercased");
+    assert_eq!(addr.label.chars().count(), 255, "the last label wins, truncated");
+
+    let written = labels::write_export(&import.labels);
+    assert_eq!(
+        written.lines().next().unwrap(),
+        format!(r#"{{"type":"tx","ref":"{txid}","label":"Rent","origin":"wpkh([d34db33f/84'/0'/0'])"}}"#)
+    );
+    assert!(written.contains(r#""label":"Change","spendable":false}"#));
+    let reread = labels::parse_export("alice", &written, Network::Bitcoin);
+    assert_eq!(reread.labels, import.labels);
+
+    let record = |s: &str| RecordType::parse(s, Network::Bitcoin).unwrap();
+    assert_eq!(
+        labels::reference(&record(&format!("{}#3", txid)), Network::Bitcoin),
+        ("tx", txid.to_string())
+    );
+    assert_eq!(
+        labels::reference(&record(&format!("{}#wpkh", XPUB)), Network::Bitcoin),
+        ("xpub", XPUB.to_string())
+    );
+    let p2wpkh = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    assert_eq!(
+        labels::reference(&record(p2wpkh), Network::Bitcoin),
+        ("addr", address.to_string()),
+        "scripts with an address are labelled as one"
+    );
+
+    let mut index = LabelIndex::default();
+    for label in import.labels.iter() {
+        index.set(label);
+    }
+    let mut cold = import.labels[1].clone();
+    cold.label = "Cold storage #2".into();
+    index.set(&cold);
+    assert_eq!(
+        index.describe_records("alice", &[address.to_string(), format!("{}#wpkh", XPUB)], Network::Bitcoin),
+        format!("Cold storage #2 ({}), Account 0 ({}#wpkh)", address, XPUB)
+    );
+    assert_eq!(index.describe_records("bob", &[address.to_string()], Network::Bitcoin), address);
+    assert_eq!(index.name("alice", "tx", txid, txid), format!("Rent ({})", txid));
+    cold.label.clear();
+    index.set(&cold);
+    assert_eq!(index.get("alice", "addr", address), None, "an empty label clears it");
+}
+
+/// Checks that each supported wallet export turns into the records of the
+/// wallet it describes.
+#[test]
+fn test_wallet_import() {
+    // The BIP84 and BIP86 test vector account keys.
+    let bip84 = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+    let bip86 = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+    let first_bip84 = "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu";
+    let parse = |s: &str| wallet_import::parse(s, Network::Bitcoin);
+    let written = |import: &wallet_import::WalletImport| -> Vec<String> {
+        import.records.iter().map(|record| record.to_string()).collect()
+    };
+    let first_address = |record: &RecordType| -> String {
+        let script = match record {
+            RecordType::Xpub(key) => key.scripts(0, 0..1).remove(0),
+            RecordType::Descriptor(descriptor) => descriptor.scripts(0, 0..1).remove(0),
+            other => panic!("unexpected record {}", other),
+        };
+        Address::from_script(&script, Network::Bitcoin).unwrap().to_string()
+    };
+
+    let core = parse(&json!({
+        "wallet_name": "hot",
+        "descriptors": [
+            { "desc": format!("wpkh([73c5da0a/84h/0h/0h]{}/0/*)", bip84), "internal": false },
+            { "desc": format!("wpkh([73c5da0a/84h/0h/0h]{}/1/*)", bip84), "internal": true },
+        ],
+    }).to_string())
+    .unwrap();
+    assert_eq!((core.format, core.name.as_deref()), ("Bitcoin Core", Some("hot")));
+    assert_eq!(core.records.len(), 2);
+    assert_eq!(first_address(&core.records[0]), first_bip84);
+    let rpc = parse(&json!({ "result": { "descriptors": [{ "desc": format!("tr({}/0/*)", bip86) }] } }).to_string());
+    assert_eq!(rpc.unwrap().records.len(), 1, "RPC responses are unwrapped");
+    let private = json!({ "descriptors": [{ "desc": "wpkh(xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi/0/*)" }] });
+    assert!(parse(&private.to_string()).is_err(), "private keys are refused");
+
+    let zpub = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    let electrum = parse(&json!({ "wallet_type": "standard", "keystore": { "type": "bip32", "xpub": zpub } }).to_string()).unwrap();
+    assert_eq!(written(&electrum), vec![zpub]);
+    assert_eq!(first_address(&electrum.records[0]), first_bip84);
+    let xprv = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+    let hot = json!({ "wallet_type": "standard", "keystore": { "type": "bip32", "xpub": zpub, "xprv": xprv } });
+    assert!(parse(&hot.to_string()).is_err(), "keystores with an xprv are refused");
+    let seeded = json!({ "wallet_type": "standard", "keystore": { "type": "bip32", "xpub": zpub, "seed": "abandon abandon about" } });
+    assert!(parse(&seeded.to_string()).is_err(), "keystores with a seed are refused");
+
+    // Electrum writes multisig cosigners with SLIP-132 Zpub prefixes.
+    let as_zpub = |xpub: &str| {
+        let mut data = bitcoin::base58::decode_check(xpub).unwrap();
+        data[..4].copy_from_slice(&[0x02, 0xaa, 0x7e, 0xd3]);
+        bitcoin::base58::encode_check(&data)
+    };
+    let multisig = parse(&json!({
+        "wallet_type": "2of2",
+        "x1/": { "type": "bip32", "xpub": as_zpub(bip86) },
+        "x2/": { "type": "bip32", "xpub": as_zpub(bip84) },
+    }).to_string())
+    .unwrap();
+    let expected = WatchedDescriptor::parse(&format!("wsh(sortedmulti(2,{}/<0;1>/*,{}/<0;1>/*))", bip86, bip84), Network::Bitcoin).unwrap();
+    assert_eq!(written(&multisig), vec![expected.to_string()]);
+    let cosigner = json!({
+        "wallet_type": "2of2",
+        "x1/": { "type": "bip32", "xpub": as_zpub(bip86), "xprv": xprv },
+        "x2/": { "type": "bip32", "xpub": as_zpub(bip84) },
+    });
+    assert!(parse(&cosigner.to_string()).is_err(), "cosigner keystores with an xprv are refused");
+
+    let imported = parse(&json!({ "wallet_type": "imported", "addresses": { first_bip84: {} } }).to_string()).unwrap();
+    assert_eq!(written(&imported), vec![first_bip84]);
+    let keypairs = json!({
+        "wallet_type": "imported",
+        "keystore": { "type": "imported", "keypairs": { "02aa": "L1..." } },
+        "addresses": { first_bip84: {} },
+    });
+    assert!(parse(&keypairs.to_string()).is_err(), "imported private keys are refused");
+
+    let specter = parse(&json!({
+        "label": "Vault",
+        "blockheight": 800_000,
+        "descriptor": format!("wpkh({}/<0;1>/*)", bip84),
+    }).to_string())
+    .unwrap();
+    assert_eq!((specter.name.as_deref(), specter.birth_height), (Some("Vault"), Some(800_000)));
+    assert_eq!(first_address(&specter.records[0]), first_bip84);
+
+    let coldcard = parse(&json!({
+        "chain": "BTC",
+        "xfp": "73C5DA0A",
+        "bip84": { "xpub": bip84, "deriv": "m/84'/0'/0'", "first": first_bip84 },
+        "bip86": { "xpub": bip86, "desc": format!("tr([73c5da0a/86h/0h/0h]{}/<0;1>/*)", bip86) },
+        "bip48_2": { "xpub": bip84 },
+    }).to_string())
+    .unwrap();
+    assert_eq!(coldcard.records.len(), 2, "multisig cosigner keys are not wallets");
+    assert_eq!(coldcard.records[0].to_string(), format!("{}#wpkh", bip84));
+    assert_eq!(first_address(&coldcard.records[0]), first_bip84);
+    assert_eq!(
+        first_address(&coldcard.records[1]),
+        "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+    );
+
+    let template = format!("wsh(sortedmulti(2,[73c5da0a/48h/0h/0h/2h]{}/**,{}/**))", bip84, bip86);
+    let first = first_address(&RecordType::Descriptor(Box::new(
+        WatchedDescriptor::parse(&template.replace("/**", "/<0;1>/*"), Network::Bitcoin).unwrap(),
+    )));
+    let bsms = parse(&format!("BSMS 1.0\n{}\n/0/*,/1/*\n{}\n", template, first)).unwrap();
+    assert_eq!((bsms.format, bsms.records.len()), ("BSMS", 1));
+    assert!(parse(&format!("BSMS 1.0\n{}\n/0/*,/1/*\n{}\n", template, first_bip84)).is_err());
+
+    let sparrow = format!(
+        "# Receive and change descriptor (BIP389):\nwpkh({}/<0;1>/*)\n\n# Taproot\ntr({}/0/*)\n",
+        bip84, bip86
+    );
+    assert_eq!(parse(&sparrow).unwrap().records.len(), 2);
+
+    assert!(parse("hello").is_err());
+    assert!(parse("{}").is_err());
+    assert!(parse(&json!({ "descriptors": [] }).to_string()).is_err(), "nothing to watch");
+}
@@ -0,0 +1,297 @@
+use bitcoin::opcodes::all::{OP_CLTV, OP_CSV};
+use bitcoin::script::Instruction;
+use bitcoin::{absolute, relative, BlockHash, OutPoint, Script, ScriptBuf};
+use dotenvy::dotenv;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::models::RecordType;
+use crate::utxo_index::Coin;
+use crate::{db_operations, derivation, labels, nostr_notify, rpc, utxo_index};
+
+/// Seconds per block used to turn time-based locks into a block count.
+const BLOCK_INTERVAL: u32 = 600;
+
+/// Blocks before maturity at which reminders are sent, 0 being maturity.
+static REMINDER_LEADS: Lazy<Vec<u32>> = Lazy::new(|| {
+    dotenv().ok();
+    let mut leads: Vec<u32> = env::var("TIMELOCK_REMINDER_BLOCKS")
+        .unwrap_or_else(|_| "144,6,0".to_string())
+        .split(',')
+        .filter_map(|lead| lead.trim().parse().ok())
+        .collect();
+    leads.sort_unstable();
+    leads.dedup();
+    leads
+});
+
+/// Timelocks found in each script, as deriving descriptors is not free.
+static SCRIPT_LOCKS: Lazy<Mutex<HashMap<ScriptBuf, Vec<Timelock>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A lock on an output, for one of its owners.
+type LockKey = (OutPoint, String, Timelock);
+
+/// Smallest lead already reminded of, per lock. Rebuilt from the checkpoint
+/// at startup.
+static REMINDED: Lazy<Mutex<HashMap<LockKey, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A lock a spending script puts on an output: an `OP_CHECKLOCKTIMEVERIFY`
+/// the spending transaction's nLockTime has to reach, or an
+/// `OP_CHECKSEQUENCEVERIFY` its input's nSequence has to reach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Timelock {
+    Absolute(absolute::LockTime),
+    Relative(relative::LockTime),
+}
+
+impl Timelock {
+    /// Blocks left before a transaction spending an output confirmed at
+    /// `confirmed_at` can be mined in the block after `tip`, 0 once it can.
+    /// Time-based locks are estimated at ten minutes a block from the median
+    /// time past `mtp` of `tip`. `None` for a relative lock on an
+    /// unconfirmed output, which has not started counting.
+    pub fn blocks_left(&self, confirmed_at: Option<u32>, tip: u32, mtp: u32) -> Option<u32> {
+        match self {
+            Timelock::Absolute(absolute::LockTime::Blocks(height)) => {
+                Some(height.to_consensus_u32().saturating_sub(tip))
+            }
+            // Final once the median time past is beyond the lock.
+            Timelock::Absolute(absolute::LockTime::Seconds(time)) => {
+                let time = time.to_consensus_u32();
+                Some(if mtp > time {
+                    0
+                } else {
+                    (time - mtp) / BLOCK_INTERVAL + 1
+                })
+            }
+            Timelock::Relative(relative::LockTime::Blocks(blocks)) => {
+                let matures_at = confirmed_at? + u32::from(blocks.value());
+                Some(matures_at.saturating_sub(tip + 1))
+            }
+            Timelock::Relative(relative::LockTime::Time(time)) => {
+                let blocks = (u32::from(time.value()) * 512).div_ceil(BLOCK_INTERVAL);
+                Some((confirmed_at? + blocks).saturating_sub(tip + 1))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Timelock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timelock::Absolute(absolute::LockTime::Blocks(height)) => {
+                write!(f, "locked until block {}", height)
+            }
+            Timelock::Absolute(absolute::LockTime::Seconds(time)) => {
+                write!(f, "locked until unix time {}", time)
+            }
+            Timelock::Relative(relative::LockTime::Blocks(blocks)) => {
+                write!(f, "locked for {} blocks after confirmation", blocks.value())
+            }
+            Timelock::Relative(relative::LockTime::Time(time)) => write!(
+                f,
+                "locked for {} seconds after confirmation",
+                u32::from(time.value()) * 512
+            ),
+        }
+    }
+}
+
+/// The timelocks `script` checks: a number pushed right before
+/// `OP_CHECKLOCKTIMEVERIFY` or `OP_CHECKSEQUENCEVERIFY`.
+pub fn script_timelocks(script: &Script) -> Vec<Timelock> {
+    let mut locks = Vec::new();
+    let mut previous: Option<Instruction> = None;
+    for instruction in script.instructions() {
+        let Ok(instruction) = instruction else {
+            break;
+        };
+        if let (Some(value), Instruction::Op(op)) =
+            (previous.as_ref().and_then(lock_value), &instruction)
+        {
+            if *op == OP_CLTV {
+                locks.push(Timelock::Absolute(absolute::LockTime::from_consensus(value)));
+            } else if *op == OP_CSV {
+                if let Ok(lock) = relative::LockTime::from_consensus(value) {
+                    locks.push(Timelock::Relative(lock));
+                }
+            }
+        }
+        previous = Some(instruction);
+    }
+    locks
+}
+
+/// A lock value pushed as a script number. Unlike other numbers, it may
+/// take five bytes.
+fn lock_value(instruction: &Instruction) -> Option<u32> {
+    match instruction {
+        Instruction::PushBytes(bytes) if bytes.len() == 5 => {
+            let bytes = bytes.as_bytes();
+            (bytes[4] == 0).then(|| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+        _ => instruction.script_num().and_then(|n| u32::try_from(n).ok()),
+    }
+}
+
+/// Timelocks on the scripts able to spend an output paying to `script`.
+/// Outputs of a descriptor are spent through its witness, redeem or leaf
+/// scripts; a bare script carries its own. Each spending path's lock is
+/// listed on its own.
+pub fn output_timelocks(script: &Script) -> Vec<Timelock> {
+    let mut cache = SCRIPT_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(locks) = cache.get(script) {
+        return locks.clone();
+    }
+    let spending_scripts = match derivation::origin(script) {
+        Some((RecordType::Descriptor(descriptor), chain, index)) => {
+            descriptor.spending_scripts(chain, index)
+        }
+        _ => vec![script.to_owned()],
+    };
+    let locks: Vec<Timelock> = spending_scripts
+        .iter()
+        .flat_map(|spending| script_timelocks(spending))
+        .collect();
+    cache.insert(script.to_owned(), locks.clone());
+    locks
+}
+
+/// Every timelocked output of `coins` with the blocks left on each lock.
+pub fn maturities(
+    coins: Vec<(OutPoint, Coin)>,
+    tip: u32,
+    mtp: u32,
+) -> Vec<(OutPoint, Coin, Timelock, Option<u32>)> {
+    coins
+        .into_iter()
+        .flat_map(|(outpoint, coin)| {
+            output_timelocks(&coin.script).into_iter().map(move |lock| {
+                let left = lock.blocks_left(coin.block_height, tip, mtp);
+                (outpoint, coin.clone(), lock, left)
+            })
+        })
+        .collect()
+}
+
+/// The smallest lead `left` blocks are within, if any.
+fn due_lead(left: u32) -> Option<u32> {
+    REMINDER_LEADS.iter().copied().find(|lead| left <= *lead)
+}
+
+/// Forgets the reminders and cached scripts of outputs no longer in `coins`,
+/// once they are spent or their record is no longer watched.
+fn prune(coins: &[(OutPoint, Coin)]) {
+    let owned: HashSet<(OutPoint, &str)> = coins
+        .iter()
+        .map(|(outpoint, coin)| (*outpoint, coin.user.as_str()))
+        .collect();
+    REMINDED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|(outpoint, user, _), _| owned.contains(&(*outpoint, user.as_str())));
+    let scripts: HashSet<&Script> = coins.iter().map(|(_, coin)| coin.script.as_script()).collect();
+    SCRIPT_LOCKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|script, _| scripts.contains(script.as_script()));
+}
+
+/// Marks the reminders due by the last processed block as sent, so they are
+/// not repeated after a restart. Runs once at startup, after the UTXO index
+/// and derivation windows are loaded.
+pub fn load() {
+    let checkpoint = match db_operations::get_checkpoint() {
+        Ok(Some(checkpoint)) => checkpoint,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch checkpoint from DB: {}", e);
+            return;
+        }
+    };
+    let Ok(block_hash) = BlockHash::from_str(&checkpoint.block_hash) else {
+        eprintln!("❌ Invalid checkpoint hash {}", checkpoint.block_hash);
+        return;
+    };
+    let mtp = match rpc::get_rpc_client().and_then(|client| client.get_median_time_past(&block_hash)) {
+        Ok(mtp) => mtp as u32,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch median time of {}: {}", block_hash, e);
+            return;
+        }
+    };
+
+    let mut reminded = REMINDED.lock().unwrap_or_else(|e| e.into_inner());
+    let coins = utxo_index::all_coins();
+    for (outpoint, coin, lock, left) in maturities(coins, checkpoint.block_height as u32, mtp) {
+        if let Some(lead) = left.and_then(due_lead) {
+            reminded.insert((outpoint, coin.user, lock), lead);
+        }
+    }
+    println!("✅ {} timelock reminder(s) already sent", reminded.len());
+}
+
+/// Reminds owners of timelocked outputs as their locks come within a lead
+/// time of maturing. A lock already mature when first seen is not
+/// reminded of.
+pub async fn process_block(block_hash: BlockHash, height: u64) {
+    let coins = utxo_index::all_coins();
+    prune(&coins);
+    if coins.is_empty() {
+        return;
+    }
+    let mtp = match rpc::with_client(move |client| client.get_median_time_past(&block_hash)).await {
+        Ok(mtp) => mtp as u32,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch median time of {}: {}", block_hash, e);
+            return;
+        }
+    };
+
+    let mut reminded = REMINDED.lock().unwrap_or_else(|e| e.into_inner());
+    for (outpoint, coin, lock, left) in maturities(coins, height as u32, mtp) {
+        let Some(left) = left else {
+            continue;
+        };
+        let Some(lead) = due_lead(left) else {
+            continue;
+        };
+        let key = (outpoint, coin.user.clone(), lock);
+        let first_seen = !reminded.contains_key(&key);
+        let last = reminded.entry(key).or_insert(u32::MAX);
+        if lead >= *last {
+            continue;
+        }
+        *last = lead;
+        if first_seen && left == 0 {
+            continue;
+        }
+
+        let outpoint = labels::describe_output(&coin.user, &outpoint);
+        let record = labels::describe_records(&coin.user, std::slice::from_ref(&coin.record));
+        let message = if left == 0 {
+            format!(
+                "Your watched UTXO {} ({}, {} BTC) is now spendable: it was {}",
+                outpoint,
+                record,
+                coin.value.to_btc(),
+                lock
+            )
+        } else {
+            format!(
+                "Your watched UTXO {} ({}, {} BTC) will be spendable in ~{} blocks: it is {}",
+                outpoint,
+                record,
+                coin.value.to_btc(),
+                left,
+                lock
+            )
+        };
+        nostr_notify::send_message(message, coin.user);
+    }
+}
@@ -0,0 +1,411 @@
+use bitcoin::{Block, BlockHash, OutPoint, Transaction, Txid};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::chain_tracker::REORG_WINDOW;
+use crate::models::RecordType;
+use crate::{chain_source, confirmations, db_operations, labels, nostr_notify, rpc, watch_index};
+
+static PENDING: Lazy<Mutex<PendingTxs>> = Lazy::new(|| Mutex::new(PendingTxs::default()));
+
+/// A transaction a user waits for, written as its txid, optionally followed
+/// by `#<depth>` to also be told once it is buried that many blocks deep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TxWatch {
+    pub txid: Txid,
+    pub depth: Option<u32>,
+}
+
+impl TxWatch {
+    pub fn parse(s: &str) -> Result<Self, &'static str> {
+        let (txid, depth) = match s.split_once('#') {
+            Some((txid, depth)) => (txid, Some(depth)),
+            None => (s, None),
+        };
+        let txid = Txid::from_str(txid).map_err(|_| "Invalid txid")?;
+        let depth = match depth {
+            Some(depth) => Some(
+                depth
+                    .parse()
+                    .ok()
+                    .filter(|depth| *depth > 0)
+                    .ok_or("Invalid confirmation depth")?,
+            ),
+            None => None,
+        };
+        Ok(TxWatch { txid, depth })
+    }
+}
+
+impl fmt::Display for TxWatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.depth {
+            Some(depth) => write!(f, "{}#{}", self.txid, depth),
+            None => write!(f, "{}", self.txid),
+        }
+    }
+}
+
+/// Watched transactions seen since startup. Unconfirmed ones are kept with
+/// the outpoints they spend, so a conflicting transaction is recognised as
+/// their replacement. Confirmed ones are kept until buried past the reorg
+/// window.
+#[derive(Debug, Default)]
+pub struct PendingTxs {
+    /// Confirmed txid -> height it confirmed at.
+    confirmed: HashMap<Txid, u64>,
+    /// Unconfirmed txid -> outpoints it spends. Empty when it was seen
+    /// before a restart.
+    unconfirmed: HashMap<Txid, Vec<OutPoint>>,
+    /// Outpoint -> unconfirmed watched transaction spending it.
+    spenders: HashMap<OutPoint, Txid>,
+    /// Depth watches whose users were told the depth was reached.
+    notified: HashSet<(String, TxWatch)>,
+}
+
+impl PendingTxs {
+    /// Returns false if the transaction was already seen.
+    pub fn see(&mut self, tx: &Transaction) -> bool {
+        let txid = tx.compute_txid();
+        if self.confirmed.contains_key(&txid) || self.unconfirmed.contains_key(&txid) {
+            return false;
+        }
+        let inputs: Vec<OutPoint> = tx.input.iter().map(|input| input.previous_output).collect();
+        for outpoint in inputs.iter() {
+            self.spenders.insert(*outpoint, txid);
+        }
+        self.unconfirmed.insert(txid, inputs);
+        true
+    }
+
+    /// Notes a transaction seen before a restart, or found confirmed when it
+    /// was added, whose inputs are unknown.
+    pub fn restore(&mut self, txid: Txid, confirmed_at: Option<u64>) {
+        match confirmed_at {
+            Some(height) => {
+                self.confirmed.insert(txid, height);
+            }
+            None => {
+                self.unconfirmed.entry(txid).or_default();
+            }
+        }
+    }
+
+    /// Moves a waited for transaction to the confirmed ones. Returns false if
+    /// it was not waited for.
+    pub fn confirm(&mut self, txid: &Txid, height: u64) -> bool {
+        if !self.resolve(txid) {
+            return false;
+        }
+        self.confirmed.insert(*txid, height);
+        true
+    }
+
+    /// Forgets transactions that confirmed at or below `height`.
+    pub fn prune(&mut self, height: u64) {
+        self.confirmed.retain(|_, confirmed_at| *confirmed_at > height);
+    }
+
+    /// Stops waiting for a transaction that confirmed or left the mempool.
+    /// Returns false if it was not waited for.
+    pub fn resolve(&mut self, txid: &Txid) -> bool {
+        let Some(inputs) = self.unconfirmed.remove(txid) else {
+            return false;
+        };
+        for outpoint in inputs.iter() {
+            if self.spenders.get(outpoint) == Some(txid) {
+                self.spenders.remove(outpoint);
+            }
+        }
+        true
+    }
+
+    /// Unconfirmed watched transactions spending an outpoint `tx` also
+    /// spends. They are no longer waited for.
+    pub fn replaced_by(&mut self, tx: &Transaction) -> Vec<Txid> {
+        let txid = tx.compute_txid();
+        let mut replaced: Vec<Txid> = tx
+            .input
+            .iter()
+            .filter_map(|input| self.spenders.get(&input.previous_output))
+            .filter(|spender| **spender != txid)
+            .copied()
+            .collect();
+        replaced.sort();
+        replaced.dedup();
+        for replaced_txid in replaced.iter() {
+            self.resolve(replaced_txid);
+        }
+        replaced
+    }
+
+    pub fn unconfirmed(&self) -> Vec<Txid> {
+        self.unconfirmed.keys().copied().collect()
+    }
+
+    /// Whether `user` is to be told `watch` is `depth` blocks deep: once,
+    /// when it first reaches the depth they asked for.
+    pub fn reach_depth(&mut self, user: &str, watch: TxWatch, depth: i64) -> bool {
+        if watch.depth.is_none_or(|wanted| depth < i64::from(wanted)) {
+            return false;
+        }
+        self.notified.insert((user.to_string(), watch))
+    }
+
+    /// Tells `user` again once `watch` is deep enough, after its
+    /// transaction lost its confirmation.
+    pub fn clear_depth(&mut self, user: &str, watch: TxWatch) {
+        self.notified.remove(&(user.to_string(), watch));
+    }
+
+    /// Forgets the depths notified for watches no longer in `watches`.
+    pub fn keep_depths(&mut self, watches: &[(String, TxWatch)]) {
+        self.notified.retain(|key| watches.contains(key));
+    }
+}
+
+/// Restores which watched transactions were already seen from the stored
+/// matches, and which depths were already notified from the checkpoint.
+/// Runs once at startup, after the watch index is loaded.
+pub fn load() {
+    let watches = watch_index::watched_txs();
+    let txids: Vec<String> = watches
+        .iter()
+        .map(|(_, watch)| watch.txid.to_string())
+        .collect();
+    if txids.is_empty() {
+        return;
+    }
+    let rows = match db_operations::get_match_confirmations(txids) {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch watched transactions from DB: {}", e);
+            return;
+        }
+    };
+    let checkpoint = match db_operations::get_checkpoint() {
+        Ok(checkpoint) => checkpoint.map(|c| c.block_height as i64),
+        Err(e) => {
+            eprintln!("❌ Failed to fetch checkpoint from DB: {}", e);
+            None
+        }
+    };
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    for (user, txid, block_height) in rows {
+        let Ok(txid) = Txid::from_str(&txid) else {
+            continue;
+        };
+        pending.restore(txid, block_height.map(|height| height as u64));
+        let (Some(block_height), Some(checkpoint)) = (block_height, checkpoint) else {
+            continue;
+        };
+        for (_, watch) in watches.iter().filter(|(u, w)| *u == user && w.txid == txid) {
+            pending.reach_depth(&user, *watch, checkpoint - block_height as i64 + 1);
+        }
+    }
+    println!("✅ Waiting for {} watched transaction(s) to confirm", pending.unconfirmed.len());
+}
+
+/// Tells watchers of `tx` that it appeared, and watchers of transactions it
+/// conflicts with that theirs was replaced. Called for every transaction.
+pub fn process_tx(tx: &Transaction) {
+    let txid = tx.compute_txid();
+    let replaced = PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replaced_by(tx);
+    for replaced_txid in replaced {
+        for (user, records) in watch_index::match_txids([&replaced_txid]) {
+            let message = format!(
+                "Your watched transaction {} was replaced by tx {}",
+                labels::describe_records(&user, &records),
+                labels::describe_tx(&user, txid)
+            );
+            nostr_notify::send_message(message, user);
+        }
+    }
+
+    let matches = watch_index::match_txids([&txid]);
+    if matches.is_empty() || !PENDING.lock().unwrap_or_else(|e| e.into_inner()).see(tx) {
+        return;
+    }
+    for (user, records) in matches {
+        let message = format!(
+            "Your watched transaction {} has been broadcast and is waiting for confirmation",
+            labels::describe_records(&user, &records)
+        );
+        db_operations::store_matched_address(user.clone(), records, txid.to_string(), None);
+        nostr_notify::send_message(message, user);
+    }
+}
+
+/// Marks the watched transactions in `block` as confirmed, tells users whose
+/// transactions reached the depth they asked for, and checks that the ones
+/// still waiting have not left the mempool. Their first confirmation is
+/// notified with the other matches.
+pub async fn process_block(block: &Block, height: u64) {
+    let unconfirmed = {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        for tx in block.txdata.iter() {
+            pending.confirm(&tx.compute_txid(), height);
+        }
+        pending.prune(height.saturating_sub(REORG_WINDOW as u64));
+        pending.unconfirmed()
+    };
+
+    notify_depths(height);
+
+    for txid in unconfirmed {
+        if confirmations::is_in_mempool(&txid.to_string()).await != Some(false) {
+            continue;
+        }
+        PENDING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .resolve(&txid);
+        for (user, records) in watch_index::match_txids([&txid]) {
+            let message = format!(
+                "Your watched transaction {} is no longer in the mempool. It may have been evicted or replaced.",
+                labels::describe_records(&user, &records)
+            );
+            nostr_notify::send_message(message, user);
+        }
+    }
+}
+
+/// Tells users whose watched transaction reached the depth they asked for
+/// once the block at `height` is connected. Each depth is told once, even
+/// if blocks were missed.
+fn notify_depths(height: u64) {
+    let watches: Vec<(String, TxWatch)> = watch_index::watched_txs()
+        .into_iter()
+        .filter(|(_, watch)| watch.depth.is_some_and(|depth| depth > 1))
+        .collect();
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .keep_depths(&watches);
+    if watches.is_empty() {
+        return;
+    }
+    let txids = watches.iter().map(|(_, watch)| watch.txid.to_string()).collect();
+    let rows = match db_operations::get_match_confirmations(txids) {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch confirmations of watched transactions: {}", e);
+            return;
+        }
+    };
+    for (user, watch) in watches {
+        let txid = watch.txid.to_string();
+        let confirmed_at = rows
+            .iter()
+            .find(|(u, t, _)| *u == user && *t == txid)
+            .and_then(|(_, _, block_height)| *block_height);
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(confirmed_at) = confirmed_at else {
+            pending.clear_depth(&user, watch);
+            continue;
+        };
+        let depth = height as i64 - confirmed_at as i64 + 1;
+        if pending.reach_depth(&user, watch, depth) {
+            drop(pending);
+            let message = format!(
+                "Your watched transaction {} has reached {} confirmations",
+                labels::describe_tx(&user, watch.txid),
+                depth
+            );
+            nostr_notify::send_message(message, user);
+        }
+    }
+}
+
+/// Tells the user whether a newly watched transaction is already in the
+/// mempool or confirmed, since it will not be broadcast again.
+pub async fn report_status(user: String, watch: TxWatch) {
+    let label = RecordType::Txid(watch).to_string();
+    let message = match confirmations::is_in_mempool(&watch.txid.to_string()).await {
+        Some(true) => {
+            let tx = chain_source::get_chain_source()
+                .get_transaction(&watch.txid)
+                .await;
+            if let Ok(tx) = tx {
+                PENDING.lock().unwrap_or_else(|e| e.into_inner()).see(&tx);
+            }
+            // Confirmations are notified for stored matches.
+            db_operations::store_matched_address(
+                user.clone(),
+                vec![label],
+                watch.txid.to_string(),
+                None,
+            );
+            format!(
+                "Transaction {} is in the mempool. You will be notified when it confirms.",
+                watch.txid
+            )
+        }
+        Some(false) => match confirmed_in(watch.txid).await {
+            Some((block_hash, height, depth)) => {
+                PENDING
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .restore(watch.txid, Some(height));
+                // Stored confirmed, so a reorg that unconfirms it is notified.
+                db_operations::store_matched_address(
+                    user.clone(),
+                    vec![label],
+                    watch.txid.to_string(),
+                    None,
+                );
+                if let Err(e) = db_operations::confirm_matched_transactions(
+                    vec![watch.txid.to_string()],
+                    block_hash.to_string(),
+                    height as i32,
+                ) {
+                    eprintln!("❌ Failed to store confirmation of {}: {}", watch.txid, e);
+                }
+                let reached = PENDING
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .reach_depth(&user, watch, depth as i64);
+                let mut message = format!(
+                    "Transaction {} is already confirmed in block {} and has {} confirmation(s).",
+                    watch.txid, height, depth
+                );
+                if watch.depth.is_some() && !reached {
+                    message.push_str(" You will be notified when it is deep enough.");
+                }
+                message
+            }
+            None => format!(
+                "Transaction {} is not in the mempool. You will be notified when it is broadcast.",
+                watch.txid
+            ),
+        },
+        None => return,
+    };
+    nostr_notify::send_message(message, user);
+}
+
+/// Block, height and confirmations of a transaction the node knows to be
+/// confirmed.
+async fn confirmed_in(txid: Txid) -> Option<(BlockHash, u64, u32)> {
+    let (block_hash, depth) = match rpc::with_client(move |client| client.get_transaction_block(&txid)).await {
+        Ok(confirmed) => confirmed?,
+        Err(rpc::RpcError::NotFound(_)) => return None,
+        Err(e) => {
+            eprintln!("❌ Failed to look up {} on chain: {}", txid, e);
+            return None;
+        }
+    };
+    match rpc::with_client(move |client| client.get_block_height(&block_hash)).await {
+        Ok(height) => Some((block_hash, height, depth)),
+        Err(e) => {
+            eprintln!("❌ Failed to fetch height of block {}: {}", block_hash, e);
+            None
+        }
+    }
+}
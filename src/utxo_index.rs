@@ -0,0 +1,490 @@
+use bitcoin::{Amount, Block, BlockHash, OutPoint, ScriptBuf, Transaction, Txid};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use crate::chain_tracker::{TrackedBlock, REORG_WINDOW};
+use crate::{confirmations, db_operations};
+use crate::models::WatchedUtxo;
+
+static UTXO_INDEX: Lazy<RwLock<UtxoIndex>> = Lazy::new(|| RwLock::new(UtxoIndex::default()));
+
+/// An unspent output paying to a record `user` watches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub user: String,
+    /// Label of the watched record, as shown in notifications.
+    pub record: String,
+    pub script: ScriptBuf,
+    pub value: Amount,
+    /// `None` while the creating transaction is unconfirmed.
+    pub block_height: Option<u32>,
+}
+
+/// Value of the unspent outputs of some records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub confirmed: Amount,
+    pub unconfirmed: Amount,
+}
+
+impl Balance {
+    pub fn total(&self) -> Amount {
+        self.confirmed + self.unconfirmed
+    }
+
+    pub fn add(&mut self, coin: &Coin) {
+        match coin.block_height {
+            Some(_) => self.confirmed += coin.value,
+            None => self.unconfirmed += coin.value,
+        }
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} BTC", self.total().to_btc())?;
+        if self.unconfirmed > Amount::ZERO {
+            write!(f, " ({} BTC unconfirmed)", self.unconfirmed.to_btc())?;
+        }
+        Ok(())
+    }
+}
+
+/// Unspent outputs of watched records by outpoint, so a spend is recognised
+/// from the input alone. Ordered so the outputs of one transaction are
+/// adjacent.
+#[derive(Debug, Default)]
+pub struct UtxoIndex {
+    coins: BTreeMap<OutPoint, Vec<Coin>>,
+    /// The transaction spending an output and the height it confirmed at.
+    /// Spent outputs stay indexed until their spend is buried, so an
+    /// evicted, replaced or reorganised spend can be undone.
+    spends: HashMap<OutPoint, (Txid, Option<u32>)>,
+}
+
+impl UtxoIndex {
+    /// Returns false if the user already held the output.
+    pub fn insert(&mut self, outpoint: OutPoint, coin: Coin) -> bool {
+        let owners = self.coins.entry(outpoint).or_default();
+        if owners.iter().any(|c| c.user == coin.user && c.record == coin.record) {
+            return false;
+        }
+        owners.push(coin);
+        true
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&[Coin]> {
+        self.coins.get(outpoint).map(Vec::as_slice)
+    }
+
+    /// Marks an output as spent by the unconfirmed `txid`, which replaces
+    /// any other unconfirmed spend of it, and returns whoever held it.
+    /// Returns nothing if `txid` was already known to spend it.
+    pub fn spend(&mut self, outpoint: &OutPoint, txid: Txid) -> Vec<Coin> {
+        let Some(owners) = self.coins.get(outpoint) else {
+            return Vec::new();
+        };
+        match self.spends.get(outpoint) {
+            Some((spender, _)) if *spender == txid => return Vec::new(),
+            Some((_, Some(_))) => return Vec::new(),
+            _ => {}
+        }
+        self.spends.insert(*outpoint, (txid, None));
+        owners.clone()
+    }
+
+    /// Records that `txid` spending `outpoint` confirmed at `height`.
+    /// Returns false if the output is not indexed.
+    pub fn confirm_spend(&mut self, outpoint: &OutPoint, txid: Txid, height: u32) -> bool {
+        if !self.coins.contains_key(outpoint) {
+            return false;
+        }
+        self.spends.insert(*outpoint, (txid, Some(height)));
+        true
+    }
+
+    /// Makes the outputs the unconfirmed `txid` spent unspent again, once it
+    /// left the mempool.
+    pub fn unspend(&mut self, txid: Txid) -> Vec<OutPoint> {
+        let restored: Vec<OutPoint> = self
+            .spends
+            .iter()
+            .filter(|(_, (spender, height))| *spender == txid && height.is_none())
+            .map(|(outpoint, _)| *outpoint)
+            .collect();
+        for outpoint in restored.iter() {
+            self.spends.remove(outpoint);
+        }
+        restored
+    }
+
+    /// Unconfirmed transactions spending indexed outputs.
+    pub fn pending_spenders(&self) -> BTreeSet<Txid> {
+        self.spends
+            .values()
+            .filter(|(_, height)| height.is_none())
+            .map(|(spender, _)| *spender)
+            .collect()
+    }
+
+    /// Drops the outputs whose spend confirmed at or below `height`.
+    pub fn prune(&mut self, height: u32) {
+        let buried: Vec<OutPoint> = self
+            .spends
+            .iter()
+            .filter(|(_, (_, spent))| spent.is_some_and(|h| h <= height))
+            .map(|(outpoint, _)| *outpoint)
+            .collect();
+        for outpoint in buried.iter() {
+            self.spends.remove(outpoint);
+            self.coins.remove(outpoint);
+        }
+    }
+
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spends.contains_key(outpoint)
+    }
+
+    /// Marks the outputs of `txid` as confirmed at `height`.
+    pub fn confirm(&mut self, txid: Txid, height: u32) -> usize {
+        let mut confirmed = 0;
+        let outputs = OutPoint::new(txid, 0)..=OutPoint::new(txid, u32::MAX);
+        for (_, owners) in self.coins.range_mut(outputs) {
+            for coin in owners.iter_mut().filter(|c| c.block_height.is_none()) {
+                coin.block_height = Some(height);
+                confirmed += 1;
+            }
+        }
+        confirmed
+    }
+
+    /// Marks outputs and spends confirmed at or above `height` as
+    /// unconfirmed again.
+    pub fn unconfirm_from(&mut self, height: u32) {
+        for coin in self.coins.values_mut().flatten() {
+            if coin.block_height.is_some_and(|h| h >= height) {
+                coin.block_height = None;
+            }
+        }
+        for (_, spent) in self.spends.values_mut() {
+            if spent.is_some_and(|h| h >= height) {
+                *spent = None;
+            }
+        }
+    }
+
+    /// Drops the outputs `user` held through `record`.
+    pub fn forget(&mut self, user: &str, record: &str) {
+        self.coins.retain(|_, owners| {
+            owners.retain(|c| !(c.user == user && c.record == record));
+            !owners.is_empty()
+        });
+        let coins = &self.coins;
+        self.spends.retain(|outpoint, _| coins.contains_key(outpoint));
+    }
+
+    /// Every unspent output `user` holds.
+    pub fn holdings(&self, user: &str) -> Vec<(OutPoint, Coin)> {
+        self.iter()
+            .filter(|(_, c)| c.user == user)
+            .map(|(outpoint, c)| (*outpoint, c.clone()))
+            .collect()
+    }
+
+    /// Balance of every record `user` holds outputs of.
+    pub fn balances(&self, user: &str) -> BTreeMap<String, Balance> {
+        let mut balances: BTreeMap<String, Balance> = BTreeMap::new();
+        for (_, coin) in self.iter().filter(|(_, c)| c.user == user) {
+            balances.entry(coin.record.clone()).or_default().add(coin);
+        }
+        balances
+    }
+
+    /// Balance of `records` of `user`, counting the outputs `counted`
+    /// returns true for given their outpoint and spender, spent or not.
+    pub fn balance(
+        &self,
+        user: &str,
+        records: &[String],
+        counted: impl Fn(&OutPoint, Option<Txid>) -> bool,
+    ) -> Balance {
+        let mut balance = Balance::default();
+        for (outpoint, owners) in self.coins.iter() {
+            let spender = self.spends.get(outpoint).map(|(txid, _)| *txid);
+            for coin in owners {
+                if coin.user == user && records.contains(&coin.record) && counted(outpoint, spender) {
+                    balance.add(coin);
+                }
+            }
+        }
+        balance
+    }
+
+    /// Every output no transaction spends.
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &Coin)> {
+        self.coins
+            .iter()
+            .filter(|(outpoint, _)| !self.is_spent(outpoint))
+            .flat_map(|(outpoint, owners)| owners.iter().map(move |coin| (outpoint, coin)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+    }
+}
+
+/// Fills the index from the rows of `watched_utxos` whose spend, if any, is
+/// unconfirmed. Runs once at startup.
+pub fn load() {
+    let rows = match db_operations::get_unspent_watched_utxos() {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("❌ Failed to fetch watched UTXOs from DB: {}", e);
+            return;
+        }
+    };
+    let mut index = UTXO_INDEX.write().unwrap_or_else(|e| e.into_inner());
+    for row in rows {
+        let txid = Txid::from_str(&row.txid);
+        let script = ScriptBuf::from_hex(&row.script_pubkey);
+        let (Ok(txid), Ok(script)) = (txid, script) else {
+            eprintln!("❌ Skipping invalid watched UTXO {}:{}", row.txid, row.vout);
+            continue;
+        };
+        let outpoint = OutPoint::new(txid, row.vout as u32);
+        index.insert(
+            outpoint,
+            Coin {
+                user: row.nostr_pubkey,
+                record: row.record,
+                script,
+                value: Amount::from_sat(row.value as u64),
+                block_height: row.block_height.map(|h| h as u32),
+            },
+        );
+        if let Some(spender) = row.spent_by.as_deref().and_then(|s| Txid::from_str(s).ok()) {
+            index.spend(&outpoint, spender);
+        }
+    }
+    println!("✅ Tracking {} watched UTXO(s)", index.len());
+}
+
+/// Records a newly seen output paying to a watched record.
+pub fn add(outpoint: OutPoint, coin: Coin) {
+    insert(outpoint, coin, None);
+}
+
+/// Records an output a backfill found confirmed in `block_hash`.
+pub fn add_confirmed(outpoint: OutPoint, coin: Coin, block_hash: BlockHash) {
+    insert(outpoint, coin, Some(block_hash));
+}
+
+fn insert(outpoint: OutPoint, coin: Coin, block_hash: Option<BlockHash>) {
+    let row = WatchedUtxo {
+        txid: outpoint.txid.to_string(),
+        vout: outpoint.vout as i32,
+        nostr_pubkey: coin.user.clone(),
+        record: coin.record.clone(),
+        script_pubkey: coin.script.to_hex_string(),
+        value: coin.value.to_sat() as i64,
+        block_hash: block_hash.map(|hash| hash.to_string()),
+        block_height: coin.block_height.map(|height| height as i32),
+        spent_by: None,
+        spent_height: None,
+    };
+    if !UTXO_INDEX
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(outpoint, coin)
+    {
+        return;
+    }
+    if let Err(e) = db_operations::store_watched_utxos(vec![row]) {
+        eprintln!("❌ Failed to store watched UTXO {}: {}", outpoint, e);
+    }
+}
+
+/// Script of a watched output, if `outpoint` is one.
+pub fn script_of(outpoint: &OutPoint) -> Option<ScriptBuf> {
+    UTXO_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(outpoint)
+        .and_then(|owners| owners.first())
+        .map(|coin| coin.script.clone())
+}
+
+/// Marks `outpoint` as spent by the unconfirmed `spending_txid` if it is a
+/// watched output, returning whoever held it. The output stays indexed
+/// until the spend is buried.
+pub fn spend(outpoint: &OutPoint, spending_txid: Txid) -> Vec<Coin> {
+    let coins = UTXO_INDEX
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .spend(outpoint, spending_txid);
+    if !coins.is_empty() {
+        if let Err(e) = db_operations::spend_watched_utxo(
+            outpoint.txid.to_string(),
+            outpoint.vout as i32,
+            spending_txid.to_string(),
+        ) {
+            eprintln!("❌ Failed to mark watched UTXO {} spent: {}", outpoint, e);
+        }
+    }
+    coins
+}
+
+/// Confirms the watched outputs created in `block`, including ones already
+/// spent since, and the spends of watched outputs in it. Outputs whose spend
+/// is now buried past the reorg window are dropped.
+pub fn confirm_block(block: &Block, height: u64) {
+    let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+    let mut index = UTXO_INDEX.write().unwrap_or_else(|e| e.into_inner());
+    let mut spent = Vec::new();
+    for (tx, txid) in block.txdata.iter().zip(txids.iter()) {
+        index.confirm(*txid, height as u32);
+        for input in tx.input.iter() {
+            if index.confirm_spend(&input.previous_output, *txid, height as u32) {
+                spent.push((input.previous_output, *txid));
+            }
+        }
+    }
+    if let Some(buried) = (height as u32).checked_sub(REORG_WINDOW as u32) {
+        index.prune(buried);
+    }
+    drop(index);
+
+    let txids = txids.iter().map(|txid| txid.to_string()).collect();
+    let hash = block.block_hash();
+    if let Err(e) = db_operations::confirm_watched_utxos(txids, hash.to_string(), height as i32) {
+        eprintln!("❌ Failed to confirm watched UTXOs in {}: {}", hash, e);
+    }
+    for (outpoint, spender) in spent {
+        if let Err(e) = db_operations::confirm_watched_spend(
+            outpoint.txid.to_string(),
+            outpoint.vout as i32,
+            spender.to_string(),
+            height as i32,
+        ) {
+            eprintln!("❌ Failed to confirm spend of watched UTXO {}: {}", outpoint, e);
+        }
+    }
+}
+
+/// Rolls back confirmations of watched outputs from blocks that were
+/// reorganised away.
+pub fn unconfirm_blocks(disconnected: &[TrackedBlock]) {
+    let Some(lowest) = disconnected.iter().map(|b| b.height).min() else {
+        return;
+    };
+    UTXO_INDEX
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .unconfirm_from(lowest as u32);
+    let hashes = disconnected.iter().map(|b| b.hash.to_string()).collect();
+    if let Err(e) = db_operations::unconfirm_watched_utxos(hashes) {
+        eprintln!("❌ Failed to roll back watched UTXO confirmations: {}", e);
+    }
+    if let Err(e) = db_operations::unconfirm_watched_spends(lowest as i32) {
+        eprintln!("❌ Failed to roll back watched UTXO spends: {}", e);
+    }
+}
+
+/// Makes watched outputs unspent again when the unconfirmed transaction
+/// spending them was evicted from the mempool, replaced, or reorganised
+/// away and not taken back. Runs after every block.
+pub async fn restore_dropped_spends() {
+    let spenders = UTXO_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .pending_spenders();
+    for spender in spenders {
+        if confirmations::is_in_mempool(&spender.to_string()).await != Some(false) {
+            continue;
+        }
+        let restored = UTXO_INDEX
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .unspend(spender);
+        if restored.is_empty() {
+            continue;
+        }
+        println!(
+            "🔄 {} left the mempool unconfirmed, {} watched UTXO(s) unspent again",
+            spender,
+            restored.len()
+        );
+        if let Err(e) = db_operations::unspend_watched_utxos(spender.to_string()) {
+            eprintln!("❌ Failed to restore watched UTXOs spent by {}: {}", spender, e);
+        }
+    }
+}
+
+/// Drops the outputs `user` held through `record` once it is no longer
+/// watched.
+pub fn forget(user: &str, record: &str) {
+    UTXO_INDEX
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .forget(user, record);
+    if let Err(e) = db_operations::remove_watched_utxos(user.to_string(), record.to_string()) {
+        eprintln!("❌ Failed to remove watched UTXOs of {}: {}", record, e);
+    }
+}
+
+/// Every unspent output of every user.
+pub fn all_coins() -> Vec<(OutPoint, Coin)> {
+    UTXO_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(outpoint, coin)| (*outpoint, coin.clone()))
+        .collect()
+}
+
+/// Every unspent output `user` holds.
+pub fn holdings(user: &str) -> Vec<(OutPoint, Coin)> {
+    UTXO_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .holdings(user)
+}
+
+pub fn balances(user: &str) -> BTreeMap<String, Balance> {
+    UTXO_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .balances(user)
+}
+
+/// Sentence giving the balance of `records` of `user`, and what it was
+/// before `tx` if that changed it. Expects the outputs `tx` creates and the
+/// spends it makes to be indexed. Empty when the records hold nothing either
+/// way.
+pub fn describe_balance(user: &str, records: &[String], tx: Option<&Transaction>) -> String {
+    let index = UTXO_INDEX.read().unwrap_or_else(|e| e.into_inner());
+    let after = index.balance(user, records, |_, spender| spender.is_none());
+    let before = match tx {
+        Some(tx) => {
+            let txid = tx.compute_txid();
+            index.balance(user, records, |outpoint, spender| {
+                outpoint.txid != txid && spender.is_none_or(|spender| spender == txid)
+            })
+        }
+        None => after,
+    };
+    if before.total() != after.total() {
+        format!(" Balance now {}, was {}.", after, before)
+    } else if after.total() > Amount::ZERO {
+        format!(" Balance now {}.", after)
+    } else {
+        String::new()
+    }
+}
@@ -0,0 +1,263 @@
+use bitcoin::address::Address;
+use bitcoin::{base58, Network};
+use serde_json::Value;
+use std::str::FromStr;
+
+use crate::descriptor::WatchedDescriptor;
+use crate::models::RecordType;
+
+/// Version bytes of the SLIP-132 multisig prefixes Electrum writes, with the
+/// script the cosigner keys go into. Standard xpub/tpub keys are legacy P2SH.
+const MULTISIG_VERSIONS: [([u8; 4], &str, &str); 6] = [
+    ([0x04, 0x88, 0xb2, 0x1e], "sh(", ")"),
+    ([0x02, 0x95, 0xb4, 0x3f], "sh(wsh(", "))"),
+    ([0x02, 0xaa, 0x7e, 0xd3], "wsh(", ")"),
+    ([0x04, 0x35, 0x87, 0xcf], "sh(", ")"),
+    ([0x02, 0x42, 0x89, 0xef], "sh(wsh(", "))"),
+    ([0x02, 0x57, 0x54, 0x83], "wsh(", ")"),
+];
+
+/// Account sections of a Coldcard generic export and the script type of
+/// their xpub.
+const COLDCARD_ACCOUNTS: [(&str, &str); 4] = [
+    ("bip44", "pkh"),
+    ("bip49", "sh-wpkh"),
+    ("bip84", "wpkh"),
+    ("bip86", "tr"),
+];
+
+/// The records a wallet export describes.
+#[derive(Debug, Default)]
+pub struct WalletImport {
+    /// Which kind of export the file was.
+    pub format: &'static str,
+    pub records: Vec<RecordType>,
+    /// Name of the wallet, if the export has one.
+    pub name: Option<String>,
+    /// Height the wallet was created at, if the export has one.
+    pub birth_height: Option<u64>,
+}
+
+impl WalletImport {
+    fn new(format: &'static str) -> Self {
+        WalletImport {
+            format,
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, record: RecordType) {
+        if !self.records.contains(&record) {
+            self.records.push(record);
+        }
+    }
+
+    fn add_descriptor(&mut self, descriptor: &str, network: Network) -> Result<(), &'static str> {
+        let descriptor = WatchedDescriptor::parse(descriptor, network)?;
+        self.add(RecordType::Descriptor(Box::new(descriptor)));
+        Ok(())
+    }
+}
+
+/// Reads a wallet export: Bitcoin Core `listdescriptors` output, an
+/// Electrum or Sparrow wallet file, a Specter-style `{"descriptor": ...}`
+/// file, a BSMS descriptor record, a Coldcard generic export, or plain
+/// descriptors one per line. Exports with private keys are refused.
+pub fn parse(text: &str, network: Network) -> Result<WalletImport, &'static str> {
+    let text = text.trim();
+    if text.starts_with("BSMS") {
+        return parse_bsms(text, network);
+    }
+    let Ok(json) = serde_json::from_str::<Value>(text) else {
+        return parse_descriptors(text, network);
+    };
+    // `bitcoin-cli listdescriptors` prints the result, the RPC wraps it.
+    let json = json.get("result").filter(|r| r.is_object()).unwrap_or(&json);
+
+    let import = if json.get("descriptors").is_some() {
+        parse_core(json, network)?
+    } else if json.get("keystore").is_some() || json.get("wallet_type").is_some() {
+        parse_electrum(json, network)?
+    } else if json.get("descriptor").is_some() {
+        parse_specter(json, network)?
+    } else if COLDCARD_ACCOUNTS.iter().any(|(section, _)| json.get(section).is_some()) {
+        parse_coldcard(json, network)?
+    } else {
+        return Err("Unrecognised wallet export");
+    };
+    if import.records.is_empty() {
+        return Err("Wallet export has nothing to watch");
+    }
+    Ok(import)
+}
+
+fn parse_core(json: &Value, network: Network) -> Result<WalletImport, &'static str> {
+    let mut import = WalletImport::new("Bitcoin Core");
+    import.name = json["wallet_name"].as_str().map(str::to_string);
+    let descriptors = json["descriptors"].as_array().ok_or("Invalid descriptor list")?;
+    for entry in descriptors {
+        let descriptor = entry["desc"].as_str().ok_or("Descriptor entry without desc")?;
+        import.add_descriptor(descriptor, network)?;
+    }
+    Ok(import)
+}
+
+fn parse_electrum(json: &Value, network: Network) -> Result<WalletImport, &'static str> {
+    let mut import = WalletImport::new("Electrum");
+    let wallet_type = json["wallet_type"].as_str().unwrap_or("standard");
+
+    if wallet_type == "imported" {
+        watch_only(&json["keystore"])?;
+        let addresses = json["addresses"].as_object().ok_or("Wallet has no addresses")?;
+        for address in addresses.keys() {
+            let address = Address::from_str(address)
+                .map_err(|_| "Invalid address")?
+                .require_network(network)
+                .map_err(|_| "Address belongs to a different network")?;
+            import.add(RecordType::Address(address));
+        }
+        return Ok(import);
+    }
+
+    if let Some((threshold, _)) = wallet_type.split_once("of") {
+        let threshold: usize = threshold.parse().map_err(|_| "Unknown wallet type")?;
+        let mut cosigners = Vec::new();
+        let mut n = 1;
+        while let Some(keystore) = json.get(format!("x{}/", n)) {
+            watch_only(keystore)?;
+            cosigners.push(keystore["xpub"].as_str().ok_or("Keystore has no xpub")?);
+            n += 1;
+        }
+        let descriptor = electrum_multisig(threshold, &cosigners)?;
+        import.add_descriptor(&descriptor, network)?;
+        return Ok(import);
+    }
+
+    watch_only(&json["keystore"])?;
+    let xpub = json["keystore"]["xpub"].as_str().ok_or("Keystore has no xpub")?;
+    import.add(RecordType::parse(xpub, network)?);
+    Ok(import)
+}
+
+/// Refuses an Electrum keystore holding a seed or private keys. Electrum
+/// keeps these next to the xpub unless the wallet is watch-only.
+fn watch_only(keystore: &Value) -> Result<(), &'static str> {
+    let secret = ["xprv", "seed", "keypairs"].iter().any(|field| match &keystore[field] {
+        Value::Null => false,
+        Value::String(s) => !s.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+        _ => true,
+    });
+    if secret {
+        return Err("Wallet export contains private keys");
+    }
+    Ok(())
+}
+
+/// The descriptor of an Electrum multisig wallet, whose cosigner keys are
+/// sorted as BIP-67 asks and derived on `/0/*` and `/1/*`.
+fn electrum_multisig(threshold: usize, cosigners: &[&str]) -> Result<String, &'static str> {
+    if cosigners.is_empty() || threshold == 0 || threshold > cosigners.len() {
+        return Err("Invalid multisig wallet");
+    }
+    let mut wrapper = None;
+    let mut keys = Vec::new();
+    for cosigner in cosigners {
+        let mut data = base58::decode_check(cosigner).map_err(|_| "Invalid extended public key")?;
+        let (version, prefix, suffix) = MULTISIG_VERSIONS
+            .iter()
+            .find(|(version, _, _)| data.starts_with(version))
+            .ok_or("Unknown extended public key prefix")?;
+        if wrapper.is_some_and(|wrapper| wrapper != (prefix, suffix)) {
+            return Err("Cosigner keys are for different script types");
+        }
+        wrapper = Some((prefix, suffix));
+        // Only the xpub and tpub version bytes can be read back.
+        let testnet = MULTISIG_VERSIONS[3..].iter().any(|(v, _, _)| v == version);
+        let standard = if testnet { MULTISIG_VERSIONS[3].0 } else { MULTISIG_VERSIONS[0].0 };
+        data[..4].copy_from_slice(&standard);
+        keys.push(format!("{}/<0;1>/*", base58::encode_check(&data)));
+    }
+    let (prefix, suffix) = wrapper.ok_or("Invalid multisig wallet")?;
+    Ok(format!(
+        "{}sortedmulti({},{}){}",
+        prefix,
+        threshold,
+        keys.join(","),
+        suffix
+    ))
+}
+
+fn parse_specter(json: &Value, network: Network) -> Result<WalletImport, &'static str> {
+    let mut import = WalletImport::new("descriptor");
+    import.name = json["label"].as_str().map(str::to_string);
+    import.birth_height = json["blockheight"].as_u64().filter(|height| *height > 0);
+    let descriptor = json["descriptor"].as_str().ok_or("Invalid descriptor")?;
+    import.add_descriptor(descriptor, network)?;
+    Ok(import)
+}
+
+fn parse_coldcard(json: &Value, network: Network) -> Result<WalletImport, &'static str> {
+    let mut import = WalletImport::new("Coldcard");
+    for (section, script_type) in COLDCARD_ACCOUNTS {
+        let account = &json[section];
+        if let Some(descriptor) = account["desc"].as_str() {
+            import.add_descriptor(descriptor, network)?;
+        } else if let Some(xpub) = account["xpub"].as_str() {
+            import.add(RecordType::parse(&format!("{}#{}", xpub, script_type), network)?);
+        }
+    }
+    Ok(import)
+}
+
+/// A BIP-129 descriptor record: a `BSMS 1.0` line, the descriptor with
+/// `/**` for the receive and change paths, the path restrictions and the
+/// first address, which must match.
+fn parse_bsms(text: &str, network: Network) -> Result<WalletImport, &'static str> {
+    let mut import = WalletImport::new("BSMS");
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    if lines.first() != Some(&"BSMS 1.0") {
+        return Err("Unsupported BSMS version");
+    }
+    let template = lines.get(1).ok_or("BSMS record has no descriptor")?;
+    // A checksum covers the template, not the expanded descriptor.
+    let descriptor = if template.contains("/**") {
+        let (body, _) = template.split_once('#').unwrap_or((template, ""));
+        body.replace("/**", "/<0;1>/*")
+    } else {
+        template.to_string()
+    };
+    let descriptor = WatchedDescriptor::parse(&descriptor, network)?;
+
+    if let Some(first) = lines.get(3).filter(|line| !line.is_empty()) {
+        let derived = descriptor
+            .scripts(0, 0..1)
+            .first()
+            .and_then(|script| Address::from_script(script, network).ok())
+            .map(|addr| addr.to_string());
+        if derived.as_deref() != Some(*first) {
+            return Err("BSMS first address does not match the descriptor");
+        }
+    }
+    import.add(RecordType::Descriptor(Box::new(descriptor)));
+    Ok(import)
+}
+
+/// Descriptors one per line, as Sparrow exports them. Lines starting with
+/// `#` are comments.
+fn parse_descriptors(text: &str, network: Network) -> Result<WalletImport, &'static str> {
+    let mut import = WalletImport::new("descriptor");
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !line.contains('(') {
+            return Err("Unrecognised wallet export");
+        }
+        import.add_descriptor(line, network)?;
+    }
+    if import.records.is_empty() {
+        return Err("Unrecognised wallet export");
+    }
+    Ok(import)
+}
@@ -0,0 +1,331 @@
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::{OutPoint, Script, ScriptBuf, Txid};
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::task;
+
+use crate::db_operations;
+use crate::network;
+use crate::tx_watch::TxWatch;
+use crate::models::RecordType;
+
+static WATCH_INDEX: Lazy<RwLock<WatchIndex>> = Lazy::new(|| RwLock::new(WatchIndex::default()));
+
+static UPDATES: OnceCell<UnboundedSender<WatchUpdate>> = OnceCell::new();
+
+/// A change to the records users watch, sent by the HTTP routes.
+#[derive(Debug, Clone)]
+pub enum WatchUpdate {
+    Add { user: String, record: RecordType },
+    Remove { user: String, record: RecordType },
+    /// The scripts of a record grew, as with an xpub whose derivation
+    /// window moved.
+    Refresh { record: RecordType },
+}
+
+/// Every user's watched records, kept in memory so matching a transaction
+/// needs no database round-trip. Records are indexed by the scripts and
+/// outpoints they watch, so matching costs one lookup per script or input
+/// regardless of how many users and records there are.
+#[derive(Debug, Default)]
+pub struct WatchIndex {
+    /// nostr_pubkey -> records, to answer per-user questions and removals.
+    records: HashMap<String, HashSet<RecordType>>,
+    /// script_pubkey -> (nostr_pubkey, record label) of everyone watching it.
+    scripts: HashMap<ScriptBuf, Vec<(String, String)>>,
+    /// outpoint -> (nostr_pubkey, record label) of everyone watching it.
+    outpoints: HashMap<OutPoint, Vec<(String, String)>>,
+    /// public key -> (nostr_pubkey, record label) of everyone watching it.
+    keys: HashMap<XOnlyPublicKey, Vec<(String, String)>>,
+    /// txid -> (nostr_pubkey, record label) of everyone watching it.
+    txids: HashMap<Txid, Vec<(String, String)>>,
+}
+
+impl WatchIndex {
+    /// Returns false if the user already watched the record.
+    pub fn insert(&mut self, user: String, record: RecordType) -> bool {
+        if !self.records.entry(user.clone()).or_default().insert(record.clone()) {
+            return false;
+        }
+        let label = record.label();
+        for script in record.script_pubkeys() {
+            self.scripts
+                .entry(script)
+                .or_default()
+                .push((user.clone(), label.clone()));
+        }
+        match record {
+            RecordType::Utxo(outpoint) => {
+                self.outpoints.entry(outpoint).or_default().push((user, label));
+            }
+            RecordType::Pubkey(key) => {
+                self.keys.entry(key.into()).or_default().push((user, label));
+            }
+            RecordType::Txid(watch) => {
+                self.txids.entry(watch.txid).or_default().push((user, label));
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Returns false if the user did not watch the record.
+    pub fn remove(&mut self, user: &str, record: &RecordType) -> bool {
+        let Some(records) = self.records.get_mut(user) else {
+            return false;
+        };
+        if !records.remove(record) {
+            return false;
+        }
+        if records.is_empty() {
+            self.records.remove(user);
+        }
+
+        let label = record.label();
+        match record {
+            // Derived scripts are dropped wherever they are, since the
+            // derivation window may already be forgotten.
+            RecordType::Xpub(_) | RecordType::Descriptor(_) => self.scripts.retain(|_, watchers| {
+                watchers.retain(|(u, l)| !(u == user && *l == label));
+                !watchers.is_empty()
+            }),
+            _ => {
+                for script in record.script_pubkeys() {
+                    if let Some(watchers) = self.scripts.get_mut(&script) {
+                        watchers.retain(|(u, l)| !(u == user && *l == label));
+                        if watchers.is_empty() {
+                            self.scripts.remove(&script);
+                        }
+                    }
+                }
+            }
+        }
+        match record {
+            RecordType::Utxo(outpoint) => {
+                if let Some(watchers) = self.outpoints.get_mut(outpoint) {
+                    watchers.retain(|(u, _)| u != user);
+                    if watchers.is_empty() {
+                        self.outpoints.remove(outpoint);
+                    }
+                }
+            }
+            RecordType::Pubkey(key) => {
+                let key = XOnlyPublicKey::from(*key);
+                if let Some(watchers) = self.keys.get_mut(&key) {
+                    watchers.retain(|(u, l)| !(u == user && *l == label));
+                    if watchers.is_empty() {
+                        self.keys.remove(&key);
+                    }
+                }
+            }
+            RecordType::Txid(watch) => {
+                if let Some(watchers) = self.txids.get_mut(&watch.txid) {
+                    watchers.retain(|(u, l)| !(u == user && *l == label));
+                    if watchers.is_empty() {
+                        self.txids.remove(&watch.txid);
+                    }
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    pub fn apply(&mut self, update: WatchUpdate) -> bool {
+        match update {
+            WatchUpdate::Add { user, record } => self.insert(user, record),
+            WatchUpdate::Remove { user, record } => self.remove(&user, &record),
+            WatchUpdate::Refresh { record } => self.refresh(&record),
+        }
+    }
+
+    /// Indexes scripts `record` watches that are not indexed yet, for every
+    /// user watching it. Returns false if nobody watches it.
+    pub fn refresh(&mut self, record: &RecordType) -> bool {
+        let users: Vec<String> = self
+            .records
+            .iter()
+            .filter(|(_, records)| records.contains(record))
+            .map(|(user, _)| user.clone())
+            .collect();
+        if users.is_empty() {
+            return false;
+        }
+        let label = record.label();
+        for script in record.script_pubkeys() {
+            let watchers = self.scripts.entry(script).or_default();
+            for user in users.iter() {
+                if !watchers.iter().any(|(u, l)| u == user && *l == label) {
+                    watchers.push((user.clone(), label.clone()));
+                }
+            }
+        }
+        true
+    }
+
+    pub fn users(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn records(&self) -> usize {
+        self.records.values().map(HashSet::len).sum()
+    }
+
+    /// Users watching any of `scripts`, each with the labels of the records
+    /// that matched.
+    pub fn match_scripts<'a>(
+        &self,
+        scripts: impl IntoIterator<Item = &'a Script>,
+    ) -> HashMap<String, Vec<String>> {
+        collect_matches(scripts.into_iter().filter_map(|script| self.scripts.get(script)))
+    }
+
+    /// Users watching any of `outpoints` as a UTXO record, each with the
+    /// labels of the records that matched.
+    pub fn match_outpoints<'a>(
+        &self,
+        outpoints: impl IntoIterator<Item = &'a OutPoint>,
+    ) -> HashMap<String, Vec<String>> {
+        collect_matches(outpoints.into_iter().filter_map(|outpoint| self.outpoints.get(outpoint)))
+    }
+
+    /// Users watching any of `keys` as a public key record, each with the
+    /// labels of the records that matched.
+    pub fn match_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a XOnlyPublicKey>,
+    ) -> HashMap<String, Vec<String>> {
+        collect_matches(keys.into_iter().filter_map(|key| self.keys.get(key)))
+    }
+
+    /// Users watching any of `txids`, each with the labels of the records
+    /// that matched.
+    pub fn match_txids<'a>(
+        &self,
+        txids: impl IntoIterator<Item = &'a Txid>,
+    ) -> HashMap<String, Vec<String>> {
+        collect_matches(txids.into_iter().filter_map(|txid| self.txids.get(txid)))
+    }
+
+    /// Every watched transaction, with the user watching it.
+    pub fn watched_txs(&self) -> Vec<(String, TxWatch)> {
+        self.records
+            .iter()
+            .flat_map(|(user, records)| {
+                records.iter().filter_map(move |record| match record {
+                    RecordType::Txid(watch) => Some((user.clone(), *watch)),
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Groups watchers by user, listing each matched label once.
+fn collect_matches<'a>(
+    watchers: impl Iterator<Item = &'a Vec<(String, String)>>,
+) -> HashMap<String, Vec<String>> {
+    let mut matches: HashMap<String, Vec<String>> = HashMap::new();
+    for (user, label) in watchers.flatten() {
+        let labels = matches.entry(user.clone()).or_default();
+        if !labels.contains(label) {
+            labels.push(label.clone());
+        }
+    }
+    matches
+}
+
+/// Fills the index from `user_addresses`. Runs once at startup.
+pub fn load() {
+    match db_operations::normalize_user_addresses(network::get_network()) {
+        Ok(0) => {}
+        Ok(rewritten) => println!("🔄 Normalised {} stored record(s)", rewritten),
+        Err(e) => eprintln!("❌ Failed to normalise stored records: {}", e),
+    }
+
+    let mut index = WATCH_INDEX.write().unwrap_or_else(|e| e.into_inner());
+    match db_operations::get_all_tagged_addresses() {
+        Ok(rows) => {
+            for row in rows {
+                index.insert(row.nostr_pubkey, row.record);
+            }
+        }
+        Err(e) => eprintln!("❌ Failed to fetch addresses from DB: {}", e),
+    }
+    println!(
+        "✅ Watching {} record(s) for {} user(s)",
+        index.records(),
+        index.users()
+    );
+}
+
+/// Starts the task applying updates sent with `send` to the index.
+pub fn spawn_updater() {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<WatchUpdate>();
+    if UPDATES.set(sender).is_err() {
+        return;
+    }
+    task::spawn(async move {
+        while let Some(update) = receiver.recv().await {
+            WATCH_INDEX
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .apply(update);
+        }
+    });
+}
+
+/// Queues a change to the index. Safe to call from any thread.
+pub fn send(update: WatchUpdate) {
+    match UPDATES.get() {
+        Some(sender) => {
+            if sender.send(update).is_err() {
+                eprintln!("❌ Watch index updater is not running");
+            }
+        }
+        None => eprintln!("❌ Watch index update sent before the updater started"),
+    }
+}
+
+pub fn match_scripts<'a>(
+    scripts: impl IntoIterator<Item = &'a Script>,
+) -> HashMap<String, Vec<String>> {
+    WATCH_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .match_scripts(scripts)
+}
+
+pub fn match_outpoints<'a>(
+    outpoints: impl IntoIterator<Item = &'a OutPoint>,
+) -> HashMap<String, Vec<String>> {
+    WATCH_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .match_outpoints(outpoints)
+}
+
+pub fn match_keys<'a>(
+    keys: impl IntoIterator<Item = &'a XOnlyPublicKey>,
+) -> HashMap<String, Vec<String>> {
+    WATCH_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .match_keys(keys)
+}
+
+pub fn match_txids<'a>(txids: impl IntoIterator<Item = &'a Txid>) -> HashMap<String, Vec<String>> {
+    WATCH_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .match_txids(txids)
+}
+
+pub fn watched_txs() -> Vec<(String, TxWatch)> {
+    WATCH_INDEX
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .watched_txs()
+}
@@ -0,0 +1,122 @@
+use bitcoin::base58;
+use bitcoin::bip32::{ChildNumber, Xpub};
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::{PublicKey, ScriptBuf};
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::ops::Range;
+
+static SECP: Lazy<Secp256k1<VerifyOnly>> = Lazy::new(Secp256k1::verification_only);
+
+/// Version bytes of the SLIP-132 prefixes and the script type each implies.
+const VERSIONS: [(&str, [u8; 4], ScriptType); 6] = [
+    ("xpub", [0x04, 0x88, 0xb2, 0x1e], ScriptType::Pkh),
+    ("ypub", [0x04, 0x9d, 0x7c, 0xb2], ScriptType::ShWpkh),
+    ("zpub", [0x04, 0xb2, 0x47, 0x46], ScriptType::Wpkh),
+    ("tpub", [0x04, 0x35, 0x87, 0xcf], ScriptType::Pkh),
+    ("upub", [0x04, 0x4a, 0x52, 0x62], ScriptType::ShWpkh),
+    ("vpub", [0x04, 0x5f, 0x1c, 0xf6], ScriptType::Wpkh),
+];
+
+/// How addresses are derived from the keys of an extended public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScriptType {
+    /// BIP44 P2PKH.
+    Pkh,
+    /// BIP49 P2SH-P2WPKH.
+    ShWpkh,
+    /// BIP84 P2WPKH.
+    Wpkh,
+    /// BIP86 P2TR key path.
+    Tr,
+}
+
+impl ScriptType {
+    fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "pkh" => Some(ScriptType::Pkh),
+            "sh-wpkh" => Some(ScriptType::ShWpkh),
+            "wpkh" => Some(ScriptType::Wpkh),
+            "tr" => Some(ScriptType::Tr),
+            _ => None,
+        }
+    }
+
+    fn script(self, key: &Xpub) -> ScriptBuf {
+        let pubkey = key.to_pub();
+        match self {
+            ScriptType::Pkh => ScriptBuf::new_p2pkh(&PublicKey::from(pubkey).pubkey_hash()),
+            ScriptType::ShWpkh => {
+                ScriptBuf::new_p2sh(&ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()).script_hash())
+            }
+            ScriptType::Wpkh => ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()),
+            ScriptType::Tr => ScriptBuf::new_p2tr(&SECP, key.to_x_only_pub(), None),
+        }
+    }
+}
+
+/// An account-level extended public key as a user entered it, optionally
+/// followed by `#pkh`, `#sh-wpkh`, `#wpkh` or `#tr` to override the script
+/// type its prefix implies.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExtendedKey {
+    text: String,
+    key: Xpub,
+    script_type: ScriptType,
+}
+
+impl ExtendedKey {
+    /// Parses the key without checking which network it belongs to.
+    pub fn parse(s: &str) -> Result<Self, &'static str> {
+        let (encoded, suffix) = match s.split_once('#') {
+            Some((encoded, suffix)) => (encoded, Some(suffix)),
+            None => (s, None),
+        };
+        let (_, _, implied) = VERSIONS
+            .iter()
+            .find(|(prefix, _, _)| encoded.starts_with(prefix))
+            .ok_or("Unknown extended public key prefix")?;
+        let script_type = match suffix {
+            Some(suffix) => ScriptType::from_suffix(suffix).ok_or("Unknown script type")?,
+            None => *implied,
+        };
+
+        let mut data = base58::decode_check(encoded).map_err(|_| "Invalid extended public key")?;
+        if data.len() != 78 {
+            return Err("Invalid extended public key");
+        }
+        // Xpub only knows the xpub and tpub version bytes.
+        let testnet = VERSIONS[3..].iter().any(|(_, version, _)| data.starts_with(version));
+        let canonical = if testnet { VERSIONS[3].1 } else { VERSIONS[0].1 };
+        data[..4].copy_from_slice(&canonical);
+        let key = Xpub::decode(&data).map_err(|_| "Invalid extended public key")?;
+
+        Ok(ExtendedKey {
+            text: s.to_string(),
+            key,
+            script_type,
+        })
+    }
+
+    /// The key as encoded, without a script type suffix.
+    pub fn encoded(&self) -> &str {
+        self.text.split('#').next().unwrap_or(&self.text)
+    }
+
+    /// Scripts at `indexes` of `chain` (0 for receive, 1 for change).
+    pub fn scripts(&self, chain: u32, indexes: Range<u32>) -> Vec<ScriptBuf> {
+        let Ok(chain_key) = self.key.ckd_pub(&SECP, ChildNumber::Normal { index: chain }) else {
+            return Vec::new();
+        };
+        indexes
+            .filter_map(|index| chain_key.ckd_pub(&SECP, ChildNumber::Normal { index }).ok())
+            .map(|key| self.script_type.script(&key))
+            .collect()
+    }
+}
+
+impl fmt::Display for ExtendedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}